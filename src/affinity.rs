@@ -0,0 +1,45 @@
+use std::io;
+use std::sync::Arc;
+
+/// Numbers of the CPUs the worker threads are pinned to, or `None` if they
+/// are not pinned.
+pub(crate) type CpuSet = Option<Arc<[usize]>>;
+
+/// Pins the current thread to the CPUs from `cpus`. Does nothing if `cpus` is
+/// `None`.
+#[cfg(all(feature = "affinity", target_os = "linux"))]
+pub(crate) fn pin_current_thread(cpus: Option<&[usize]>) -> io::Result<()> {
+    use std::io::{Error, ErrorKind};
+    use std::mem;
+
+    let cpus = match cpus {
+        Some(cpus) => cpus,
+        None => return Ok(())
+    };
+    // `cpu_set_t` is a plain bit mask, which is valid when zeroed, and the
+    // CPU numbers are checked against its size
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        for &cpu in cpus {
+            if cpu >= libc::CPU_SETSIZE as usize {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("CPU number {} is out of range", cpu)
+                ));
+            }
+            libc::CPU_SET(cpu, &mut set);
+        }
+        let size = mem::size_of::<libc::cpu_set_t>();
+        if libc::sched_setaffinity(0, size, &set) != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Pins the current thread to the CPUs from `cpus`. The pinning is supported
+/// only on Linux with the `affinity` feature, so it does nothing here.
+#[cfg(not(all(feature = "affinity", target_os = "linux")))]
+pub(crate) fn pin_current_thread(_cpus: Option<&[usize]>) -> io::Result<()> {
+    Ok(())
+}
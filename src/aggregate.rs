@@ -0,0 +1,200 @@
+use std::ops::AddAssign;
+use super::lines::{FromLine, IntoLine};
+use super::split::SplitIter;
+
+/// Computes a statistic over the values in the group.
+pub trait Aggregate<V> {
+    /// Type of the resulting statistic
+    type Output;
+
+    /// Adds the next value of the group.
+    fn add(&mut self, value: V);
+
+    /// Finishes the aggregation and returns the statistic. `add()` is always
+    /// called at least once before `finish()`.
+    fn finish(self) -> Self::Output;
+}
+
+/// Counts the values in the group.
+#[derive(Clone, Debug, Default)]
+pub struct Count(usize);
+
+impl<V> Aggregate<V> for Count {
+    type Output = usize;
+
+    fn add(&mut self, _value: V) {
+        self.0 += 1;
+    }
+
+    fn finish(self) -> usize {
+        self.0
+    }
+}
+
+/// Computes the sum of the values in the group.
+#[derive(Clone, Debug, Default)]
+pub struct Sum<V>(V);
+
+impl<V: AddAssign> Aggregate<V> for Sum<V> {
+    type Output = V;
+
+    fn add(&mut self, value: V) {
+        self.0 += value;
+    }
+
+    fn finish(self) -> V {
+        self.0
+    }
+}
+
+/// Finds the minimum value in the group.
+#[derive(Clone, Debug)]
+pub struct Min<V>(Option<V>);
+
+impl<V> Default for Min<V> {
+    fn default() -> Min<V> {
+        Min(None)
+    }
+}
+
+impl<V: Ord> Aggregate<V> for Min<V> {
+    type Output = V;
+
+    fn add(&mut self, value: V) {
+        self.0 = Some(match self.0.take() {
+            Some(cur) => cur.min(value),
+            None => value
+        });
+    }
+
+    fn finish(self) -> V {
+        self.0.expect("the group is empty")
+    }
+}
+
+/// Finds the maximum value in the group.
+#[derive(Clone, Debug)]
+pub struct Max<V>(Option<V>);
+
+impl<V> Default for Max<V> {
+    fn default() -> Max<V> {
+        Max(None)
+    }
+}
+
+impl<V: Ord> Aggregate<V> for Max<V> {
+    type Output = V;
+
+    fn add(&mut self, value: V) {
+        self.0 = Some(match self.0.take() {
+            Some(cur) => cur.max(value),
+            None => value
+        });
+    }
+
+    fn finish(self) -> V {
+        self.0.expect("the group is empty")
+    }
+}
+
+/// Takes the first value in the group.
+#[derive(Clone, Debug)]
+pub struct First<V>(Option<V>);
+
+impl<V> Default for First<V> {
+    fn default() -> First<V> {
+        First(None)
+    }
+}
+
+impl<V> Aggregate<V> for First<V> {
+    type Output = V;
+
+    fn add(&mut self, value: V) {
+        if self.0.is_none() {
+            self.0 = Some(value);
+        }
+    }
+
+    fn finish(self) -> V {
+        self.0.expect("the group is empty")
+    }
+}
+
+/// Takes the last value in the group.
+#[derive(Clone, Debug)]
+pub struct Last<V>(Option<V>);
+
+impl<V> Default for Last<V> {
+    fn default() -> Last<V> {
+        Last(None)
+    }
+}
+
+impl<V> Aggregate<V> for Last<V> {
+    type Output = V;
+
+    fn add(&mut self, value: V) {
+        self.0 = Some(value);
+    }
+
+    fn finish(self) -> V {
+        self.0.expect("the group is empty")
+    }
+}
+
+/// Iterator that yields the first element and the aggregated statistic for
+/// each group of `SplitIter`.
+pub struct AggregateIter<Iter, T, F, ValueFn, NewAgg> {
+    /// Source of the groups
+    groups: SplitIter<Iter, T, F>,
+    /// Function that extracts the aggregated value from the element
+    value: ValueFn,
+    /// Function that creates a new aggregator for each group
+    new_agg: NewAgg
+}
+
+impl<Iter, T, F, V, A, ValueFn, NewAgg> Iterator
+    for AggregateIter<Iter, T, F, ValueFn, NewAgg>
+where
+    Iter: Iterator<Item = T>,
+    T: FromLine + IntoLine,
+    F: FnMut(&T, &T) -> bool,
+    A: Aggregate<V>,
+    ValueFn: FnMut(&T) -> V,
+    NewAgg: FnMut() -> A
+{
+    type Item = (T, A::Output);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.groups.peek()?;
+        let mut agg = (self.new_agg)();
+        let first = self.groups.aggregate_group(&mut self.value, &mut agg)?;
+        Some((first, agg.finish()))
+    }
+}
+
+/// Computes a statistic over each group of `groups` (which is created by
+/// `split()` or `split_by_key()`) with the aggregator returned by `new_agg`.
+/// The aggregator takes the values obtained from the elements with `value`.
+/// Yields the first element of each group with the statistic, so the key of
+/// the group can be taken from it.
+///
+/// Unlike taking the groups from `SplitIter`, the contents of the groups are
+/// never stored, so the groups may be arbitrarily large, and the limits set
+/// in `SplitConfig` don't apply.
+pub fn aggregate<Iter, T, F, V, A, ValueFn, NewAgg>(
+    groups: SplitIter<Iter, T, F>,
+    value: ValueFn,
+    new_agg: NewAgg
+) -> AggregateIter<Iter, T, F, ValueFn, NewAgg>
+where
+    Iter: Iterator<Item = T>,
+    T: FromLine + IntoLine,
+    F: FnMut(&T, &T) -> bool,
+    A: Aggregate<V>,
+    ValueFn: FnMut(&T) -> V,
+    NewAgg: FnMut() -> A
+{
+    AggregateIter { groups, value, new_agg }
+}
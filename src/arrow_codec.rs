@@ -0,0 +1,381 @@
+use std::io::{self, Error, ErrorKind};
+#[cfg(feature = "parquet")]
+use std::io::Write;
+use std::sync::Arc;
+#[cfg(feature = "parquet")]
+use std::cmp;
+#[cfg(feature = "parquet")]
+use std::marker::PhantomData;
+#[cfg(feature = "parquet")]
+use std::mem;
+#[cfg(feature = "parquet")]
+use std::vec;
+use arrow::array::{
+    Array, ArrayRef, StringArray, Int8Array, Int16Array, Int32Array,
+    Int64Array, UInt8Array, UInt16Array, UInt32Array, UInt64Array
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+#[cfg(feature = "parquet")]
+use bytes::Bytes;
+#[cfg(feature = "parquet")]
+use parquet::arrow::ArrowWriter;
+#[cfg(feature = "parquet")]
+use parquet::arrow::arrow_reader::{
+    ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder
+};
+use super::kv::KeyValue;
+#[cfg(feature = "parquet")]
+use super::lines::IntoLine;
+use super::record_batch::arrow_error;
+#[cfg(feature = "parquet")]
+use super::record_batch::{DEFAULT_BATCH_SIZE, parquet_error};
+#[cfg(feature = "parquet")]
+use super::run::{DecodedRecords, KeyRange, RecordWriter, RunCodec};
+#[cfg(feature = "parquet")]
+use super::throttle::Throttled;
+#[cfg(feature = "parquet")]
+use super::tiers::{SpillReader, SpillWriter};
+
+/// Converts the records into the columns of the Arrow record batches and
+/// back, so they are stored in the columnar formats with the schema derived
+/// from the type of the records rather than as lines.
+///
+/// It's implemented for `String` and the integers, which take one column
+/// `value`, and for `KeyValue`, which takes the columns of the key and the
+/// columns of the value, named `key` and `value` if they take one column,
+/// and prefixed with `key.` and `value.` otherwise.
+pub trait ArrowCodec: Sized {
+    /// Returns the fields of the columns that hold the records.
+    fn fields() -> Vec<Field>;
+
+    /// Converts the records into the columns, one for each of `fields()`.
+    fn to_columns(records: Vec<Self>) -> io::Result<Vec<ArrayRef>>;
+
+    /// Converts the columns, one for each of `fields()`, back into the
+    /// records. Returns `ErrorKind::InvalidData` error if they don't match
+    /// the fields.
+    fn from_columns(columns: &[ArrayRef]) -> io::Result<Vec<Self>>;
+}
+
+/// Returns the schema of the batches that hold the records of type `T`.
+pub fn codec_schema<T: ArrowCodec>() -> SchemaRef {
+    Arc::new(Schema::new(T::fields()))
+}
+
+/// Converts the records into a batch with `schema`, which is the schema
+/// returned by `codec_schema()`.
+pub(crate) fn records_batch<T: ArrowCodec>(
+    schema: &SchemaRef,
+    records: Vec<T>
+) -> io::Result<RecordBatch> {
+    RecordBatch::try_new(schema.clone(), T::to_columns(records)?)
+        .map_err(arrow_error)
+}
+
+/// Returns the error about the columns that don't match the fields of the
+/// records.
+fn invalid_columns() -> Error {
+    Error::new(ErrorKind::InvalidData,
+               "the columns don't match the fields of the records")
+}
+
+/// Takes the only column from `columns` as an array of type `A` without the
+/// nulls.
+fn single_column<A: Array + 'static>(columns: &[ArrayRef]) -> io::Result<&A> {
+    match columns {
+        [column] if column.null_count() == 0 => {
+            column.as_any().downcast_ref().ok_or_else(invalid_columns)
+        },
+        _ => Err(invalid_columns())
+    }
+}
+
+impl ArrowCodec for String {
+    fn fields() -> Vec<Field> {
+        vec![Field::new("value", DataType::Utf8, false)]
+    }
+
+    fn to_columns(records: Vec<Self>) -> io::Result<Vec<ArrayRef>> {
+        Ok(vec![Arc::new(StringArray::from(records))])
+    }
+
+    fn from_columns(columns: &[ArrayRef]) -> io::Result<Vec<Self>> {
+        let array: &StringArray = single_column(columns)?;
+        Ok((0..array.len()).map(|idx| array.value(idx).to_string()).collect())
+    }
+}
+
+macro_rules! impl_arrow_codec_for_int {
+    ($($t:ty => $array:ty, $data_type:expr);*) => {$(
+        impl ArrowCodec for $t {
+            fn fields() -> Vec<Field> {
+                vec![Field::new("value", $data_type, false)]
+            }
+
+            fn to_columns(records: Vec<Self>) -> io::Result<Vec<ArrayRef>> {
+                Ok(vec![Arc::new(<$array>::from(records))])
+            }
+
+            fn from_columns(columns: &[ArrayRef]) -> io::Result<Vec<Self>> {
+                let array: &$array = single_column(columns)?;
+                Ok(array.values().to_vec())
+            }
+        }
+    )*}
+}
+
+impl_arrow_codec_for_int!(
+    u8 => UInt8Array, DataType::UInt8;
+    u16 => UInt16Array, DataType::UInt16;
+    u32 => UInt32Array, DataType::UInt32;
+    u64 => UInt64Array, DataType::UInt64;
+    i8 => Int8Array, DataType::Int8;
+    i16 => Int16Array, DataType::Int16;
+    i32 => Int32Array, DataType::Int32;
+    i64 => Int64Array, DataType::Int64
+);
+
+/// Names the `fields` of the key or the value of the pair after `prefix`.
+fn prefixed(prefix: &str, fields: Vec<Field>) -> Vec<Field> {
+    if fields.len() == 1 {
+        return fields.into_iter().map(|field| field.with_name(prefix))
+            .collect();
+    }
+    fields.into_iter()
+        .map(|field| {
+            let name = format!("{}.{}", prefix, field.name());
+            field.with_name(name)
+        })
+        .collect()
+}
+
+impl<K: ArrowCodec, V: ArrowCodec> ArrowCodec for KeyValue<K, V> {
+    fn fields() -> Vec<Field> {
+        let mut fields = prefixed("key", K::fields());
+        fields.extend(prefixed("value", V::fields()));
+        fields
+    }
+
+    fn to_columns(records: Vec<Self>) -> io::Result<Vec<ArrayRef>> {
+        let (keys, values): (Vec<_>, Vec<_>) = records.into_iter()
+            .map(KeyValue::into_pair)
+            .unzip();
+        let mut columns = K::to_columns(keys)?;
+        columns.extend(V::to_columns(values)?);
+        Ok(columns)
+    }
+
+    fn from_columns(columns: &[ArrayRef]) -> io::Result<Vec<Self>> {
+        let key_len = K::fields().len();
+        if columns.len() < key_len {
+            return Err(invalid_columns());
+        }
+        let keys = K::from_columns(&columns[..key_len])?;
+        let values = V::from_columns(&columns[key_len..])?;
+        if keys.len() != values.len() {
+            return Err(invalid_columns());
+        }
+        Ok(keys.into_iter().zip(values).map(KeyValue::from).collect())
+    }
+}
+
+/// Format of the run files of `Sort` that stores the records in Parquet
+/// with the schema given by `ArrowCodec`.
+#[cfg(feature = "parquet")]
+pub(crate) struct ParquetRuns<T> {
+    /// Schema of the batches
+    schema: SchemaRef,
+    _marker: PhantomData<fn() -> T>
+}
+
+#[cfg(feature = "parquet")]
+impl<T: ArrowCodec> ParquetRuns<T> {
+    /// Creates the format for the records of type `T`.
+    pub fn new() -> ParquetRuns<T> {
+        ParquetRuns { schema: codec_schema::<T>(), _marker: PhantomData }
+    }
+}
+
+/// Writer of the records into a Parquet run file.
+#[cfg(feature = "parquet")]
+struct ParquetRunWriter<T> {
+    /// The underlying writer
+    writer: ArrowWriter<Throttled<SpillWriter>>,
+    /// Schema of the batches
+    schema: SchemaRef,
+    /// Records that are not converted into a batch yet
+    pending: Vec<T>,
+    /// Number of the records written
+    records: u64,
+    /// First record of the run as a line
+    first: Option<String>,
+    /// Last batch written
+    last_batch: Option<RecordBatch>
+}
+
+/// Converts the row number `idx` of `batch` into a line.
+#[cfg(feature = "parquet")]
+fn row_line<T>(batch: &RecordBatch, idx: usize) -> io::Result<String>
+where
+    T: ArrowCodec + IntoLine
+{
+    let row = batch.slice(idx, 1);
+    let data = T::from_columns(row.columns())?.pop()
+        .ok_or_else(invalid_columns)?;
+    Ok(data.into_line())
+}
+
+#[cfg(feature = "parquet")]
+impl<T: ArrowCodec + IntoLine> ParquetRunWriter<T> {
+    /// Converts the pending records into a batch and writes it.
+    fn write_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let records = mem::replace(
+            &mut self.pending,
+            Vec::with_capacity(DEFAULT_BATCH_SIZE)
+        );
+        let batch = records_batch(&self.schema, records)?;
+        if self.first.is_none() {
+            self.first = Some(row_line::<T>(&batch, 0)?);
+        }
+        self.writer.write(&batch).map_err(parquet_error)?;
+        self.last_batch = Some(batch);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "parquet")]
+impl<T: ArrowCodec + IntoLine> RecordWriter<T> for ParquetRunWriter<T> {
+    fn write_record(&mut self, data: T) -> io::Result<()> {
+        self.pending.push(data);
+        self.records += 1;
+        if self.pending.len() == DEFAULT_BATCH_SIZE {
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<(u64, Option<KeyRange>)> {
+        self.write_pending()?;
+        self.writer.finish().map_err(parquet_error)?;
+        let len = self.writer.bytes_written() as u64;
+        let range = match (self.first.take(), self.last_batch.take()) {
+            (Some(first), Some(batch)) => Some(KeyRange {
+                records: self.records,
+                first,
+                last: row_line::<T>(&batch, batch.num_rows() - 1)?
+            }),
+            _ => None
+        };
+        Ok((len, range))
+    }
+}
+
+/// Iterator over the records in a Parquet run file.
+#[cfg(feature = "parquet")]
+struct ParquetRecords<T> {
+    /// Reader of the batches
+    reader: ParquetRecordBatchReader,
+    /// Records of the current batch that are not returned yet
+    batch: vec::IntoIter<T>
+}
+
+#[cfg(feature = "parquet")]
+impl<T: ArrowCodec> Iterator for ParquetRecords<T> {
+    type Item = io::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(data) = self.batch.next() {
+                return Some(Ok(data));
+            }
+            let batch = match self.reader.next()? {
+                Ok(batch) => batch,
+                Err(err) => return Some(Err(arrow_error(err)))
+            };
+            match T::from_columns(batch.columns()) {
+                Ok(records) => self.batch = records.into_iter(),
+                Err(err) => return Some(Err(err))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "parquet")]
+impl<T> RunCodec<T> for ParquetRuns<T>
+where
+    T: ArrowCodec + IntoLine + Send + 'static
+{
+    fn writer(
+        &self,
+        file: Throttled<SpillWriter>
+    ) -> io::Result<Box<dyn RecordWriter<T>>> {
+        let writer = ArrowWriter::try_new(file, self.schema.clone(), None)
+            .map_err(parquet_error)?;
+        Ok(Box::new(ParquetRunWriter {
+            writer,
+            schema: self.schema.clone(),
+            pending: Vec::with_capacity(DEFAULT_BATCH_SIZE),
+            records: 0,
+            first: None,
+            last_batch: None
+        }))
+    }
+
+    fn records(&self, file: SpillReader) -> io::Result<DecodedRecords<T>> {
+        let reader = match file {
+            SpillReader::Disk(file) => {
+                ParquetRecordBatchReaderBuilder::try_new(file)
+                    .map_err(parquet_error)?
+                    .with_batch_size(DEFAULT_BATCH_SIZE)
+                    .build()
+            },
+            SpillReader::Memory(data) => {
+                let data = Bytes::from_owner(data.into_inner());
+                ParquetRecordBatchReaderBuilder::try_new(data)
+                    .map_err(parquet_error)?
+                    .with_batch_size(DEFAULT_BATCH_SIZE)
+                    .build()
+            }
+        }.map_err(parquet_error)?;
+        Ok(Box::new(ParquetRecords {
+            reader,
+            batch: Vec::new().into_iter()
+        }))
+    }
+}
+
+/// Writes the records from `iter` into `writer` as a Parquet file with the
+/// schema derived from `ArrowCodec`, in the batches of `batch_size` rows.
+/// Unlike `write_parquet_lines()`, the columns keep the types of the fields
+/// of the records, so the file is ready for the data lake tools as is.
+#[cfg(feature = "parquet")]
+pub fn write_parquet_records<I, T, W>(iter: I, writer: W,
+                                      batch_size: usize) -> io::Result<()>
+where
+    I: Iterator<Item = io::Result<T>>,
+    T: ArrowCodec,
+    W: Write + Send
+{
+    let schema = codec_schema::<T>();
+    let batch_size = cmp::max(batch_size, 1);
+    let mut writer = ArrowWriter::try_new(writer, schema.clone(), None)
+        .map_err(parquet_error)?;
+    let mut records = Vec::with_capacity(batch_size);
+    for maybe_data in iter {
+        records.push(maybe_data?);
+        if records.len() == batch_size {
+            let batch = records_batch(&schema, mem::take(&mut records))?;
+            writer.write(&batch).map_err(parquet_error)?;
+        }
+    }
+    if !records.is_empty() {
+        writer.write(&records_batch(&schema, records)?)
+            .map_err(parquet_error)?;
+    }
+    writer.close().map_err(parquet_error)?;
+    Ok(())
+}
@@ -0,0 +1,209 @@
+use std::io::{self, Read, Write, Error, ErrorKind};
+use std::fmt::Write as _;
+use apache_avro::{Reader, Writer};
+use apache_avro::reader::datum::GenericDatumReader;
+use apache_avro::writer::datum::GenericDatumWriter;
+use apache_avro::types::Value;
+use super::encoding::{parse_hex, push_hex};
+use super::float::F64Sortable;
+use super::lines::{FromLine, IntoLine};
+use super::sort::{Sort, SortStats, Config, until_error};
+
+/// Key of the Avro record. The related Avro types are mapped into the same
+/// variant, so, for example, `int` and `long` keys are compared as numbers.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum AvroKey {
+    /// `null`
+    Null,
+    /// `boolean`
+    Bool(bool),
+    /// `int`, `long` and the dates and times based on them
+    Long(i64),
+    /// `float` and `double`
+    Double(F64Sortable),
+    /// `string`, `enum` (by symbol) and `uuid`
+    Str(String),
+    /// `bytes` and `fixed`
+    Bytes(Vec<u8>)
+}
+
+impl AvroKey {
+    /// Converts the field value into the key. Returns an error if the value
+    /// cannot be used as the key.
+    fn from_value(value: &Value) -> io::Result<AvroKey> {
+        Ok(match value {
+            Value::Null => AvroKey::Null,
+            Value::Boolean(val) => AvroKey::Bool(*val),
+            Value::Int(val) | Value::Date(val) | Value::TimeMillis(val) => {
+                AvroKey::Long(i64::from(*val))
+            },
+            Value::Long(val)
+            | Value::TimeMicros(val)
+            | Value::TimestampMillis(val)
+            | Value::TimestampMicros(val)
+            | Value::TimestampNanos(val)
+            | Value::LocalTimestampMillis(val)
+            | Value::LocalTimestampMicros(val)
+            | Value::LocalTimestampNanos(val) => AvroKey::Long(*val),
+            Value::Float(val) => AvroKey::Double(F64Sortable(f64::from(*val))),
+            Value::Double(val) => AvroKey::Double(F64Sortable(*val)),
+            Value::String(val) | Value::Enum(_, val) => {
+                AvroKey::Str(val.clone())
+            },
+            Value::Uuid(val) => AvroKey::Str(val.to_string()),
+            Value::Bytes(val) | Value::Fixed(_, val) => {
+                AvroKey::Bytes(val.clone())
+            },
+            Value::Union(_, val) => AvroKey::from_value(val)?,
+            _ => return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "unsupported type of the key field"
+            ))
+        })
+    }
+}
+
+/// Avro record along with its key. The record is kept as the binary Avro
+/// datum, so it's decoded with the schema only when written into the output.
+///
+/// The line consists of the key, written as the type tag followed by the
+/// value, and the datum in hex form, separated with a space.
+#[derive(Clone, Debug)]
+struct AvroRecord {
+    /// Key of the record
+    key: AvroKey,
+    /// Binary Avro encoding of the record
+    datum: Vec<u8>
+}
+
+impl IntoLine for AvroRecord {
+    fn line_len(&self) -> usize {
+        let key_len = match &self.key {
+            AvroKey::Null => 1,
+            AvroKey::Bool(_) => 2,
+            AvroKey::Long(_) | AvroKey::Double(_) => 21,
+            AvroKey::Str(val) => 1 + 2 * val.len(),
+            AvroKey::Bytes(val) => 1 + 2 * val.len()
+        };
+        key_len + 1 + 2 * self.datum.len()
+    }
+
+    fn into_line(self) -> String {
+        let mut line = String::with_capacity(self.line_len());
+        match &self.key {
+            AvroKey::Null => line.push('n'),
+            AvroKey::Bool(val) => {
+                line.push_str(if *val { "b1" } else { "b0" })
+            },
+            AvroKey::Long(val) => write!(line, "l{}", val).unwrap(),
+            AvroKey::Double(val) => {
+                write!(line, "d{:016x}", val.0.to_bits()).unwrap()
+            },
+            AvroKey::Str(val) => {
+                line.push('s');
+                push_hex(&mut line, val.as_bytes());
+            },
+            AvroKey::Bytes(val) => {
+                line.push('y');
+                push_hex(&mut line, val);
+            }
+        }
+        line.push(' ');
+        push_hex(&mut line, &self.datum);
+        line
+    }
+}
+
+impl FromLine for AvroRecord {
+    fn from_line(line: &str) -> io::Result<Self> {
+        let invalid = || Error::from(ErrorKind::InvalidInput);
+        let (key, datum) = line.split_once(' ').ok_or_else(invalid)?;
+        if key.is_empty() || !key.is_char_boundary(1) {
+            return Err(invalid());
+        }
+        let (tag, val) = key.split_at(1);
+        let key = match tag {
+            "n" => AvroKey::Null,
+            "b" => AvroKey::Bool(val == "1"),
+            "l" => AvroKey::Long(val.parse().map_err(|_| invalid())?),
+            "d" => {
+                let bits = u64::from_str_radix(val, 16)
+                    .map_err(|_| invalid())?;
+                AvroKey::Double(F64Sortable(f64::from_bits(bits)))
+            },
+            "s" => {
+                let bytes = parse_hex(val)?;
+                AvroKey::Str(String::from_utf8(bytes).map_err(|_| invalid())?)
+            },
+            "y" => AvroKey::Bytes(parse_hex(val)?),
+            _ => return Err(invalid())
+        };
+        Ok(AvroRecord { key, datum: parse_hex(datum)? })
+    }
+}
+
+/// Converts the Avro error into `io::Error`.
+fn avro_error(err: apache_avro::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, err)
+}
+
+/// Sorts the records read from the Avro object container file by `field` and
+/// appends them into `writer`, which is flushed afterwards. The records must
+/// match the schema of `writer`, which is usually the writer schema of
+/// `reader`, and the codec of the output is chosen when creating `writer`.
+///
+/// The key field may have any primitive type, a logical type based on one, or
+/// a union of them. Returns the statistics collected while sorting, or the
+/// first error that occurred while reading, encoding or writing the records.
+pub fn sort_avro<R, W>(
+    reader: Reader<'_, R>,
+    writer: &mut Writer<'_, W>,
+    field: &str,
+    config: Config
+) -> io::Result<SortStats>
+where
+    R: Read,
+    W: Write
+{
+    let schema = writer.schema();
+    let datum_writer = GenericDatumWriter::builder(schema)
+        .build()
+        .map_err(avro_error)?;
+    let datum_reader = GenericDatumReader::builder(schema)
+        .build()
+        .map_err(avro_error)?;
+    let mut error = None;
+    let records = reader.map(|maybe_value| {
+        maybe_value.map_err(avro_error).and_then(|value| {
+            let key = match &value {
+                Value::Record(fields) => fields.iter()
+                    .find(|(name, _)| name == field)
+                    .map(|(_, key)| key),
+                _ => None
+            };
+            let key = key.ok_or_else(|| Error::new(
+                ErrorKind::InvalidInput,
+                format!("no field named {:?}", field)
+            ))?;
+            let key = AvroKey::from_value(key)?;
+            let datum = datum_writer.write_value_to_vec(value)
+                .map_err(avro_error)?;
+            Ok(AvroRecord { key, datum })
+        })
+    });
+    let compare = |a: &AvroRecord, b: &AvroRecord| a.key.cmp(&b.key);
+    let sorted = Sort::with_compare(config, compare)?
+        .sort(until_error(records, &mut error))?;
+    if let Some(err) = error {
+        return Err(err);
+    }
+    let stats = sorted.stats();
+    for maybe_record in sorted {
+        let datum = maybe_record?.datum;
+        let value = datum_reader.read_value(&mut &datum[..])
+            .map_err(avro_error)?;
+        writer.append_value(value).map_err(avro_error)?;
+    }
+    writer.flush().map_err(avro_error)?;
+    Ok(stats)
+}
@@ -0,0 +1,135 @@
+use std::io::{self, BufWriter, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::fs::File;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::str;
+use super::kv::KeyValue;
+use super::lines::{FromLine, IntoLine};
+use super::sort::{SortedIter, SortStats};
+
+/// Reference to the value stored out of line in the blob file, which is
+/// sorted instead of the value by `Sort::sort_blobs()`.
+#[derive(Clone, Copy, Debug)]
+pub struct BlobRef {
+    /// Offset of the value in the blob file
+    offset: u64,
+    /// Length of the value in bytes
+    len: u64
+}
+
+impl IntoLine for BlobRef {
+    fn line_len(&self) -> usize {
+        self.offset.line_len() + 1 + self.len.line_len()
+    }
+
+    fn into_line(self) -> String {
+        format!("{},{}", self.offset, self.len)
+    }
+}
+
+impl FromLine for BlobRef {
+    fn from_line(line: &str) -> io::Result<Self> {
+        let (offset, len) = line.split_once(',')
+            .ok_or_else(|| Error::from(ErrorKind::InvalidInput))?;
+        Ok(BlobRef {
+            offset: u64::from_line(offset)?,
+            len: u64::from_line(len)?
+        })
+    }
+}
+
+/// Writer of the values into the blob file, one after another.
+pub(crate) struct BlobWriter {
+    /// The blob file
+    file: BufWriter<File>,
+    /// Number of bytes written so far
+    offset: u64
+}
+
+impl BlobWriter {
+    /// Creates the blob file at `path`.
+    pub fn create(path: &Path) -> io::Result<BlobWriter> {
+        Ok(BlobWriter { file: BufWriter::new(File::create(path)?), offset: 0 })
+    }
+
+    /// Writes the value of the pair into the blob file, and replaces it with
+    /// the reference.
+    pub fn push<K, V>(
+        &mut self,
+        pair: KeyValue<K, V>
+    ) -> io::Result<KeyValue<K, BlobRef>>
+    where
+        V: IntoLine
+    {
+        let value = pair.value.into_line();
+        self.file.write_all(value.as_bytes())?;
+        let blob = BlobRef { offset: self.offset, len: value.len() as u64 };
+        self.offset += blob.len;
+        Ok(KeyValue { key: pair.key, value: blob })
+    }
+
+    /// Flushes the blob file. Returns its size.
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.file.flush()?;
+        Ok(self.offset)
+    }
+}
+
+/// Iterator over the sorted pairs that reads their values back from the blob
+/// file, created by `Sort::sort_blobs()`.
+pub struct BlobIter<K, V> {
+    /// Iterator over the sorted keys with the references to the values
+    inner: SortedIter<KeyValue<K, BlobRef>>,
+    /// The blob file
+    blobs: File,
+    /// Buffer for the value being read
+    buf: Vec<u8>,
+    _marker: PhantomData<V>
+}
+
+impl<K, V> BlobIter<K, V> {
+    /// Creates the iterator over `inner` that reads the values from the blob
+    /// file at `path`.
+    pub(crate) fn open(inner: SortedIter<KeyValue<K, BlobRef>>,
+                       path: &Path) -> io::Result<BlobIter<K, V>> {
+        Ok(BlobIter {
+            inner,
+            blobs: File::open(path)?,
+            buf: Vec::new(),
+            _marker: PhantomData
+        })
+    }
+
+    /// Returns the statistics collected while sorting.
+    pub fn stats(&self) -> SortStats {
+        self.inner.stats()
+    }
+
+    /// Reads the value referenced by `blob`.
+    fn read(&mut self, blob: BlobRef) -> io::Result<V>
+    where
+        V: FromLine
+    {
+        self.buf.resize(blob.len as usize, 0);
+        self.blobs.seek(SeekFrom::Start(blob.offset))?;
+        self.blobs.read_exact(&mut self.buf)?;
+        let value = str::from_utf8(&self.buf)
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        V::from_line(value)
+    }
+}
+
+impl<K: FromLine, V: FromLine> Iterator for BlobIter<K, V> {
+    type Item = io::Result<KeyValue<K, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pair = match self.inner.next()? {
+            Ok(pair) => pair,
+            Err(err) => return Some(Err(err))
+        };
+        Some(self.read(pair.value).map(|value| KeyValue {
+            key: pair.key,
+            value
+        }))
+    }
+}
@@ -0,0 +1,245 @@
+use std::io::{self, Write, Error, ErrorKind};
+use std::mem;
+use std::ops::Range;
+use std::str;
+use super::lines::{FromLine, IntoLine};
+use super::run::KeyRange;
+
+/// Magic bytes at the end of the framed output
+const MAGIC: &[u8; 8] = b"EXTSBLK1";
+
+/// Description of one block in the framed output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockInfo {
+    /// Offset of the block from the start of the output
+    pub offset: u64,
+    /// Length of the block (in bytes), including the length prefix
+    pub len: u64,
+    /// Number of records in the block
+    pub records: u64,
+    /// First line of the block, which is the minimum key
+    pub first: String,
+    /// Last line of the block, which is the maximum key
+    pub last: String
+}
+
+impl BlockInfo {
+    /// Returns the range of the payload in the output, excluding the length
+    /// prefix.
+    pub fn payload(&self) -> Range<usize> {
+        (self.offset + 8) as usize..(self.offset + self.len) as usize
+    }
+
+    /// Returns the range of the keys in the block, so the blocks of the output
+    /// can be pruned in the same way as the runs.
+    pub fn key_range(&self) -> KeyRange {
+        KeyRange {
+            records: self.records,
+            first: self.first.clone(),
+            last: self.last.clone()
+        }
+    }
+}
+
+/// Writer of the block that is being filled.
+struct BlockWriter<W> {
+    /// Destination of the data
+    writer: W,
+    /// Payload of the current block
+    payload: Vec<u8>,
+    /// Number of records in the current block
+    records: u64,
+    /// First line of the current block
+    first: String,
+    /// Start of the last line in `payload`
+    last_start: usize,
+    /// Number of bytes written so far
+    offset: u64,
+    /// Descriptions of the written blocks
+    blocks: Vec<BlockInfo>
+}
+
+impl<W: Write> BlockWriter<W> {
+    /// Adds the line into the current block.
+    fn add(&mut self, line: &str) {
+        if self.records == 0 {
+            self.first = line.to_string();
+        }
+        self.last_start = self.payload.len();
+        self.payload.extend_from_slice(line.as_bytes());
+        self.payload.push(b'\n');
+        self.records += 1;
+    }
+
+    /// Writes the current block, if it's not empty.
+    fn finish_block(&mut self) -> io::Result<()> {
+        if self.records == 0 {
+            return Ok(());
+        }
+        let last = &self.payload[self.last_start..self.payload.len() - 1];
+        let last = str::from_utf8(last).unwrap().to_string();
+        self.writer.write_all(&(self.payload.len() as u64).to_le_bytes())?;
+        self.writer.write_all(&self.payload)?;
+        let len = 8 + self.payload.len() as u64;
+        self.blocks.push(BlockInfo {
+            offset: self.offset,
+            len,
+            records: self.records,
+            first: mem::take(&mut self.first),
+            last
+        });
+        self.offset += len;
+        self.payload.clear();
+        self.records = 0;
+        Ok(())
+    }
+
+    /// Writes the footer.
+    fn write_footer(&mut self) -> io::Result<()> {
+        let mut footer = Vec::new();
+        footer.extend_from_slice(&(self.blocks.len() as u64).to_le_bytes());
+        for block in &self.blocks {
+            for num in &[block.offset, block.len, block.records] {
+                footer.extend_from_slice(&num.to_le_bytes());
+            }
+            for line in &[&block.first, &block.last] {
+                footer.extend_from_slice(&(line.len() as u64).to_le_bytes());
+                footer.extend_from_slice(line.as_bytes());
+            }
+        }
+        footer.extend_from_slice(&(footer.len() as u64).to_le_bytes());
+        footer.extend_from_slice(MAGIC);
+        self.writer.write_all(&footer)?;
+        self.writer.flush()
+    }
+}
+
+/// Writes the sorted records from `iter` into `writer` in the framed block
+/// format. A new block is started when the payload of the current one reaches
+/// `block_size` bytes. Returns the descriptions of the written blocks.
+///
+/// Each block consists of the payload length (`u64`) followed by the payload,
+/// which is the lines of the records, each terminated by a newline. The blocks
+/// are followed by the footer:
+///
+/// - the number of blocks (`u64`);
+/// - for each block: its offset, its length including the length prefix and
+///   the number of records (all `u64`), then its first and last lines, each
+///   written as the length (`u64`) followed by the bytes;
+/// - the length of the footer up to this point (`u64`);
+/// - the magic bytes `EXTSBLK1`.
+///
+/// All the integers are little-endian. As the data is sorted, the first and
+/// the last lines are the minimum and the maximum keys of the block, so the
+/// readers can map the output into memory and binary search the blocks using
+/// only the footer, which is read by `read_block_index()`.
+pub fn write_blocks<I, T, W>(iter: I, writer: W,
+                             block_size: usize) -> io::Result<Vec<BlockInfo>>
+where
+    I: Iterator<Item = io::Result<T>>,
+    T: IntoLine,
+    W: Write
+{
+    let mut block_writer = BlockWriter {
+        writer,
+        payload: Vec::new(),
+        records: 0,
+        first: String::new(),
+        last_start: 0,
+        offset: 0,
+        blocks: Vec::new()
+    };
+    for maybe_data in iter {
+        block_writer.add(&maybe_data?.into_line());
+        if block_writer.payload.len() >= block_size {
+            block_writer.finish_block()?;
+        }
+    }
+    block_writer.finish_block()?;
+    block_writer.write_footer()?;
+    Ok(block_writer.blocks)
+}
+
+/// Cursor over the footer bytes.
+struct Footer<'a> {
+    /// The remaining bytes
+    data: &'a [u8]
+}
+
+impl<'a> Footer<'a> {
+    /// Takes `len` bytes from the footer.
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(invalid_data("truncated block footer"));
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    /// Takes `u64` from the footer.
+    fn take_u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Takes the length-prefixed line from the footer.
+    fn take_line(&mut self) -> io::Result<String> {
+        let len = self.take_u64()? as usize;
+        str::from_utf8(self.take(len)?)
+            .map(str::to_string)
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err))
+    }
+}
+
+/// Creates `InvalidData` error with the given message.
+fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Reads the descriptions of the blocks from the footer of `data`, which is
+/// the whole output written by `write_blocks()`, usually mapped into memory.
+pub fn read_block_index(data: &[u8]) -> io::Result<Vec<BlockInfo>> {
+    if data.len() < 16 || &data[data.len() - 8..] != MAGIC {
+        return Err(invalid_data("not a framed block output"));
+    }
+    let mut len_bytes = [0; 8];
+    len_bytes.copy_from_slice(&data[data.len() - 16..data.len() - 8]);
+    let footer_len = u64::from_le_bytes(len_bytes) as usize;
+    let footer_start = (data.len() - 16).checked_sub(footer_len)
+        .ok_or_else(|| invalid_data("truncated block footer"))?;
+    let mut footer = Footer { data: &data[footer_start..data.len() - 16] };
+    let num_blocks = footer.take_u64()?;
+    let mut blocks = Vec::new();
+    for _ in 0..num_blocks {
+        let offset = footer.take_u64()?;
+        let len = footer.take_u64()?;
+        let records = footer.take_u64()?;
+        let first = footer.take_line()?;
+        let last = footer.take_line()?;
+        let end = offset.checked_add(len);
+        if len < 8 || end.is_none_or(|end| end > footer_start as u64) {
+            return Err(invalid_data("block is out of range"));
+        }
+        blocks.push(BlockInfo { offset, len, records, first, last });
+    }
+    Ok(blocks)
+}
+
+/// Iterates over the records in the payload of the block.
+pub fn block_records<'a, T>(
+    payload: &'a [u8]
+) -> impl Iterator<Item = io::Result<T>> + 'a
+where
+    T: FromLine + 'a
+{
+    payload.strip_suffix(b"\n")
+        .into_iter()
+        .flat_map(|lines| lines.split(|&byte| byte == b'\n'))
+        .map(|line| {
+            str::from_utf8(line)
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err))
+                .and_then(T::from_line)
+        })
+}
@@ -0,0 +1,97 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Pool of the vectors that are reused instead of being allocated again.
+///
+/// The vectors are returned into the pool empty, but keep their capacity, so
+/// the chunks of the split phase don't reallocate as they grow.
+pub(crate) struct BufferPool<T> {
+    /// Vectors that are ready to be reused
+    free: Mutex<Vec<Vec<T>>>,
+    /// Maximum number of vectors kept in the pool
+    max_len: usize
+}
+
+impl<T> BufferPool<T> {
+    /// Creates a new pool that keeps at most `max_len` vectors.
+    pub fn new(max_len: usize) -> BufferPool<T> {
+        BufferPool { free: Mutex::new(Vec::new()), max_len }
+    }
+
+    /// Takes an empty vector from the pool, or creates a new one if the pool
+    /// is empty.
+    pub fn take(&self) -> Vec<T> {
+        self.free.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    /// Returns the vector into the pool. The vector is dropped if the pool is
+    /// full or it has no allocated memory.
+    pub fn give(&self, mut buf: Vec<T>) {
+        buf.clear();
+        let mut free = self.free.lock().unwrap();
+        if buf.capacity() != 0 && free.len() < self.max_len {
+            free.push(buf);
+        }
+    }
+
+    /// Drops all the vectors kept in the pool.
+    pub fn clear(&self) {
+        self.free.lock().unwrap().clear();
+    }
+}
+
+/// Bound on the number of chunks that are sorted or wait to be sorted, or on
+/// the number of merges that perform I/O at once.
+///
+/// The producer takes a slot for each chunk before passing it to the jobs, and
+/// blocks while all the slots are taken, so reading the input overlaps with
+/// sorting and writing the chunks, but doesn't run arbitrarily far ahead.
+/// Likewise, each merge job takes a slot while it reads and writes the files.
+pub(crate) struct InFlight {
+    /// Number of the slots taken
+    taken: Mutex<usize>,
+    /// Notified when a slot is freed
+    freed: Condvar,
+    /// Maximum number of the slots taken at once
+    max_len: AtomicUsize
+}
+
+/// Slot taken from `InFlight`, which is freed when dropped.
+pub(crate) struct Slot(Arc<InFlight>);
+
+impl InFlight {
+    /// Creates a new bound that allows `max_len` slots taken, or one if
+    /// `max_len` is zero.
+    pub fn new(max_len: usize) -> InFlight {
+        InFlight {
+            taken: Mutex::new(0),
+            freed: Condvar::new(),
+            max_len: AtomicUsize::new(max_len.max(1))
+        }
+    }
+
+    /// Changes the maximum number of the slots taken at once, or sets it to
+    /// one if `max_len` is zero. The slots already taken are kept.
+    pub fn set_max_len(&self, max_len: usize) {
+        let _taken = self.taken.lock().unwrap();
+        self.max_len.store(max_len.max(1), Ordering::Relaxed);
+        self.freed.notify_all();
+    }
+
+    /// Takes a slot, waiting until one is freed if all of them are taken.
+    pub fn acquire(self: &Arc<Self>) -> Slot {
+        let mut taken = self.taken.lock().unwrap();
+        while *taken >= self.max_len.load(Ordering::Relaxed) {
+            taken = self.freed.wait(taken).unwrap();
+        }
+        *taken += 1;
+        Slot(self.clone())
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        *self.0.taken.lock().unwrap() -= 1;
+        self.0.freed.notify_one();
+    }
+}
@@ -0,0 +1,87 @@
+use std::io;
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+use super::lines::{FromLine, IntoLine};
+
+/// String wrapper that is compared ignoring the case.
+///
+/// By default, the original string is preserved, so the sorted output
+/// contains the strings as they were in the input. Use `folded()` to store
+/// the lowercase string instead.
+#[derive(Clone, Debug, Default)]
+pub struct CaseInsensitive<S>(pub S);
+
+impl<S: AsRef<str>> CaseInsensitive<S> {
+    /// Returns the lowercase characters of the string.
+    fn lowercase_chars(&self) -> impl Iterator<Item = char> + '_ {
+        self.0.as_ref().chars().flat_map(char::to_lowercase)
+    }
+
+    /// Returns the original string.
+    pub fn as_str(&self) -> &str {
+        self.0.as_ref()
+    }
+
+    /// Unwraps the original string.
+    pub fn into_inner(self) -> S {
+        self.0
+    }
+}
+
+impl CaseInsensitive<String> {
+    /// Creates a wrapper over the lowercase version of the string, so the
+    /// original case is not preserved.
+    pub fn folded(s: &str) -> CaseInsensitive<String> {
+        CaseInsensitive(s.to_lowercase())
+    }
+}
+
+impl<S> From<S> for CaseInsensitive<S> {
+    fn from(s: S) -> CaseInsensitive<S> {
+        CaseInsensitive(s)
+    }
+}
+
+impl<S: AsRef<str>> PartialEq for CaseInsensitive<S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.lowercase_chars().eq(other.lowercase_chars())
+    }
+}
+
+impl<S: AsRef<str>> Eq for CaseInsensitive<S> {}
+
+impl<S: AsRef<str>> PartialOrd for CaseInsensitive<S> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<S: AsRef<str>> Ord for CaseInsensitive<S> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.lowercase_chars().cmp(other.lowercase_chars())
+    }
+}
+
+impl<S: AsRef<str>> Hash for CaseInsensitive<S> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for c in self.lowercase_chars() {
+            c.hash(state);
+        }
+    }
+}
+
+impl<S: IntoLine> IntoLine for CaseInsensitive<S> {
+    fn line_len(&self) -> usize {
+        self.0.line_len()
+    }
+
+    fn into_line(self) -> String {
+        self.0.into_line()
+    }
+}
+
+impl<S: FromLine> FromLine for CaseInsensitive<S> {
+    fn from_line(line: &str) -> io::Result<Self> {
+        Ok(CaseInsensitive(S::from_line(line)?))
+    }
+}
@@ -0,0 +1,112 @@
+use std::io::{self, Error, ErrorKind};
+use std::cmp::Ordering;
+use icu_collator::{CollatorBorrowed, CollatorPreferences};
+pub use icu_collator::options::CollatorOptions;
+use icu_locale_core::Locale;
+use super::compare::Compare;
+use super::encoding::{parse_hex, push_hex};
+use super::lines::{FromLine, IntoLine};
+
+/// Locale-aware string collation based on ICU4X.
+///
+/// It can be used either as a comparator for the strings, or to produce the
+/// `Collated` strings that carry their collation sort keys, so the sort keys
+/// are computed only once instead of on each comparison.
+#[derive(Debug)]
+pub struct Collation {
+    /// Underlying ICU4X collator
+    collator: CollatorBorrowed<'static>
+}
+
+impl Collation {
+    /// Creates a collation for the given locale (e.g. `"de"` or `"sv-SE"`)
+    /// with default options.
+    pub fn new(locale: &str) -> io::Result<Collation> {
+        Self::with_options(locale, CollatorOptions::default())
+    }
+
+    /// Creates a collation for the given locale and options.
+    pub fn with_options(locale: &str,
+                        options: CollatorOptions) -> io::Result<Collation> {
+        let locale = Locale::try_from_str(locale)
+            .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+        let prefs = CollatorPreferences::from(&locale);
+        let collator = CollatorBorrowed::try_new(prefs, options)
+            .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+        Ok(Collation { collator })
+    }
+
+    /// Computes the sort key of the string. Comparing the sort keys bytewise
+    /// gives the same result as comparing the strings with the collation.
+    pub fn sort_key(&self, s: &str) -> Vec<u8> {
+        let mut key = Vec::new();
+        let Ok(()) = self.collator.write_sort_key_to(s, &mut key);
+        key
+    }
+
+    /// Wraps the string together with its sort key.
+    pub fn collated(&self, text: String) -> Collated {
+        Collated { key: self.sort_key(&text), text }
+    }
+}
+
+impl<S: AsRef<str>> Compare<S> for Collation {
+    fn compare(&self, a: &S, b: &S) -> Ordering {
+        self.collator.compare(a.as_ref(), b.as_ref())
+    }
+}
+
+/// String along with its collation sort key. The strings are sorted by the
+/// sort keys, and the strings with equal keys are compared bytewise.
+///
+/// The sort key is kept in the line in hex form, so it's not recomputed when
+/// the string is read back.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Collated {
+    /// Collation sort key
+    key: Vec<u8>,
+    /// Original string
+    text: String
+}
+
+impl Collated {
+    /// Returns the original string.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the collation sort key.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Converts into the original string.
+    pub fn into_text(self) -> String {
+        self.text
+    }
+}
+
+impl IntoLine for Collated {
+    fn line_len(&self) -> usize {
+        2 * self.key.len() + 1 + self.text.len()
+    }
+
+    fn into_line(self) -> String {
+        let mut line = String::with_capacity(self.line_len());
+        push_hex(&mut line, &self.key);
+        line.push(' ');
+        line.push_str(&self.text);
+        line
+    }
+}
+
+impl FromLine for Collated {
+    fn from_line(line: &str) -> io::Result<Self> {
+        let invalid = || {
+            Error::new(ErrorKind::InvalidData, "invalid collated string")
+        };
+        let (hex_key, text) = line.split_once(' ').ok_or_else(invalid)?;
+        let key = parse_hex(hex_key).map_err(|_| invalid())?;
+        Ok(Collated { key, text: text.to_string() })
+    }
+}
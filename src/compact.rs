@@ -0,0 +1,145 @@
+use std::io::{self, Error, ErrorKind};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use super::compare::ByOrd;
+use super::kv::KeyValue;
+use super::lines::{FromLine, IntoLine};
+use super::long_path::extend_path;
+use super::run::{KeyRange, RunWriter};
+use super::sort::file_records;
+use super::source::{MergeSource, merge_newest};
+
+/// Sorted file on disk, written by `compact()`. Unlike the temporary files of
+/// the sort, it's kept after the sort ends.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SortedFile {
+    /// Path to the file
+    path: PathBuf,
+    /// Number of bytes in the file
+    len: u64,
+    /// Range of the keys in the file, or `None` if it's empty
+    range: Option<KeyRange>
+}
+
+impl SortedFile {
+    /// Returns the path to the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the number of bytes in the file.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Checks whether the file contains no records.
+    pub fn is_empty(&self) -> bool {
+        self.range.is_none()
+    }
+
+    /// Returns the number of records in the file.
+    pub fn records(&self) -> u64 {
+        self.range.as_ref().map_or(0, |range| range.records)
+    }
+
+    /// Returns the range of the keys in the file, or `None` if it's empty.
+    pub fn key_range(&self) -> Option<&KeyRange> {
+        self.range.as_ref()
+    }
+
+    /// Opens the file and iterates over its records.
+    pub fn iter<T>(&self) -> io::Result<impl Iterator<Item = io::Result<T>>>
+    where
+        T: FromLine
+    {
+        file_records(&self.path, false)
+    }
+}
+
+impl<'a, T> From<SortedFile> for MergeSource<'a, T> {
+    fn from(file: SortedFile) -> MergeSource<'a, T> {
+        MergeSource::File(file.path)
+    }
+}
+
+/// Merges the key-value files sorted by key into the file at `output`,
+/// keeping only the most recent value for each key. The files are given from
+/// the oldest to the newest, so the value from the later file wins, and the
+/// later value wins within one file. It's the compaction step of an LSM-style
+/// store, and its result can be compacted again with the newer files.
+///
+/// It's the minor compaction, which keeps the tombstones, as the older files
+/// that are not compacted yet may still contain the deleted keys. Use
+/// `compact_major()` to drop them.
+pub fn compact<K, V, P, Q>(files: &[P], output: Q) -> io::Result<SortedFile>
+where
+    K: Ord + FromLine + IntoLine,
+    V: FromLine + IntoLine,
+    P: AsRef<Path>,
+    Q: AsRef<Path>
+{
+    compact_major::<K, V, P, Q, _>(files, output, |_| false)
+}
+
+/// Same as `compact()`, but performs the major compaction, which covers all
+/// the files that may contain the key. The values for which `is_tombstone`
+/// returns `true` mark the deleted keys, so if the most recent value for the
+/// key is a tombstone, the key is dropped from the output entirely.
+///
+/// Fails with `ErrorKind::InvalidInput` if `output` is one of `files`, as it
+/// would be overwritten while it's being read.
+pub fn compact_major<K, V, P, Q, F>(
+    files: &[P],
+    output: Q,
+    is_tombstone: F
+) -> io::Result<SortedFile>
+where
+    K: Ord + FromLine + IntoLine,
+    V: FromLine + IntoLine,
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    F: Fn(&V) -> bool
+{
+    // The output that doesn't exist yet can't be one of the inputs
+    if let Ok(output) = fs::canonicalize(extend_path(output.as_ref())?) {
+        for path in files {
+            if fs::canonicalize(extend_path(path.as_ref())?)? == output {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("output {} is one of the inputs",
+                            output.display())
+                ));
+            }
+        }
+    }
+    let sources = files.iter()
+        .map(|path| MergeSource::File(path.as_ref().to_path_buf()))
+        .collect();
+    let merged = merge_newest::<KeyValue<K, V>, _>(sources, ByOrd)?;
+    let live = merged.filter(|maybe_data| match maybe_data {
+        Ok(data) => !is_tombstone(&data.value),
+        Err(_) => true
+    });
+    write_sorted_file(live, output)
+}
+
+/// Writes the sorted elements into the file at `path`, which is extended to
+/// allow the long paths. Returns the first error that occurred while reading
+/// the elements or writing them.
+pub(crate) fn write_sorted_file<T, I, P>(iter: I,
+                                         path: P) -> io::Result<SortedFile>
+where
+    T: IntoLine,
+    I: Iterator<Item = io::Result<T>>,
+    P: AsRef<Path>
+{
+    let path = path.as_ref().to_path_buf();
+    let mut buf_write = RunWriter::new(File::create(extend_path(&path)?)?);
+    let mut len = 0;
+    for maybe_data in iter {
+        len += buf_write.write_record(maybe_data?)?;
+    }
+    buf_write.flush()?;
+    let range = buf_write.take_range();
+    Ok(SortedFile { path, len, range })
+}
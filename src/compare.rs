@@ -0,0 +1,99 @@
+use std::cmp::Ordering;
+
+/// Comparator that defines the order in which the elements are sorted.
+///
+/// It's implemented for all the functions with signature
+/// `Fn(&T, &T) -> Ordering`, and the complex comparators can be built with
+/// `by_key()`, `then_by()`, `then()` and `reverse()`.
+pub trait Compare<T: ?Sized>: Send + Sync {
+    /// Compares two elements.
+    fn compare(&self, a: &T, b: &T) -> Ordering;
+}
+
+impl<T: ?Sized, F> Compare<T> for F
+where
+    F: Fn(&T, &T) -> Ordering + Send + Sync
+{
+    fn compare(&self, a: &T, b: &T) -> Ordering {
+        self(a, b)
+    }
+}
+
+/// Comparator that compares the elements by their `Ord` implementation. Unlike
+/// `T::cmp`, it's `'static` even if `T` is not.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ByOrd;
+
+impl<T: Ord> Compare<T> for ByOrd {
+    fn compare(&self, a: &T, b: &T) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// Comparator that compares the elements by the key extracted with a function.
+#[derive(Clone, Debug)]
+pub struct ByKey<F>(F);
+
+/// Comparator that compares the elements with the first comparator, and uses
+/// the second one if the elements are equal.
+#[derive(Clone, Debug)]
+pub struct ThenBy<A, B>(A, B);
+
+/// Comparator that reverses the order of another comparator.
+#[derive(Clone, Debug)]
+pub struct Reversed<C>(C);
+
+impl<T, K, F> Compare<T> for ByKey<F>
+where
+    K: Ord,
+    F: Fn(&T) -> K + Send + Sync
+{
+    fn compare(&self, a: &T, b: &T) -> Ordering {
+        (self.0)(a).cmp(&(self.0)(b))
+    }
+}
+
+impl<T, A: Compare<T>, B: Compare<T>> Compare<T> for ThenBy<A, B> {
+    fn compare(&self, a: &T, b: &T) -> Ordering {
+        self.0.compare(a, b).then_with(|| self.1.compare(a, b))
+    }
+}
+
+impl<T, C: Compare<T>> Compare<T> for Reversed<C> {
+    fn compare(&self, a: &T, b: &T) -> Ordering {
+        self.0.compare(b, a)
+    }
+}
+
+macro_rules! impl_builder {
+    ($name:ident<$($param:ident),*>) => {
+        impl<$($param),*> $name<$($param),*> {
+            /// Adds a secondary comparison by the key extracted with `f`,
+            /// which is used if the elements are equal.
+            pub fn then_by<F2>(self, f: F2) -> ThenBy<Self, ByKey<F2>> {
+                ThenBy(self, ByKey(f))
+            }
+
+            /// Adds a secondary comparator, which is used if the elements are
+            /// equal.
+            pub fn then<C2>(self, other: C2) -> ThenBy<Self, C2> {
+                ThenBy(self, other)
+            }
+
+            /// Reverses the order.
+            pub fn reverse(self) -> Reversed<Self> {
+                Reversed(self)
+            }
+        }
+    }
+}
+
+impl_builder!(ByKey<F>);
+impl_builder!(ThenBy<A, B>);
+impl_builder!(Reversed<C>);
+
+/// Creates a comparator that compares the elements by the key extracted with
+/// `f`.
+pub fn by_key<F>(f: F) -> ByKey<F> {
+    ByKey(f)
+}
@@ -0,0 +1,145 @@
+use std::io::{self, Read, Write, Error, ErrorKind};
+use std::cmp::Ordering;
+use csv::StringRecord;
+use super::compare::Compare;
+use super::lines::{FromLine, IntoLine};
+use super::sort::{Sort, SortStats, Config, until_error};
+
+/// Record of a CSV file that can be sorted by `Sort`.
+///
+/// The fields may contain any characters, including the quotes and the
+/// newlines. The record is converted into the line by escaping the newlines,
+/// tabs, zero characters and backslashes in each field with a backslash and
+/// terminating each field with a tab, so the line-based sorting never breaks
+/// the record apart.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CsvRecord(pub StringRecord);
+
+impl IntoLine for CsvRecord {
+    fn line_len(&self) -> usize {
+        self.0.as_slice().len() + self.0.len()
+    }
+
+    fn into_line(self) -> String {
+        let mut line = String::with_capacity(self.line_len());
+        for field in self.0.iter() {
+            for ch in field.chars() {
+                match ch {
+                    '\\' => line.push_str("\\\\"),
+                    '\n' => line.push_str("\\n"),
+                    '\r' => line.push_str("\\r"),
+                    '\t' => line.push_str("\\t"),
+                    '\0' => line.push_str("\\0"),
+                    ch => line.push(ch)
+                }
+            }
+            line.push('\t');
+        }
+        line
+    }
+}
+
+impl FromLine for CsvRecord {
+    fn from_line(line: &str) -> io::Result<Self> {
+        let mut record = StringRecord::new();
+        let mut field = String::new();
+        let mut chars = line.chars();
+        while let Some(ch) = chars.next() {
+            match ch {
+                '\t' => {
+                    record.push_field(&field);
+                    field.clear();
+                },
+                '\\' => field.push(match chars.next() {
+                    Some('\\') => '\\',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('0') => '\0',
+                    _ => return Err(Error::from(ErrorKind::InvalidInput))
+                }),
+                ch => field.push(ch)
+            }
+        }
+        if !field.is_empty() {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+        Ok(CsvRecord(record))
+    }
+}
+
+/// Column of the CSV file to sort by.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CsvColumn {
+    /// Zero-based index of the column
+    Index(usize),
+    /// Name of the column in the header
+    Name(String)
+}
+
+/// Comparator that compares the records by the fields in the given columns
+/// lexicographically. The missing fields go first.
+struct ByColumns {
+    /// Indices of the columns to compare
+    columns: Vec<usize>
+}
+
+impl Compare<CsvRecord> for ByColumns {
+    fn compare(&self, a: &CsvRecord, b: &CsvRecord) -> Ordering {
+        self.columns.iter()
+            .map(|&idx| a.0.get(idx).cmp(&b.0.get(idx)))
+            .find(|&ord| ord != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+/// Sorts the records read from `reader` by the fields in `key` columns and
+/// writes them into `writer`. If `reader` has headers, they are written first.
+/// The records with equal keys keep their order.
+///
+/// The columns given by name are looked up in the headers. Returns the
+/// statistics collected while sorting.
+pub fn sort_csv<R, W>(
+    reader: &mut csv::Reader<R>,
+    writer: &mut csv::Writer<W>,
+    key: &[CsvColumn],
+    config: Config
+) -> io::Result<SortStats>
+where
+    R: Read,
+    W: Write
+{
+    let mut columns = Vec::new();
+    for column in key {
+        columns.push(match column {
+            CsvColumn::Index(idx) => *idx,
+            CsvColumn::Name(name) => {
+                reader.headers()?
+                    .iter()
+                    .position(|header| header == name)
+                    .ok_or_else(|| Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("no column named {:?}", name)
+                    ))?
+            }
+        });
+    }
+    if reader.has_headers() {
+        writer.write_record(reader.headers()?)?;
+    }
+
+    let mut error = None;
+    let records = reader.records()
+        .map(|maybe_record| maybe_record.map(CsvRecord));
+    let sort = Sort::with_compare(config, ByColumns { columns })?;
+    let sorted = sort.sort(until_error(records, &mut error))?;
+    if let Some(err) = error {
+        return Err(err.into());
+    }
+    let stats = sorted.stats();
+    for maybe_record in sorted {
+        writer.write_record(&maybe_record?.0)?;
+    }
+    writer.flush()?;
+    Ok(stats)
+}
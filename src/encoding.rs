@@ -0,0 +1,50 @@
+use std::io::{self, Error, ErrorKind};
+#[cfg(any(feature = "avro", feature = "icu"))]
+use std::fmt::Write;
+
+/// Writes `bytes` into `line` in hex form, which takes twice as much space as
+/// the bytes themselves.
+#[cfg(any(feature = "avro", feature = "icu"))]
+pub(crate) fn push_hex(line: &mut String, bytes: &[u8]) {
+    for byte in bytes {
+        write!(line, "{:02x}", byte).unwrap();
+    }
+}
+
+/// Parses the bytes written in hex form.
+#[cfg(any(feature = "avro", feature = "icu"))]
+pub(crate) fn parse_hex(text: &str) -> io::Result<Vec<u8>> {
+    // `from_str_radix` accepts a leading sign, so every character is checked
+    // before parsing, which also keeps the slicing on the char boundaries
+    if !text.len().is_multiple_of(2)
+        || !text.bytes().all(|byte| byte.is_ascii_hexdigit())
+    {
+        return Err(Error::from(ErrorKind::InvalidData));
+    }
+    (0..text.len()).step_by(2)
+        .map(|pos| u8::from_str_radix(&text[pos..pos + 2], 16))
+        .collect::<Result<_, _>>()
+        .map_err(|_| Error::from(ErrorKind::InvalidData))
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(any(feature = "avro", feature = "icu"))]
+    #[test]
+    fn hex_roundtrip() {
+        use std::io;
+        use super::{parse_hex, push_hex};
+
+        let mut line = String::new();
+        push_hex(&mut line, &[0x00, 0x7f, 0xab, 0xff]);
+        assert_eq!(line, "007fabff");
+        assert_eq!(parse_hex(&line).unwrap(), [0x00, 0x7f, 0xab, 0xff]);
+        assert!(parse_hex("abc").is_err());
+        assert!(parse_hex("zz").is_err());
+        assert!(parse_hex("\u{e9}0").is_err());
+        for text in ["+a", "a+", "-1", "0x"] {
+            let err = parse_hex(text).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use std::io;
+use std::cmp;
+use std::mem;
+use std::panic;
+use std::sync::Mutex;
+use std::thread;
+use super::affinity::{CpuSet, pin_current_thread};
+use super::job::Job;
+use super::lines::{FromLine, IntoLine};
+#[cfg(feature = "threads")]
+use super::pool::Pool;
+
+/// Runs the jobs of the sorter.
+///
+/// The executor is chosen when the sorter is created, so the bounds required
+/// by each kind of execution (like `Send` or `'static`) are checked only
+/// there, and the rest of the sorter doesn't depend on them.
+pub(crate) enum Executor<T> {
+    /// The jobs are run in the thread pool. The function adds the job into
+    /// the pool
+    #[cfg(feature = "threads")]
+    Pool(Pool, fn(&Pool, Job<T>)),
+    /// The jobs are collected into batches, and each batch is run on scoped
+    /// threads, so the elements don't need to be `'static`
+    Scoped {
+        /// Number of jobs in one batch
+        num_threads: usize,
+        /// Jobs waiting to be run
+        batch: Mutex<Vec<Job<T>>>,
+        /// CPUs the threads are pinned to
+        cpus: CpuSet,
+        /// Function that runs the batch
+        run_batch: fn(Vec<Job<T>>, &CpuSet) -> io::Result<()>,
+        /// It contains `Ok(())` if all the jobs succeeded, and the first error
+        /// otherwise
+        result: Mutex<io::Result<()>>
+    },
+    /// The jobs are run immediately on the calling thread, so the elements
+    /// don't need to be `Send`. It contains `Ok(())` if all the jobs
+    /// succeeded, and the first error otherwise
+    Inline(Mutex<io::Result<()>>)
+}
+
+/// Stores `job_result` into `result` unless it already contains an error.
+fn keep_first_error(result: &Mutex<io::Result<()>>,
+                    job_result: io::Result<()>) {
+    let mut result = result.lock().unwrap();
+    if result.is_ok() {
+        *result = job_result;
+    }
+}
+
+/// Takes the result stored in `result`, resetting it to `Ok(())`.
+fn take_result(result: &Mutex<io::Result<()>>) -> io::Result<()> {
+    mem::replace(&mut result.lock().unwrap(), Ok(()))
+}
+
+/// Runs the jobs on scoped threads, one thread per job, which are pinned to
+/// `cpus`. Returns the first error that occurred in the jobs, if any.
+fn run_scoped<T>(jobs: Vec<Job<T>>, cpus: &CpuSet) -> io::Result<()>
+where
+    T: FromLine + IntoLine + Send
+{
+    thread::scope(|scope| {
+        let handles: Vec<_> = jobs.into_iter()
+            .map(|job| scope.spawn(move || {
+                pin_current_thread(cpus.as_deref())?;
+                job.run()
+            }))
+            .collect();
+        let mut result = Ok(());
+        for handle in handles {
+            let job_result = match handle.join() {
+                Ok(job_result) => job_result,
+                Err(payload) => panic::resume_unwind(payload)
+            };
+            if result.is_ok() {
+                result = job_result;
+            }
+        }
+        result
+    })
+}
+
+impl<T: FromLine + IntoLine> Executor<T> {
+    /// Creates an executor that runs the jobs in the thread pool with
+    /// `num_threads` threads pinned to `cpus`.
+    #[cfg(feature = "threads")]
+    pub fn pool(num_threads: usize, cpus: CpuSet) -> Executor<T>
+    where
+        T: Send + 'static
+    {
+        Executor::Pool(Pool::new(num_threads, cpus), |pool, job| {
+            pool.add(move || job.run())
+        })
+    }
+
+    /// Creates an executor that runs up to `num_threads` jobs at once on
+    /// scoped threads pinned to `cpus`.
+    pub fn scoped(num_threads: usize, cpus: CpuSet) -> Executor<T>
+    where
+        T: Send
+    {
+        Executor::Scoped {
+            num_threads: cmp::max(num_threads, 1),
+            batch: Mutex::new(Vec::new()),
+            cpus,
+            run_batch: run_scoped::<T>,
+            result: Mutex::new(Ok(()))
+        }
+    }
+
+    /// Creates an executor that runs the jobs on the calling thread.
+    pub fn inline() -> Executor<T> {
+        Executor::Inline(Mutex::new(Ok(())))
+    }
+
+    /// Indicates whether the jobs run in the background while more jobs are
+    /// added. Otherwise, `add()` itself waits for the jobs when needed.
+    pub fn runs_in_background(&self) -> bool {
+        match self {
+            #[cfg(feature = "threads")]
+            Executor::Pool(..) => true,
+            _ => false
+        }
+    }
+
+    /// Adds a job. It may start immediately or when `join()` is invoked.
+    pub fn add(&self, job: Job<T>) {
+        match self {
+            #[cfg(feature = "threads")]
+            Executor::Pool(pool, add) => add(pool, job),
+            Executor::Scoped { num_threads, batch, .. } => {
+                let jobs = {
+                    let mut batch = batch.lock().unwrap();
+                    batch.push(job);
+                    if batch.len() < *num_threads {
+                        return;
+                    }
+                    mem::take(&mut *batch)
+                };
+                self.run_batch(jobs);
+            },
+            Executor::Inline(result) => keep_first_error(result, job.run())
+        }
+    }
+
+    /// Runs the batch of jobs, remembering the first error.
+    fn run_batch(&self, jobs: Vec<Job<T>>) {
+        if let Executor::Scoped { run_batch, result, cpus, .. } = self {
+            keep_first_error(result, run_batch(jobs, cpus));
+        }
+    }
+
+    /// Finishes all the currently added jobs. Returns the first error that
+    /// occurred in the jobs, if any.
+    pub fn join(&self) -> io::Result<()> {
+        match self {
+            #[cfg(feature = "threads")]
+            Executor::Pool(pool, _) => pool.join(),
+            Executor::Scoped { batch, result, .. } => {
+                let jobs = mem::take(&mut *batch.lock().unwrap());
+                self.run_batch(jobs);
+                take_result(result)
+            },
+            Executor::Inline(result) => take_result(result)
+        }
+    }
+}
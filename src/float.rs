@@ -0,0 +1,86 @@
+use std::io::{self, Error, ErrorKind};
+use std::cmp::Ordering;
+use super::lines::{FromLine, IntoLine};
+
+macro_rules! float_sortable {
+    ($(#[$doc:meta])* $name:ident, $float:ty, $bits:ty, $max_len:expr) => {
+        $(#[$doc])*
+        ///
+        /// The values are compared with `total_cmp()`, so `-0.0` goes before
+        /// `0.0`, and the NaNs go to the ends according to their sign. The
+        /// conversion into the line is exact: the numbers are written in the
+        /// shortest form that parses back to the same value, and NaNs are
+        /// written as `NaN:<hex bits>` to keep their sign and payload.
+        #[derive(Clone, Copy, Debug, Default)]
+        pub struct $name(pub $float);
+
+        impl From<$float> for $name {
+            fn from(value: $float) -> $name {
+                $name(value)
+            }
+        }
+
+        impl From<$name> for $float {
+            fn from(value: $name) -> $float {
+                value.0
+            }
+        }
+
+        impl PartialEq for $name {
+            fn eq(&self, other: &Self) -> bool {
+                self.cmp(other) == Ordering::Equal
+            }
+        }
+
+        impl Eq for $name {}
+
+        impl PartialOrd for $name {
+            fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        impl Ord for $name {
+            fn cmp(&self, other: &Self) -> Ordering {
+                self.0.total_cmp(&other.0)
+            }
+        }
+
+        impl IntoLine for $name {
+            fn line_len(&self) -> usize {
+                $max_len
+            }
+
+            fn into_line(self) -> String {
+                if self.0.is_nan() {
+                    format!("NaN:{:x}", self.0.to_bits())
+                } else {
+                    format!("{:?}", self.0)
+                }
+            }
+        }
+
+        impl FromLine for $name {
+            fn from_line(line: &str) -> io::Result<Self> {
+                let value = match line.strip_prefix("NaN:") {
+                    Some(bits) => <$bits>::from_str_radix(bits, 16)
+                        .ok()
+                        .map(<$float>::from_bits),
+                    None => line.parse().ok()
+                };
+                value.map($name)
+                    .ok_or_else(|| Error::from(ErrorKind::InvalidInput))
+            }
+        }
+    }
+}
+
+float_sortable!(
+    /// Wrapper over `f32` that implements total order, so it can be sorted.
+    F32Sortable, f32, u32, 16
+);
+
+float_sortable!(
+    /// Wrapper over `f64` that implements total order, so it can be sorted.
+    F64Sortable, f64, u64, 24
+);
@@ -0,0 +1,253 @@
+use std::hash::{Hash, Hasher};
+
+/// Minimum supported precision of `HyperLogLog`
+const MIN_PRECISION: u8 = 4;
+
+/// Maximum supported precision of `HyperLogLog`
+const MAX_PRECISION: u8 = 18;
+
+/// HyperLogLog sketch that estimates the number of distinct elements without
+/// storing them.
+///
+/// The elements are hashed with SipHash-1-3 with both keys set to zero, and the
+/// integers are fed to it in little-endian order. The hash doesn't depend on
+/// the Rust version or the platform, so the stored sketches can be merged with
+/// each other, for example, to combine the estimates computed over several
+/// shards, as long as the `Hash` implementations of the elements stay the same.
+/// Use `add_hash()` to supply another hash.
+#[derive(Clone, Debug)]
+pub struct HyperLogLog {
+    /// Number of bits of the hash used to select the register
+    precision: u8,
+    /// Maximum rank observed for each register
+    registers: Vec<u8>
+}
+
+impl Default for HyperLogLog {
+    fn default() -> HyperLogLog {
+        HyperLogLog::new(14)
+    }
+}
+
+impl HyperLogLog {
+    /// Creates an empty sketch with `2^precision` registers. The relative
+    /// error of the estimate is about `1.04 / sqrt(2^precision)`.
+    ///
+    /// Panics if `precision` is not in range from 4 to 18.
+    pub fn new(precision: u8) -> HyperLogLog {
+        assert!((MIN_PRECISION..=MAX_PRECISION).contains(&precision),
+                "unsupported precision {}", precision);
+        HyperLogLog {
+            precision,
+            registers: vec![0; 1 << precision]
+        }
+    }
+
+    /// Restores the sketch from its registers, as returned by `registers()`.
+    /// Returns `None` if the number of registers doesn't match the precision.
+    pub fn from_registers(precision: u8,
+                          registers: Vec<u8>) -> Option<HyperLogLog> {
+        if !(MIN_PRECISION..=MAX_PRECISION).contains(&precision) ||
+                registers.len() != 1 << precision {
+            return None;
+        }
+        Some(HyperLogLog { precision, registers })
+    }
+
+    /// Returns the precision of the sketch.
+    pub fn precision(&self) -> u8 {
+        self.precision
+    }
+
+    /// Returns the registers of the sketch, so it can be stored or sent
+    /// elsewhere.
+    pub fn registers(&self) -> &[u8] {
+        &self.registers
+    }
+
+    /// Adds the element into the sketch.
+    pub fn add<T: Hash + ?Sized>(&mut self, value: &T) {
+        let mut hasher = SipHasher13::new(0, 0);
+        value.hash(&mut hasher);
+        self.add_hash(hasher.finish());
+    }
+
+    /// Adds the element with the given 64-bit hash into the sketch. The hash
+    /// must be the same for the sketches that are merged with each other.
+    pub fn add_hash(&mut self, hash: u64) {
+        let idx = (hash >> (64 - self.precision)) as usize;
+        let rest = hash << self.precision;
+        let max_rank = 64 - self.precision as u32 + 1;
+        let rank = (rest.leading_zeros() + 1).min(max_rank) as u8;
+        if rank > self.registers[idx] {
+            self.registers[idx] = rank;
+        }
+    }
+
+    /// Merges `other` into this sketch, so the result estimates the number of
+    /// distinct elements in the union of both.
+    ///
+    /// Panics if the sketches have different precision.
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        assert_eq!(self.precision, other.precision,
+                   "cannot merge sketches with different precision");
+        for (reg, &other_reg) in self.registers.iter_mut()
+                                     .zip(other.registers.iter()) {
+            if other_reg > *reg {
+                *reg = other_reg;
+            }
+        }
+    }
+
+    /// Returns the estimated number of distinct elements.
+    pub fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m)
+        };
+        let sum: f64 = self.registers.iter()
+            .map(|&reg| (-(reg as f64)).exp2())
+            .sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&reg| reg == 0).count();
+        if estimate <= 2.5 * m && zeros != 0 {
+            // Use linear counting for small cardinalities
+            return m * (m / zeros as f64).ln();
+        }
+        estimate
+    }
+}
+
+/// SipHash-1-3, the algorithm behind `DefaultHasher` now. It is implemented
+/// here, since `DefaultHasher` may change in the future Rust versions.
+#[derive(Clone)]
+struct SipHasher13 {
+    v0: u64,
+    v1: u64,
+    v2: u64,
+    v3: u64,
+    /// Bytes that don't form a full word yet
+    tail: u64,
+    /// Number of bytes in `tail`
+    num_tail: usize,
+    /// Total number of bytes written
+    len: usize
+}
+
+impl SipHasher13 {
+    fn new(key0: u64, key1: u64) -> SipHasher13 {
+        SipHasher13 {
+            v0: key0 ^ 0x736f_6d65_7073_6575,
+            v1: key1 ^ 0x646f_7261_6e64_6f6d,
+            v2: key0 ^ 0x6c79_6765_6e65_7261,
+            v3: key1 ^ 0x7465_6462_7974_6573,
+            tail: 0,
+            num_tail: 0,
+            len: 0
+        }
+    }
+
+    fn round(&mut self) {
+        self.v0 = self.v0.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(13) ^ self.v0;
+        self.v0 = self.v0.rotate_left(32);
+        self.v2 = self.v2.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(16) ^ self.v2;
+        self.v0 = self.v0.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(21) ^ self.v0;
+        self.v2 = self.v2.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(17) ^ self.v2;
+        self.v2 = self.v2.rotate_left(32);
+    }
+
+    fn compress(&mut self, word: u64) {
+        self.v3 ^= word;
+        self.round();
+        self.v0 ^= word;
+    }
+}
+
+impl Hasher for SipHasher13 {
+    fn write(&mut self, bytes: &[u8]) {
+        self.len += bytes.len();
+        for &byte in bytes {
+            self.tail |= (byte as u64) << (8 * self.num_tail);
+            self.num_tail += 1;
+            if self.num_tail == 8 {
+                self.compress(self.tail);
+                self.tail = 0;
+                self.num_tail = 0;
+            }
+        }
+    }
+
+    fn write_u16(&mut self, num: u16) {
+        self.write(&num.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, num: u32) {
+        self.write(&num.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, num: u64) {
+        self.write(&num.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, num: u128) {
+        self.write(&num.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, num: usize) {
+        // Written as `u64`, so the hash is the same on 32-bit platforms
+        self.write_u64(num as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        let mut state = self.clone();
+        let last = ((self.len as u64 & 0xff) << 56) | self.tail;
+        state.compress(last);
+        state.v2 ^= 0xff;
+        for _ in 0..3 {
+            state.round();
+        }
+        state.v0 ^ state.v1 ^ state.v2 ^ state.v3
+    }
+}
+
+/// Estimates the number of distinct elements in `iter` in one pass, without
+/// sorting. Returns the sketch, so it can be merged with the sketches built
+/// over other parts of the data.
+pub fn approx_count_distinct<Iter, T>(iter: Iter) -> HyperLogLog
+where
+    Iter: Iterator<Item = T>,
+    T: Hash
+{
+    let mut sketch = HyperLogLog::default();
+    for data in iter {
+        sketch.add(&data);
+    }
+    sketch
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hash::Hasher;
+    use super::SipHasher13;
+
+    #[test]
+    fn hash_is_stable() {
+        let hash = |bytes: &[u8]| {
+            let mut hasher = SipHasher13::new(0, 0);
+            hasher.write(bytes);
+            hasher.finish()
+        };
+        assert_eq!(hash(b""), 0xd1fb_a762_150c_532c);
+        assert_eq!(hash(b"hello, world"), 0xd792_fbf8_1ec1_97f9);
+        let mut hasher = SipHasher13::new(0, 0);
+        hasher.write_u64(0x0123_4567_89ab_cdef);
+        assert_eq!(hasher.finish(), 0x8662_046e_5226_4db8);
+    }
+}
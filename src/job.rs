@@ -0,0 +1,401 @@
+use std::io::{self, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::cmp::{self, Ordering};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{self, AtomicU64};
+use super::compare::Compare;
+use super::lines::{FromLine, IntoLine};
+use super::buffer::{BufferPool, InFlight, Slot};
+use super::merge::{MergeIter, Duplicates};
+use super::run::{
+    KeyRange, LineReader, RecordWriter, RunCodec, RunReader, RunWriter
+};
+use super::sort::merge_records;
+use super::throttle::{Throttle, Throttled};
+use super::tiers::SpillWriter;
+use super::tracker::Tracker;
+
+/// Function that sorts a chunk of data in the order defined by the
+/// comparator.
+pub(crate) type ChunkSorter<T> =
+    Arc<dyn Fn(&mut [T], &dyn Compare<T>) + Send + Sync>;
+
+/// Function that checks a condition on the element, such as whether it's kept
+/// in the output or whether it's expired.
+pub(crate) type Filter<T> = Arc<dyn Fn(&T) -> bool + Send + Sync>;
+
+/// Writer of the temporary file with a run.
+pub(crate) enum RunSink<T> {
+    /// The records are written as lines
+    Lines(RunWriter<Throttled<SpillWriter>>),
+    /// The records are written in the format of the run codec
+    Encoded(Box<dyn RecordWriter<T>>)
+}
+
+impl<T: IntoLine> RunSink<T> {
+    /// Writes the record. Returns the number of bytes written, which is
+    /// known only after `finish()` for the encoded records.
+    pub fn write_record(&mut self, data: T) -> io::Result<u64> {
+        match self {
+            RunSink::Lines(writer) => writer.write_record(data),
+            RunSink::Encoded(writer) => writer.write_record(data).map(|_| 0)
+        }
+    }
+
+    /// Writes the rest of the data. Returns the number of bytes written that
+    /// were not returned by `write_record()`, and the range of the written
+    /// records.
+    pub fn finish(&mut self) -> io::Result<(u64, Option<KeyRange>)> {
+        match self {
+            RunSink::Lines(writer) => {
+                writer.flush()?;
+                Ok((0, writer.take_range()))
+            },
+            RunSink::Encoded(writer) => writer.finish()
+        }
+    }
+}
+
+/// Work to be done by a job.
+pub(crate) enum Task<T> {
+    /// Sort the chunk of data, which becomes the run with the given number
+    Split(usize, Vec<T>),
+    /// Merge the files, removing them afterwards
+    Merge(Vec<PathBuf>)
+}
+
+/// Job of the split or the merge phase, which writes its result into a
+/// temporary file. The jobs are plain data, so they can be run by any
+/// `Executor`.
+pub(crate) struct Job<T> {
+    /// Work to be done
+    pub task: Task<T>,
+    /// File to write the result into
+    pub out_filename: PathBuf,
+    /// Comparator that defines the order of the elements
+    pub compare: Arc<dyn Compare<T>>,
+    /// Defines what happens with the equal elements
+    pub duplicates: Duplicates<T>,
+    /// Function that sorts the chunks, if it differs from `slice::sort_by()`
+    pub sorter: Option<ChunkSorter<T>>,
+    /// Number of bytes written into the temporary files by the jobs
+    pub bytes_written: Arc<AtomicU64>,
+    /// Pool the chunk is returned into after it's written
+    pub chunks: Arc<BufferPool<T>>,
+    /// Indicates whether the merged files are mapped into memory
+    pub mmap: bool,
+    /// Format of the run files, if they are not written as lines
+    pub codec: Option<Arc<dyn RunCodec<T>>>,
+    /// Key ranges of the written runs along with their numbers
+    pub run_ranges: Arc<Mutex<Vec<(usize, KeyRange)>>>,
+    /// Function that checks whether the element is expired and is dropped
+    pub expired: Option<Filter<T>>,
+    /// Maximum number of files opened at once while merging
+    pub max_open_files: usize,
+    /// Slot of the chunk in flight, which is freed when the job finishes
+    pub slot: Option<Slot>,
+    /// Bound on the number of merges that perform I/O at once
+    pub io_limit: Arc<InFlight>,
+    /// Throttle of reading and writing the files, if the rate is limited
+    pub throttle: Option<Arc<Throttle>>,
+    /// Tracker of the temporary files written and removed by the job
+    pub tracker: Tracker,
+    /// Indicates whether the result is checked to be sorted after it's
+    /// written
+    pub verify: bool
+}
+
+/// Writes the element into `buf_write` unless it's expired. Returns the number
+/// of bytes written.
+pub(crate) fn write_live<T>(buf_write: &mut RunSink<T>, data: T,
+                            expired: Option<&Filter<T>>) -> io::Result<u64>
+where
+    T: IntoLine
+{
+    if expired.is_some_and(|expired| expired(&data)) {
+        return Ok(0);
+    }
+    buf_write.write_record(data)
+}
+
+/// Reads the file back with `tracker` and checks that it contains `records`
+/// elements in non-decreasing order. The file is decoded with `codec` if it's
+/// not `None`.
+/// Returns `ErrorKind::InvalidData` error with the file name and the byte
+/// offset of the offending line (or the index of the offending record in the
+/// encoded file) otherwise.
+pub(crate) fn verify_run<T: FromLine>(
+    path: &Path,
+    compare: &dyn Compare<T>,
+    records: u64,
+    codec: Option<&Arc<dyn RunCodec<T>>>,
+    tracker: &Tracker
+) -> io::Result<()> {
+    let invalid = |msg: String| {
+        Error::new(ErrorKind::InvalidData,
+                   format!("{}: {}", path.display(), msg))
+    };
+    let file = tracker.open(path)?;
+    let mut prev: Option<T> = None;
+    let mut count = 0;
+    // Returns whether `data` doesn't go before the previous element
+    let mut push = |data: T| {
+        let sorted = prev.as_ref().is_none_or(|prev| {
+            compare.compare(prev, &data) != Ordering::Greater
+        });
+        prev = Some(data);
+        count += 1;
+        sorted
+    };
+    match codec {
+        Some(codec) => {
+            for (index, maybe_data) in codec.records(file)?.enumerate() {
+                let data = maybe_data.map_err(|err| {
+                    invalid(format!("cannot decode the record {}: {}",
+                                    index, err))
+                })?;
+                if !push(data) {
+                    return Err(invalid(format!(
+                        "the record {} is less than the previous one", index
+                    )));
+                }
+            }
+        },
+        None => {
+            let mut reader = RunReader::new(file);
+            let mut offset = 0;
+            while let Some(line) = reader.next_line()? {
+                let len = line.len() as u64 + 1;
+                let data = T::from_line(line).map_err(|err| {
+                    invalid(format!("cannot parse the line at offset {}: {}",
+                                    offset, err))
+                })?;
+                if !push(data) {
+                    return Err(invalid(format!(
+                        "the line at offset {} is less than the previous one",
+                        offset
+                    )));
+                }
+                offset += len;
+            }
+        }
+    }
+    if count != records {
+        return Err(invalid(format!("{} records were written, but {} were \
+                                    read back", records, count)));
+    }
+    Ok(())
+}
+
+/// Sorts `data_vec` and writes the result. The chunks sorted in the reverse
+/// order are detected and reversed in linear time. In the unique mode, the
+/// duplicates are dropped or combined while writing. The emptied vector is
+/// returned into `chunks`.
+fn split_chunk<T>(mut data_vec: Vec<T>, compare: &dyn Compare<T>,
+                  duplicates: &Duplicates<T>, sorter: Option<ChunkSorter<T>>,
+                  chunks: &BufferPool<T>,
+                  buf_write: &mut RunSink<T>) -> io::Result<u64>
+where
+    T: IntoLine
+{
+    // The strictly descending chunk is just reversed, the stability is
+    // preserved as it contains no equal elements
+    let descending = data_vec.windows(2).all(|pair| {
+        compare.compare(&pair[0], &pair[1]) == Ordering::Greater
+    });
+    match sorter {
+        _ if descending => data_vec.reverse(),
+        Some(sorter) => sorter(&mut data_vec, compare),
+        None => data_vec.sort_by(|a, b| compare.compare(a, b))
+    }
+    let mut total_len = 0;
+    let mut last = None;
+    for data in data_vec.drain(..) {
+        if let Some(data) = duplicates.push(compare, &mut last, data) {
+            total_len += buf_write.write_record(data)?;
+        }
+    }
+    if let Some(data) = last {
+        total_len += buf_write.write_record(data)?;
+    }
+    chunks.give(data_vec);
+    Ok(total_len)
+}
+
+/// Settings of the merges performed by a job.
+struct MergeSettings<'a, T> {
+    /// Comparator that defines the order of the elements
+    compare: &'a Arc<dyn Compare<T>>,
+    /// Defines what happens with the equal elements
+    duplicates: &'a Duplicates<T>,
+    /// Indicates whether the merged files are mapped into memory
+    mmap: bool,
+    /// Format of the run files, if they are not written as lines
+    codec: Option<&'a Arc<dyn RunCodec<T>>>,
+    /// Function that checks whether the element is expired and is dropped
+    expired: Option<&'a Filter<T>>,
+    /// Throttle of reading and writing the files, if the rate is limited
+    throttle: Option<&'a Arc<Throttle>>,
+    /// Tracker of the temporary files written and removed by the merges
+    tracker: &'a Tracker
+}
+
+/// Creates the temporary file at `path` with `tracker` for writing through
+/// `throttle`. The records are written in the format of `codec`, or as lines
+/// if it's `None`.
+pub(crate) fn create_run<T>(
+    path: &Path,
+    codec: Option<&Arc<dyn RunCodec<T>>>,
+    throttle: Option<&Arc<Throttle>>,
+    tracker: &Tracker
+) -> io::Result<RunSink<T>> {
+    let file = tracker.create(path)?;
+    // Only the writes to the disk are throttled
+    let throttle = throttle.filter(|_| file.on_disk());
+    let file = Throttled::new(file, throttle);
+    Ok(match codec {
+        Some(codec) => RunSink::Encoded(codec.writer(file)?),
+        None => RunSink::Lines(RunWriter::new(file))
+    })
+}
+
+/// Merges the files and writes the result, dropping the expired elements.
+fn merge_files<T>(filenames: &[PathBuf], settings: &MergeSettings<T>,
+                  buf_write: &mut RunSink<T>) -> io::Result<u64>
+where
+    T: FromLine + IntoLine
+{
+    let iters_vec = merge_records(filenames, settings.mmap, settings.codec,
+                                  settings.tracker)?
+        .into_iter()
+        .map(|records| records.with_throttle(settings.throttle))
+        .collect();
+    let mut total_len = 0;
+    let compare = settings.compare.clone();
+    let duplicates = settings.duplicates.clone();
+    for maybe_data in MergeIter::new(iters_vec, compare, duplicates)? {
+        total_len += write_live(buf_write, maybe_data?, settings.expired)?;
+    }
+    Ok(total_len)
+}
+
+/// Returns the name of the temporary file written by the sub-merge number
+/// `num` at the given `level` of the merge tree for `out_filename`.
+fn sub_merge_file_name(out_filename: &Path, level: usize,
+                       num: usize) -> PathBuf {
+    let mut name = out_filename.as_os_str().to_owned();
+    name.push(format!(".{}-{}", level, num));
+    PathBuf::from(name)
+}
+
+/// Merges the files in the groups of no more than `max_open_files` into the
+/// temporary files next to `out_filename`, until no more than
+/// `max_open_files` remain. Returns the remaining files and the number of
+/// bytes written. The files written by the previous levels are removed after
+/// they're merged, but the original `filenames` are left intact.
+fn merge_tree<T>(filenames: &[PathBuf], out_filename: &Path,
+                 max_open_files: usize,
+                 settings: &MergeSettings<T>) -> io::Result<(Vec<PathBuf>, u64)>
+where
+    T: FromLine + IntoLine
+{
+    let max_open_files = cmp::max(max_open_files, 2);
+    let mut inputs = filenames.to_vec();
+    let mut total_len = 0;
+    let mut level = 0;
+    while inputs.len() > max_open_files {
+        // Split the files into the groups of equal size, so no group is
+        // merged just to be copied
+        let groups = inputs.len().div_ceil(max_open_files);
+        let group_len = inputs.len().div_ceil(groups);
+        let mut next_inputs = Vec::with_capacity(groups);
+        for group in inputs.chunks(group_len) {
+            let sub_filename = sub_merge_file_name(out_filename, level,
+                                                   next_inputs.len());
+            let mut sub_write = create_run(&sub_filename, settings.codec,
+                                           settings.throttle,
+                                           settings.tracker)?;
+            let sub_len = merge_files(group, settings, &mut sub_write)?
+                + sub_write.finish()?.0;
+            settings.tracker.merged(group, &sub_filename, sub_len)?;
+            total_len += sub_len;
+            if level > 0 {
+                for filename in group {
+                    settings.tracker.remove(filename)?;
+                }
+            }
+            next_inputs.push(sub_filename);
+        }
+        inputs = next_inputs;
+        level += 1;
+    }
+    Ok((inputs, total_len))
+}
+
+impl<T: FromLine + IntoLine> Job<T> {
+    /// Runs the job. The merged files are removed after the result is
+    /// written.
+    pub fn run(self) -> io::Result<()> {
+        let Job {
+            task, out_filename, compare, duplicates, sorter, bytes_written,
+            chunks, mmap, codec, run_ranges, expired, max_open_files, slot,
+            io_limit, throttle, tracker, verify
+        } = self;
+        let mut buf_write = create_run(&out_filename, codec.as_ref(),
+                                       throttle.as_ref(), &tracker)?;
+        let (total_len, sub_len, run, inputs, range) = match task {
+            Task::Split(run, mut data_vec) => {
+                if let Some(expired) = &expired {
+                    data_vec.retain(|data| !expired(data));
+                }
+                let total_len = split_chunk(data_vec, &*compare, &duplicates,
+                                            sorter, &chunks, &mut buf_write)?;
+                drop(slot);
+                let (len, range) = buf_write.finish()?;
+                let total_len = total_len + len;
+                tracker.created(&out_filename, total_len)?;
+                (total_len, 0, Some(run), Vec::new(), range)
+            },
+            Task::Merge(filenames) => {
+                let _slot = io_limit.acquire();
+                let _active = tracker.start_merge();
+                let settings = MergeSettings {
+                    compare: &compare,
+                    duplicates: &duplicates,
+                    mmap,
+                    codec: codec.as_ref(),
+                    expired: expired.as_ref(),
+                    throttle: throttle.as_ref(),
+                    tracker: &tracker
+                };
+                let (sub_filenames, sub_len) = merge_tree(
+                    &filenames, &out_filename, max_open_files, &settings
+                )?;
+                let total_len = merge_files(&sub_filenames, &settings,
+                                            &mut buf_write)?;
+                let (len, range) = buf_write.finish()?;
+                let total_len = total_len + len;
+                tracker.merged(&sub_filenames, &out_filename, total_len)?;
+                if sub_filenames != filenames {
+                    for filename in sub_filenames {
+                        tracker.remove(&filename)?;
+                    }
+                }
+                (total_len, sub_len, None, filenames, range)
+            }
+        };
+        if verify {
+            let records = range.as_ref().map_or(0, |range| range.records);
+            verify_run(&out_filename, &*compare, records, codec.as_ref(),
+                       &tracker)?;
+        }
+        if let (Some(run), Some(range)) = (run, range) {
+            run_ranges.lock().unwrap().push((run, range));
+        }
+        bytes_written.fetch_add(sub_len + total_len, atomic::Ordering::Relaxed);
+        for filename in inputs {
+            tracker.remove(&filename)?;
+        }
+        Ok(())
+    }
+}
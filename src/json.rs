@@ -0,0 +1,256 @@
+use std::io::{self, BufRead, Write, Error};
+use std::cmp::Ordering;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Number, Value};
+use super::kv::KeyValue;
+use super::lines::{FromLine, IntoLine};
+use super::run::RunWriter;
+use super::sort::{Sort, SortStats, SortedIter, Config, until_error};
+
+/// Estimates the length of the compact JSON representation of `value`.
+fn estimate_len(value: &Value) -> usize {
+    match value {
+        Value::Null => 4,
+        Value::Bool(_) => 5,
+        Value::Number(_) => 8,
+        Value::String(s) => s.len() + 2,
+        Value::Array(items) => {
+            items.iter().map(|item| estimate_len(item) + 1).sum::<usize>() + 1
+        },
+        Value::Object(map) => {
+            map.iter()
+                .map(|(key, item)| key.len() + 4 + estimate_len(item))
+                .sum::<usize>() + 1
+        }
+    }
+}
+
+/// The value is converted into the line as compact JSON, which never contains
+/// raw newlines, as they are escaped inside the strings.
+impl IntoLine for Value {
+    fn line_len(&self) -> usize {
+        estimate_len(self)
+    }
+
+    fn into_line(self) -> String {
+        self.to_string()
+    }
+}
+
+impl FromLine for Value {
+    fn from_line(line: &str) -> io::Result<Self> {
+        serde_json::from_str(line).map_err(Error::from)
+    }
+}
+
+/// Returns the rank of the value type in the order of the types.
+fn type_rank(value: &Value) -> u8 {
+    match value {
+        Value::Null => 0,
+        Value::Bool(_) => 1,
+        Value::Number(_) => 2,
+        Value::String(_) => 3,
+        Value::Array(_) => 4,
+        Value::Object(_) => 5
+    }
+}
+
+/// Compares two numbers by their values. The integers are compared exactly.
+fn number_cmp(a: &Number, b: &Number) -> Ordering {
+    if let (Some(a), Some(b)) = (a.as_i64(), b.as_i64()) {
+        return a.cmp(&b);
+    }
+    if let (Some(a), Some(b)) = (a.as_u64(), b.as_u64()) {
+        return a.cmp(&b);
+    }
+    let a = a.as_f64().unwrap_or(f64::NAN);
+    let b = b.as_f64().unwrap_or(f64::NAN);
+    a.total_cmp(&b)
+}
+
+/// Compares two JSON values taking their types into account.
+///
+/// The values of different types are ordered as null, booleans, numbers,
+/// strings, arrays and objects. The numbers are compared by value, so `9`
+/// goes before `10`, and `1` is equal to `1.0`. The arrays are compared
+/// lexicographically, and the objects are compared as sequences of key-value
+/// pairs in the order of their keys.
+pub fn json_cmp(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Number(a), Value::Number(b)) => number_cmp(a, b),
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Array(a), Value::Array(b)) => {
+            a.iter()
+                .zip(b.iter())
+                .map(|(a, b)| json_cmp(a, b))
+                .find(|&ord| ord != Ordering::Equal)
+                .unwrap_or_else(|| a.len().cmp(&b.len()))
+        },
+        (Value::Object(a), Value::Object(b)) => {
+            let mut a: Vec<_> = a.iter().collect();
+            let mut b: Vec<_> = b.iter().collect();
+            a.sort_by(|x, y| x.0.cmp(y.0));
+            b.sort_by(|x, y| x.0.cmp(y.0));
+            a.iter()
+                .zip(b.iter())
+                .map(|(a, b)| a.0.cmp(b.0).then_with(|| json_cmp(a.1, b.1)))
+                .find(|&ord| ord != Ordering::Equal)
+                .unwrap_or_else(|| a.len().cmp(&b.len()))
+        },
+        (a, b) => type_rank(a).cmp(&type_rank(b))
+    }
+}
+
+/// JSON value ordered by `json_cmp()`, so it can be used as the key in
+/// `by_key()`.
+#[derive(Clone, Debug)]
+pub struct JsonOrd(pub Value);
+
+impl PartialEq for JsonOrd {
+    fn eq(&self, other: &Self) -> bool {
+        json_cmp(&self.0, &other.0) == Ordering::Equal
+    }
+}
+
+impl Eq for JsonOrd {}
+
+impl PartialOrd for JsonOrd {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for JsonOrd {
+    fn cmp(&self, other: &Self) -> Ordering {
+        json_cmp(&self.0, &other.0)
+    }
+}
+
+impl IntoLine for JsonOrd {
+    fn line_len(&self) -> usize {
+        self.0.line_len()
+    }
+
+    fn into_line(self) -> String {
+        self.0.into_line()
+    }
+}
+
+impl FromLine for JsonOrd {
+    fn from_line(line: &str) -> io::Result<Self> {
+        Value::from_line(line).map(JsonOrd)
+    }
+}
+
+/// Iterator over the JSON values sorted by `sort_json_by_key()`.
+pub struct SortedJson {
+    /// Iterator over the values along with their keys
+    inner: SortedIter<KeyValue<JsonOrd, Value>>
+}
+
+impl SortedJson {
+    /// Returns the statistics collected while sorting.
+    pub fn stats(&self) -> SortStats {
+        self.inner.stats()
+    }
+}
+
+impl Iterator for SortedJson {
+    type Item = io::Result<Value>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.inner.next()?.map(|pair| pair.value))
+    }
+}
+
+/// Sorts the JSON values from `iter` by the keys projected with `key`, which
+/// are compared with `json_cmp()`. The values with equal keys keep their
+/// order.
+///
+/// The key is projected once for each value and kept next to it until the
+/// value is returned, so `key` is never called while comparing.
+pub fn sort_json_by_key<It, F>(iter: It, mut key: F,
+                               config: Config) -> io::Result<SortedJson>
+where
+    It: Iterator<Item = Value>,
+    F: FnMut(&Value) -> Value
+{
+    let pairs = iter.map(|value| KeyValue::new(JsonOrd(key(&value)), value));
+    let inner = Sort::new(config)?.sort(pairs)?;
+    Ok(SortedJson { inner })
+}
+
+/// Same as `sort_json_by_key()`, but sorts any serializable records. The
+/// records are transcoded into JSON values, so `key` projects the key from
+/// the JSON representation of the record, and the sorted values are
+/// deserialized back.
+///
+/// Returns the first error that occurred while transcoding the records.
+pub fn sort_serde_by_key<T, It, F>(
+    iter: It,
+    key: F,
+    config: Config
+) -> io::Result<impl Iterator<Item = io::Result<T>>>
+where
+    T: Serialize + DeserializeOwned,
+    It: Iterator<Item = T>,
+    F: FnMut(&Value) -> Value
+{
+    let mut error = None;
+    let values = iter.map(serde_json::to_value);
+    let sorted = sort_json_by_key(until_error(values, &mut error), key,
+                                  config)?;
+    if let Some(err) = error {
+        return Err(err.into());
+    }
+    Ok(sorted.map(|maybe_value| {
+        maybe_value.and_then(|value| {
+            serde_json::from_value(value).map_err(Error::from)
+        })
+    }))
+}
+
+/// Sorts the newline-delimited JSON values read from `reader` by the value at
+/// `json_pointer` (like `/user/name`), which are compared with `json_cmp()`,
+/// and writes them into `writer` as compact JSON, one value per line. The
+/// values without the field are sorted as if it was `null`. The empty lines
+/// are skipped.
+///
+/// Returns the statistics collected while sorting, or the first error that
+/// occurred while reading, parsing or writing the values.
+pub fn sort_ndjson<R, W>(
+    reader: R,
+    json_pointer: &str,
+    writer: W,
+    config: Config
+) -> io::Result<SortStats>
+where
+    R: BufRead,
+    W: Write
+{
+    let mut error = None;
+    let values = reader.lines()
+        .filter(|maybe_line| {
+            maybe_line.as_ref().map_or(true, |line| !line.trim().is_empty())
+        })
+        .map(|maybe_line| {
+            maybe_line.and_then(|line| Value::from_line(&line))
+        });
+    let key = |value: &Value| {
+        value.pointer(json_pointer).cloned().unwrap_or(Value::Null)
+    };
+    let sorted = sort_json_by_key(until_error(values, &mut error), key,
+                                  config)?;
+    if let Some(err) = error {
+        return Err(err);
+    }
+    let stats = sorted.stats();
+    let mut run_write = RunWriter::new(writer);
+    for maybe_value in sorted {
+        run_write.write_line(&maybe_value?.into_line())?;
+    }
+    run_write.flush()?;
+    Ok(stats)
+}
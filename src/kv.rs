@@ -0,0 +1,83 @@
+use std::io::{self, Error, ErrorKind};
+use std::cmp::Ordering;
+use super::lines::{FromLine, IntoLine};
+
+/// Key-value pair that is compared only by its key, while the value is just
+/// carried along.
+///
+/// The pair is converted into the line as `<key length>:<key><value>`, so both
+/// the key and the value may contain any characters allowed by `IntoLine`.
+#[derive(Clone, Debug)]
+pub struct KeyValue<K, V> {
+    /// Key by which the pairs are compared
+    pub key: K,
+    /// Value carried along with the key
+    pub value: V
+}
+
+impl<K, V> KeyValue<K, V> {
+    /// Creates a new pair.
+    pub fn new(key: K, value: V) -> KeyValue<K, V> {
+        KeyValue { key, value }
+    }
+
+    /// Converts the pair into a tuple.
+    pub fn into_pair(self) -> (K, V) {
+        (self.key, self.value)
+    }
+}
+
+impl<K, V> From<(K, V)> for KeyValue<K, V> {
+    fn from((key, value): (K, V)) -> KeyValue<K, V> {
+        KeyValue { key, value }
+    }
+}
+
+impl<K: PartialEq, V> PartialEq for KeyValue<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl<K: Eq, V> Eq for KeyValue<K, V> {}
+
+impl<K: PartialOrd, V> PartialOrd for KeyValue<K, V> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.key.partial_cmp(&other.key)
+    }
+}
+
+impl<K: Ord, V> Ord for KeyValue<K, V> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+impl<K: IntoLine, V: IntoLine> IntoLine for KeyValue<K, V> {
+    fn line_len(&self) -> usize {
+        let key_len = self.key.line_len();
+        key_len.line_len() + 1 + key_len + self.value.line_len()
+    }
+
+    fn into_line(self) -> String {
+        let key = self.key.into_line();
+        let value = self.value.into_line();
+        format!("{}:{}{}", key.len(), key, value)
+    }
+}
+
+impl<K: FromLine, V: FromLine> FromLine for KeyValue<K, V> {
+    fn from_line(line: &str) -> io::Result<Self> {
+        let (key_len, rest) = line.split_once(':')
+            .ok_or_else(|| Error::from(ErrorKind::InvalidInput))?;
+        let key_len = usize::from_line(key_len)?;
+        if !rest.is_char_boundary(key_len) {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+        let (key, value) = rest.split_at(key_len);
+        Ok(KeyValue {
+            key: K::from_line(key)?,
+            value: V::from_line(value)?
+        })
+    }
+}
@@ -1,7 +1,113 @@
+mod affinity;
+mod aggregate;
+#[cfg(feature = "arrow")]
+mod arrow_codec;
+#[cfg(feature = "avro")]
+mod avro;
+mod blob;
+mod block;
+mod buffer;
+mod case;
+#[cfg(feature = "icu")]
+mod collation;
+mod compact;
+mod compare;
+#[cfg(feature = "csv")]
+mod csv_sort;
+#[cfg(any(feature = "avro", feature = "icu"))]
+mod encoding;
+mod executor;
+mod float;
+mod hll;
+mod job;
+#[cfg(feature = "json")]
+mod json;
+mod kv;
 mod lines;
+mod long_path;
+mod merge;
+mod metrics;
+mod natural;
+mod output;
+mod pressure;
+#[cfg(feature = "protobuf")]
+mod protobuf;
+#[cfg(feature = "threads")]
+mod pool;
+mod radix;
+#[cfg(feature = "arrow")]
+mod record_batch;
+mod retry;
+mod run;
+mod schedule;
+mod select;
 mod sort;
+mod source;
 mod split;
+#[cfg(feature = "test-support")]
+mod test_support;
+mod throttle;
+mod tiers;
+mod tracker;
+mod tune;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
 
+pub use aggregate::{
+    Aggregate, AggregateIter, Count, Sum, Min, Max, First, Last, aggregate
+};
+#[cfg(feature = "arrow")]
+pub use arrow_codec::{ArrowCodec, codec_schema};
+#[cfg(feature = "parquet")]
+pub use arrow_codec::write_parquet_records;
+#[cfg(feature = "avro")]
+pub use avro::sort_avro;
+pub use blob::{BlobRef, BlobIter};
+pub use block::{BlockInfo, write_blocks, read_block_index, block_records};
+pub use case::CaseInsensitive;
+#[cfg(feature = "icu")]
+pub use collation::{Collation, Collated, CollatorOptions};
+pub use compact::{SortedFile, compact, compact_major};
+pub use compare::{Compare, ByKey, ThenBy, Reversed, by_key};
+#[cfg(feature = "csv")]
+pub use csv_sort::{CsvRecord, CsvColumn, sort_csv};
+pub use float::{F32Sortable, F64Sortable};
+pub use hll::{HyperLogLog, approx_count_distinct};
+#[cfg(feature = "json")]
+pub use json::{JsonOrd, SortedJson, json_cmp, sort_json_by_key,
+               sort_serde_by_key, sort_ndjson};
+pub use kv::KeyValue;
 pub use lines::{FromLine, IntoLine};
-pub use sort::{Sort, SortedIter, Config};
-pub use split::{SameSplitIter, SplitIter, split};
+#[cfg(feature = "metrics")]
+pub use metrics::Metrics;
+pub use natural::{NaturalStr, natural_cmp};
+pub use output::Compression;
+#[cfg(feature = "protobuf")]
+pub use protobuf::{Proto, read_delimited, write_delimited, sort_protobuf};
+pub use radix::{FixedWidth, RadixKey, radix_sort, radix_sort_by_key};
+#[cfg(feature = "arrow")]
+pub use record_batch::{
+    BatchSortColumn, BatchSortConfig, SortOptions, SortedBatches, SpillFormat,
+    sort_record_batches, write_arrow_ipc_lines
+};
+#[cfg(feature = "parquet")]
+pub use record_batch::write_parquet_lines;
+pub use retry::RetryPolicy;
+pub use run::{KeyRange, LineReader, RunReader, RunRecords, RunWriter};
+pub use sort::{
+    Sort, SortedIter, AssumeValid, Chunks, SortStats, Config, RecordTooLarge,
+    sort_lines
+};
+pub use source::{MergeSource, MergedIter, merge_sources, merge_newest};
+pub use split::{
+    SameSplitIter, SplitIter, SplitConfig, GroupTooLarge, split,
+    split_with_config, split_by_key, split_by_key_with_config,
+    for_each_group_parallel
+};
+#[cfg(feature = "test-support")]
+pub use test_support::{
+    assert_line_roundtrip, tiny_config, assert_sorts, assert_sorts_by
+};
+pub use tiers::{SpillDir, SpillMemory, SpillStore};
+pub use tracker::TempFileEvent;
+pub use tune::{Strategy, Suggestion};
@@ -1,4 +1,5 @@
 use std::io::{self, Error, ErrorKind};
+use std::borrow::Cow;
 use std::marker::Sized;
 
 /// Converts the value into a single line for use in sorting.
@@ -54,3 +55,82 @@ impl<T: FromLine, E: FromLine> FromLine for Result<T, E> {
         }
     }
 }
+
+impl IntoLine for String {
+    fn line_len(&self) -> usize {
+        self.len()
+    }
+
+    fn into_line(self) -> String {
+        self
+    }
+}
+
+impl FromLine for String {
+    fn from_line(line: &str) -> io::Result<Self> {
+        Ok(line.to_string())
+    }
+}
+
+impl<'a> IntoLine for Cow<'a, str> {
+    fn line_len(&self) -> usize {
+        self.len()
+    }
+
+    fn into_line(self) -> String {
+        self.into_owned()
+    }
+}
+
+/// The lines are always read back as owned strings, so the borrowed strings
+/// can be sorted with `Sort::scoped()`.
+impl<'a> FromLine for Cow<'a, str> {
+    fn from_line(line: &str) -> io::Result<Self> {
+        Ok(Cow::Owned(line.to_string()))
+    }
+}
+
+macro_rules! impl_lines_for_int {
+    ($($t:ty),*) => {$(
+        impl FromLine for $t {
+            fn from_line(line: &str) -> io::Result<Self> {
+                line.parse()
+                    .map_err(|err| Error::new(ErrorKind::InvalidInput, err))
+            }
+        }
+    )*}
+}
+
+macro_rules! impl_into_line_for_unsigned {
+    ($($t:ty),*) => {$(
+        impl IntoLine for $t {
+            fn line_len(&self) -> usize {
+                self.checked_ilog10().map_or(1, |log| log as usize + 1)
+            }
+
+            fn into_line(self) -> String {
+                self.to_string()
+            }
+        }
+    )*}
+}
+
+macro_rules! impl_into_line_for_signed {
+    ($($t:ty),*) => {$(
+        impl IntoLine for $t {
+            fn line_len(&self) -> usize {
+                let sign_len = if *self < 0 { 1 } else { 0 };
+                sign_len + self.unsigned_abs().line_len()
+            }
+
+            fn into_line(self) -> String {
+                self.to_string()
+            }
+        }
+    )*}
+}
+
+impl_lines_for_int!(u8, u16, u32, u64, u128, usize);
+impl_lines_for_int!(i8, i16, i32, i64, i128, isize);
+impl_into_line_for_unsigned!(u8, u16, u32, u64, u128, usize);
+impl_into_line_for_signed!(i8, i16, i32, i64, i128, isize);
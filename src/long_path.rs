@@ -0,0 +1,36 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Converts `path` into the absolute path with the extended-length prefix
+/// (`\\?\`), so the files in the deeply nested directories can be created
+/// and removed even if their paths are longer than `MAX_PATH`. The paths that
+/// already have the prefix, and the device paths, are kept as is.
+#[cfg(windows)]
+pub(crate) fn extend_path(path: &Path) -> io::Result<PathBuf> {
+    use std::ffi::OsString;
+    use std::os::windows::ffi::{OsStrExt, OsStringExt};
+    use std::path::{self, Component, Prefix};
+
+    // The separators are not normalized in the prefixed paths, so the path
+    // is made absolute (which normalizes it) before adding the prefix
+    let path = path::absolute(path)?;
+    let (prefix, skip) = match path.components().next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            // `\\server\share` becomes `\\?\UNC\server\share`
+            Prefix::UNC(..) => (r"\\?\UNC", 1),
+            Prefix::Disk(_) => (r"\\?\", 0),
+            _ => return Ok(path)
+        },
+        _ => return Ok(path)
+    };
+    let mut wide: Vec<u16> = prefix.encode_utf16().collect();
+    wide.extend(path.as_os_str().encode_wide().skip(skip));
+    Ok(PathBuf::from(OsString::from_wide(&wide)))
+}
+
+/// Converts `path` into the form that allows the long paths. The paths are
+/// not limited in length here, so it's kept as is.
+#[cfg(not(windows))]
+pub(crate) fn extend_path(path: &Path) -> io::Result<PathBuf> {
+    Ok(path.to_path_buf())
+}
@@ -1,33 +1,268 @@
-use std::io::{self, BufRead, BufReader};
-use extsort::{Sort, Config, FromLine, IntoLine};
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::env;
+use std::io::{self, BufReader, Error, ErrorKind};
+use std::process;
+use std::time::{Duration, Instant};
+use extsort::{Sort, Config, Compression, SortStats, natural_cmp};
 
-#[derive(Eq, PartialEq, PartialOrd, Ord)]
-struct Line(String);
+/// Options of the `bench` subcommand.
+struct BenchOptions {
+    /// Total size of the generated data in bytes
+    size: u64,
+    /// Length of each generated record in bytes, without the newline
+    record_len: usize
+}
 
-impl FromLine for Line {
-    fn from_line(line: &str) -> io::Result<Self> {
-        Ok(Line(String::from(line)))
+impl Default for BenchOptions {
+    fn default() -> BenchOptions {
+        BenchOptions { size: 100 << 20, record_len: 100 }
     }
 }
 
-impl IntoLine for Line {
-    fn line_len(&self) -> usize { self.0.len() }
-    fn into_line(self) -> String { self.0 }
+/// Command-line options.
+#[derive(Default)]
+struct Options {
+    /// Print the sorting statistics to stderr after completion
+    verbose: bool,
+    /// Compare the runs of digits as numbers
+    natural: bool,
+    /// Number of the sorting threads, or `None` for the number of CPUs
+    threads: Option<usize>,
+    /// Total size of the data kept in memory during the split phase, or
+    /// `None` for the default size per thread
+    memory: Option<u64>,
+    /// Options of the benchmark, if it's run instead of sorting stdin
+    bench: Option<BenchOptions>
+}
+
+/// Returns the error about the invalid command line.
+fn invalid_input(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+/// Default size of the data kept in memory by each thread during the split
+/// phase
+const DEFAULT_SPLIT_SIZE: usize = 5_000_000;
+
+/// Returns the value of the option `arg` from `args`.
+fn option_value<I>(arg: &str, args: &mut I) -> io::Result<String>
+where
+    I: Iterator<Item = String>
+{
+    args.next()
+        .ok_or_else(|| invalid_input(format!("{} requires a value", arg)))
+}
+
+/// Parses the size like `512`, `64KB` or `10GB`. The suffixes denote the
+/// binary multiples, so `1KB` is 1024 bytes.
+fn parse_size(arg: &str) -> io::Result<u64> {
+    let digits = arg.find(|ch: char| !ch.is_ascii_digit())
+        .unwrap_or(arg.len());
+    let (num, suffix) = arg.split_at(digits);
+    let shift = match suffix.to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" => 10,
+        "M" | "MB" => 20,
+        "G" | "GB" => 30,
+        "T" | "TB" => 40,
+        _ => return Err(invalid_input(format!("invalid size: {}", arg)))
+    };
+    num.parse::<u64>()
+        .ok()
+        .and_then(|num| num.checked_mul(1 << shift))
+        .ok_or_else(|| invalid_input(format!("invalid size: {}", arg)))
+}
+
+impl Options {
+    /// Parses the options from the command line arguments.
+    fn parse<I: Iterator<Item = String>>(args: I) -> io::Result<Options> {
+        let mut options = Options::default();
+        let mut args = args.peekable();
+        if args.peek().is_some_and(|arg| arg == "bench") {
+            args.next();
+            options.bench = Some(BenchOptions::default());
+        }
+        while let Some(arg) = args.next() {
+            let bench = options.bench.as_mut();
+            match (arg.as_str(), bench) {
+                ("-v" | "--verbose", _) => options.verbose = true,
+                ("-V" | "--natural", _) => options.natural = true,
+                ("-j" | "--threads", _) => {
+                    let value = option_value(&arg, &mut args)?;
+                    let threads = value.parse().ok()
+                        .filter(|&threads| threads > 0)
+                        .ok_or_else(|| {
+                            invalid_input(
+                                format!("invalid number of threads: {}", value)
+                            )
+                        })?;
+                    options.threads = Some(threads);
+                },
+                ("-m" | "--memory", _) => {
+                    let value = option_value(&arg, &mut args)?;
+                    options.memory = Some(parse_size(&value)?);
+                },
+                ("--size", Some(bench)) => {
+                    let value = option_value(&arg, &mut args)?;
+                    bench.size = parse_size(&value)?;
+                },
+                ("--record-len", Some(bench)) => {
+                    let value = option_value(&arg, &mut args)?;
+                    bench.record_len = value.parse().map_err(|_| {
+                        invalid_input(format!("invalid length: {}", value))
+                    })?;
+                },
+                _ => return Err(invalid_input(
+                    format!("unknown argument: {}", arg)
+                ))
+            }
+        }
+        Ok(options)
+    }
+
+    /// Returns the sorting configuration. The memory is split evenly between
+    /// the threads.
+    fn config(&self) -> Config {
+        let num_threads = self.threads.unwrap_or(Config::default().num_threads);
+        let max_split_size = match self.memory {
+            Some(memory) => {
+                let size = memory / num_threads as u64;
+                usize::try_from(size).unwrap_or(usize::MAX).max(1)
+            },
+            None => DEFAULT_SPLIT_SIZE
+        };
+        Config {
+            num_threads,
+            max_split_size,
+            max_in_flight_chunks: num_threads,
+            io_concurrency: num_threads,
+            ..Config::default()
+        }
+    }
+
+    /// Returns the function that compares the lines.
+    fn compare(&self) -> fn(&String, &String) -> Ordering {
+        if self.natural {
+            |a, b| natural_cmp(a, b)
+        } else {
+            |a, b| a.cmp(b)
+        }
+    }
+
+    /// Creates the sorter with `config`.
+    fn sort(&self, config: Config) -> io::Result<Sort<String>> {
+        if self.natural {
+            Sort::with_compare(config, self.compare())
+        } else {
+            Sort::new(config)
+        }
+    }
+}
+
+/// Prints the statistics summary to stderr.
+fn print_stats(stats: &SortStats) {
+    eprintln!("input records:      {}", stats.input_records);
+    eprintln!("runs:               {}", stats.runs);
+    eprintln!("merge passes:       {}", stats.merge_passes);
+    eprintln!("temp bytes written: {}", stats.temp_bytes_written);
+    eprintln!("split time:         {:.3}s", stats.split_time.as_secs_f64());
+    eprintln!("merge time:         {:.3}s", stats.merge_time.as_secs_f64());
+}
+
+/// Generates `count` pseudo-random lines of `len` lowercase letters.
+fn generate(count: u64, len: usize) -> impl Iterator<Item = String> {
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    (0..count).map(move |_| {
+        let mut line = String::with_capacity(len);
+        for _ in 0..len {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            line.push((b'a' + (state % 26) as u8) as char);
+        }
+        line
+    })
+}
+
+/// Prints the duration with the throughput over `size` bytes.
+fn print_phase(name: &str, time: Duration, size: u64) {
+    let secs = time.as_secs_f64();
+    let rate = size as f64 / (1 << 20) as f64 / secs.max(1e-9);
+    println!("{:<20}{:.3}s ({:.1} MiB/s)", name, secs, rate);
+}
+
+/// Sorts the generated data, checks the result and prints the throughput
+/// and the time spent in each phase.
+fn bench(options: &Options, bench: &BenchOptions,
+         config: Config) -> io::Result<()> {
+    let count = bench.size / (bench.record_len as u64 + 1);
+    let size = count * (bench.record_len as u64 + 1);
+    println!("{:<20}{}", "records:", count);
+    println!("{:<20}{}", "data size:", size);
+    println!("{:<20}{}", "threads:", config.num_threads);
+    println!("{:<20}{}", "max split size:", config.max_split_size);
+
+    let start = Instant::now();
+    let mut sorted = options.sort(config)?
+        .sort(generate(count, bench.record_len))?;
+    let sort_time = start.elapsed();
+    let stats = sorted.stats();
+
+    let compare = options.compare();
+    let output_start = Instant::now();
+    let mut prev: Option<String> = None;
+    let mut read = 0;
+    for maybe_line in &mut sorted {
+        let line = maybe_line?;
+        if prev.as_ref().is_some_and(|prev| {
+            compare(prev, &line) == Ordering::Greater
+        }) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("the output is not sorted at line {}", read)
+            ));
+        }
+        prev = Some(line);
+        read += 1;
+    }
+    if read != count {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} lines were sorted, but {} were read", count, read)
+        ));
+    }
+    let output_time = output_start.elapsed();
+
+    // The data is generated lazily, so generating it counts as splitting
+    print_phase("split:", stats.split_time, size);
+    print_phase("merge:", stats.merge_time, size);
+    print_phase("output:", output_time, size);
+    print_phase("total:", sort_time + output_time, size);
+    if options.verbose {
+        print_stats(&stats);
+    }
+    Ok(())
 }
 
 fn main() -> io::Result<()> {
-    let lines = BufReader::new(io::stdin()).lines();
-    let mut config = Config::default();
-    config.max_split_size = 5_000_000;
-    let sort = Sort::new(config)?;
-    let sorted = sort.sort(lines.map(|maybe_line| {
-        match maybe_line {
-            Ok(line) => Line(line),
-            Err(err) => panic!("I/O error: {}", err)
-        }
-    }))?;
-    for maybe_line in sorted {
-        println!("{}", maybe_line?.0);
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(2);
+        }
+    };
+    let config = options.config();
+    if let Some(bench_options) = &options.bench {
+        return bench(&options, bench_options, config);
+    }
+    let sort = options.sort(config)?;
+    let sorted = sort.sort_lines(BufReader::new(io::stdin()))?;
+    let stats = sorted.stats();
+    sorted.write_to(io::stdout().lock(), Compression::None)?;
+    if options.verbose {
+        print_stats(&stats);
     }
     Ok(())
 }
@@ -0,0 +1,314 @@
+use std::io;
+use std::cmp::{self, Ordering};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use super::compare::Compare;
+
+/// Number of consecutive wins of the same source iterator after which the
+/// merge switches to the galloping mode
+const MIN_GALLOP: usize = 7;
+
+/// Number of elements buffered from the winning source iterator in the
+/// galloping mode
+const GALLOP_BLOCK: usize = 256;
+
+/// Function that combines two equal elements into one.
+pub(crate) type Combiner<T> = Arc<dyn Fn(T, T) -> T + Send + Sync>;
+
+/// Defines what happens with the equal elements.
+pub(crate) enum Duplicates<T> {
+    /// All the elements are kept
+    Keep,
+    /// Only the first one of the equal elements is kept
+    Drop,
+    /// The equal elements are combined into one
+    Combine(Combiner<T>)
+}
+
+impl<T> Clone for Duplicates<T> {
+    fn clone(&self) -> Duplicates<T> {
+        match self {
+            Duplicates::Keep => Duplicates::Keep,
+            Duplicates::Drop => Duplicates::Drop,
+            Duplicates::Combine(combine) => Duplicates::Combine(combine.clone())
+        }
+    }
+}
+
+impl<T> Duplicates<T> {
+    /// Checks whether the equal elements must be kept as is.
+    pub fn keep(&self) -> bool {
+        matches!(self, Duplicates::Keep)
+    }
+
+    /// Joins two equal elements into one. It must not be called if the
+    /// elements are kept.
+    pub fn join(&self, first: T, second: T) -> T {
+        match self {
+            Duplicates::Keep => unreachable!("equal elements must be kept"),
+            Duplicates::Drop => first,
+            Duplicates::Combine(combine) => combine(first, second)
+        }
+    }
+
+    /// Adds `data` after `last`, the previous element in the sorted order.
+    /// If they are equal and are not kept, they are joined into `last`.
+    /// Otherwise, `data` replaces `last`, and the old value of `last` is
+    /// returned, as no more elements can be joined into it.
+    pub fn push(&self, compare: &dyn Compare<T>, last: &mut Option<T>,
+                data: T) -> Option<T> {
+        match last.take() {
+            Some(prev) if !self.keep() &&
+                compare.compare(&prev, &data) == Ordering::Equal => {
+                *last = Some(self.join(prev, data));
+                None
+            },
+            prev => {
+                *last = Some(data);
+                prev
+            }
+        }
+    }
+
+    /// Drops or combines the adjacent equal elements in `data_vec`.
+    pub fn apply(&self, compare: &dyn Compare<T>, data_vec: Vec<T>) -> Vec<T> {
+        if self.keep() {
+            return data_vec;
+        }
+        let mut result: Vec<T> = Vec::with_capacity(data_vec.len());
+        for data in data_vec {
+            let equal = match result.last() {
+                Some(last) => compare.compare(last, &data) == Ordering::Equal,
+                None => false
+            };
+            if equal {
+                let last = result.pop().unwrap();
+                result.push(self.join(last, data));
+            } else {
+                result.push(data);
+            }
+        }
+        result
+    }
+}
+
+/// Iterator that merges several sorted iterators into one sorted iterator.
+pub(crate) struct MergeIter<I, T> {
+    /// Source iterators
+    iters: Vec<I>,
+    /// Binary heap that contains the next element from each of the source
+    /// iterators along with the index of this iterator. The smallest element
+    /// is on top
+    heap: Vec<(T, usize)>,
+    /// Comparator that defines the order of the elements
+    compare: Arc<dyn Compare<T>>,
+    /// Defines what happens with the equal elements
+    duplicates: Duplicates<T>,
+    /// Elements already read from each of the source iterators, but not yet
+    /// put into the heap
+    buffers: Vec<VecDeque<T>>,
+    /// Index of the source iterator that won the last time
+    run_idx: usize,
+    /// Number of elements in front of `buffers[run_idx]` that go before all
+    /// the elements in the heap, so they are returned without comparisons.
+    /// While it's non-zero, the source `run_idx` has no element in the heap
+    run_len: usize,
+    /// Number of consecutive wins of the source iterator `run_idx`
+    wins: usize
+}
+
+impl<I, T> MergeIter<I, T>
+where
+    I: Iterator<Item = io::Result<T>>
+{
+    /// Creates a new iterator that merges `iters`. The equal elements are
+    /// returned as is, dropped or combined as defined by `duplicates`.
+    pub fn new(iters: Vec<I>, compare: Arc<dyn Compare<T>>,
+               duplicates: Duplicates<T>) -> io::Result<MergeIter<I, T>> {
+        let heap = Vec::with_capacity(iters.len());
+        let buffers = iters.iter().map(|_| VecDeque::new()).collect();
+        let mut merge_iter = MergeIter {
+            iters,
+            heap,
+            compare,
+            duplicates,
+            buffers,
+            run_idx: 0,
+            run_len: 0,
+            wins: 0
+        };
+        for idx in 0..merge_iter.iters.len() {
+            merge_iter.refill(idx)?;
+        }
+        Ok(merge_iter)
+    }
+
+    /// Checks whether the heap entry `a` must be taken before `b`. The entries
+    /// with equal elements are taken in the order of their iterators, so the
+    /// merge is stable.
+    fn less(&self, a: &(T, usize), b: &(T, usize)) -> bool {
+        Self::less_by(&*self.compare, (&a.0, a.1), (&b.0, b.1))
+    }
+
+    /// Same as `less()`, but takes the comparator explicitly, so it can be
+    /// used while a part of `self` is borrowed.
+    fn less_by(compare: &dyn Compare<T>, a: (&T, usize),
+               b: (&T, usize)) -> bool {
+        match compare.compare(a.0, b.0) {
+            Ordering::Less => true,
+            Ordering::Greater => false,
+            Ordering::Equal => a.1 < b.1
+        }
+    }
+
+    /// Pushes the entry into the heap.
+    fn push(&mut self, entry: (T, usize)) {
+        self.heap.push(entry);
+        let mut pos = self.heap.len() - 1;
+        while pos > 0 {
+            let parent = (pos - 1) / 2;
+            if !self.less(&self.heap[pos], &self.heap[parent]) {
+                break;
+            }
+            self.heap.swap(pos, parent);
+            pos = parent;
+        }
+    }
+
+    /// Takes the smallest entry from the heap.
+    fn pop(&mut self) -> Option<(T, usize)> {
+        if self.heap.is_empty() {
+            return None;
+        }
+        let top = self.heap.swap_remove(0);
+        let len = self.heap.len();
+        let mut pos = 0;
+        loop {
+            let mut best = pos;
+            for child in [2 * pos + 1, 2 * pos + 2] {
+                if child < len && self.less(&self.heap[child], &self.heap[best]) {
+                    best = child;
+                }
+            }
+            if best == pos {
+                break;
+            }
+            self.heap.swap(pos, best);
+            pos = best;
+        }
+        Some(top)
+    }
+
+    /// Takes the next element from the iterator with index `idx` and puts it
+    /// into the heap.
+    fn refill(&mut self, idx: usize) -> io::Result<()> {
+        let next = match self.buffers[idx].pop_front() {
+            Some(data) => Some(data),
+            None => self.iters[idx].next().transpose()?
+        };
+        if let Some(data) = next {
+            self.push((data, idx));
+        }
+        Ok(())
+    }
+
+    /// Reads a block of elements from the iterator with index `idx`, which has
+    /// no element in the heap, and finds how many of them go before the top of
+    /// the heap. The search is exponential, so the long runs are found with a
+    /// few comparisons, as in TimSort.
+    fn gallop(&mut self, idx: usize) -> io::Result<()> {
+        let buffer = &mut self.buffers[idx];
+        while buffer.len() < GALLOP_BLOCK {
+            match self.iters[idx].next() {
+                Some(maybe_data) => buffer.push_back(maybe_data?),
+                None => break
+            }
+        }
+
+        let top = &self.heap[0];
+        let compare = &*self.compare;
+        let before = |data: &T| {
+            Self::less_by(compare, (data, idx), (&top.0, top.1))
+        };
+        let block = buffer.make_contiguous();
+        let mut bound = 1;
+        while bound <= block.len() && before(&block[bound - 1]) {
+            bound *= 2;
+        }
+        let first = bound / 2;
+        let last = cmp::min(bound - 1, block.len());
+        self.run_len = first + block[first..last].partition_point(before);
+        if self.run_len < MIN_GALLOP {
+            self.wins = 0;
+        }
+        Ok(())
+    }
+
+    /// Takes the smallest element from all the source iterators.
+    fn take(&mut self) -> io::Result<Option<T>> {
+        if self.run_len > 0 {
+            self.run_len -= 1;
+            let data = self.buffers[self.run_idx].pop_front();
+            if self.run_len == 0 {
+                self.refill(self.run_idx)?;
+            }
+            return Ok(data);
+        }
+
+        let (data, idx) = match self.pop() {
+            Some(entry) => entry,
+            None => return Ok(None)
+        };
+        if idx == self.run_idx {
+            self.wins += 1;
+        } else {
+            self.run_idx = idx;
+            self.wins = 1;
+        }
+        if self.wins >= MIN_GALLOP && !self.heap.is_empty() {
+            self.gallop(idx)?;
+        }
+        if self.run_len == 0 {
+            self.refill(idx)?;
+        }
+        Ok(Some(data))
+    }
+
+    /// Checks whether the next smallest element is equal to `data`.
+    fn next_equals(&self, data: &T) -> bool {
+        let next = if self.run_len > 0 {
+            self.buffers[self.run_idx].front()
+        } else {
+            self.heap.first().map(|top| &top.0)
+        };
+        match next {
+            Some(next) => self.compare.compare(next, data) == Ordering::Equal,
+            None => false
+        }
+    }
+}
+
+impl<I, T> Iterator for MergeIter<I, T>
+where
+    I: Iterator<Item = io::Result<T>>
+{
+    type Item = io::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut data = match self.take() {
+            Ok(data) => data?,
+            Err(err) => return Some(Err(err))
+        };
+        if !self.duplicates.keep() {
+            while self.next_equals(&data) {
+                match self.take() {
+                    Ok(next) => {
+                        data = self.duplicates.join(data, next.unwrap());
+                    },
+                    Err(err) => return Some(Err(err))
+                }
+            }
+        }
+        Some(Ok(data))
+    }
+}
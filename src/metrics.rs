@@ -0,0 +1,125 @@
+#[cfg(feature = "metrics")]
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Metrics of the sorter that can be exported to the monitoring systems.
+///
+/// One instance may be shared by several sorters, so the counters and the
+/// gauges are summed over all of them. The metrics are updated while sorting,
+/// and can be read at any time from another thread.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Number of records taken from the inputs
+    records: AtomicU64,
+    /// Number of bytes written into the temporary files
+    bytes_spilled: AtomicU64,
+    /// Number of the merge jobs running now
+    active_merges: AtomicU64,
+    /// Number of bytes in the temporary files that exist now
+    temp_disk_usage: AtomicU64
+}
+
+/// Merge job counted in `Metrics`, which is finished when dropped.
+pub(crate) struct ActiveMerge(Arc<Metrics>);
+
+impl Metrics {
+    /// Creates the metrics with all the values set to zero.
+    #[cfg(feature = "metrics")]
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Returns the number of records taken from the inputs.
+    #[cfg(feature = "metrics")]
+    pub fn records_processed(&self) -> u64 {
+        self.records.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes written into the temporary files.
+    #[cfg(feature = "metrics")]
+    pub fn bytes_spilled(&self) -> u64 {
+        self.bytes_spilled.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of the merge jobs running now.
+    #[cfg(feature = "metrics")]
+    pub fn active_merge_jobs(&self) -> u64 {
+        self.active_merges.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes in the temporary files that exist now.
+    #[cfg(feature = "metrics")]
+    pub fn temp_disk_usage(&self) -> u64 {
+        self.temp_disk_usage.load(Ordering::Relaxed)
+    }
+
+    /// Writes the metrics into `writer` in the Prometheus text exposition
+    /// format, with the names prefixed by `prefix` (like `extsort`), so they
+    /// can be served from the existing metrics endpoint.
+    #[cfg(feature = "metrics")]
+    pub fn write_prometheus<W: Write>(&self, mut writer: W,
+                                      prefix: &str) -> io::Result<()> {
+        let metrics = [
+            ("records_processed_total", "counter",
+             "Number of records taken from the inputs",
+             self.records_processed()),
+            ("spilled_bytes_total", "counter",
+             "Number of bytes written into the temporary files",
+             self.bytes_spilled()),
+            ("active_merge_jobs", "gauge",
+             "Number of the merge jobs running now",
+             self.active_merge_jobs()),
+            ("temp_disk_usage_bytes", "gauge",
+             "Number of bytes in the temporary files that exist now",
+             self.temp_disk_usage())
+        ];
+        for (name, kind, help, value) in metrics {
+            writeln!(writer, "# HELP {}_{} {}", prefix, name, help)?;
+            writeln!(writer, "# TYPE {}_{} {}", prefix, name, kind)?;
+            writeln!(writer, "{}_{} {}", prefix, name, value)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the metrics in the Prometheus text exposition format. See
+    /// `write_prometheus()` for details.
+    #[cfg(feature = "metrics")]
+    pub fn to_prometheus(&self, prefix: &str) -> String {
+        let mut text = Vec::new();
+        self.write_prometheus(&mut text, prefix).unwrap();
+        String::from_utf8(text).unwrap()
+    }
+
+    /// Counts `count` records taken from the input.
+    pub(crate) fn add_records(&self, count: u64) {
+        self.records.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Counts `bytes` written into the temporary files.
+    pub(crate) fn add_spilled(&self, bytes: u64) {
+        self.bytes_spilled.fetch_add(bytes, Ordering::Relaxed);
+        self.temp_disk_usage.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts `bytes` of the temporary files removed.
+    pub(crate) fn sub_temp_usage(&self, bytes: u64) {
+        let _ = self.temp_disk_usage.fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |usage| Some(usage.saturating_sub(bytes))
+        );
+    }
+
+    /// Counts the merge job until the returned value is dropped.
+    pub(crate) fn start_merge(self: &Arc<Self>) -> ActiveMerge {
+        self.active_merges.fetch_add(1, Ordering::Relaxed);
+        ActiveMerge(self.clone())
+    }
+}
+
+impl Drop for ActiveMerge {
+    fn drop(&mut self) {
+        self.0.active_merges.fetch_sub(1, Ordering::Relaxed);
+    }
+}
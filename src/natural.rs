@@ -0,0 +1,92 @@
+use std::io;
+use std::cmp::Ordering;
+use super::lines::{FromLine, IntoLine};
+
+/// Splits the leading run of ASCII digits from `s`.
+fn split_digits(s: &[u8]) -> (&[u8], &[u8]) {
+    let len = s.iter().take_while(|c| c.is_ascii_digit()).count();
+    s.split_at(len)
+}
+
+/// Removes the leading zeros from the run of digits.
+fn trim_zeros(s: &[u8]) -> &[u8] {
+    let zeros = s.iter().take_while(|&&c| c == b'0').count();
+    &s[zeros..]
+}
+
+/// Compares two runs of digits numerically.
+fn compare_numbers(a: &[u8], b: &[u8]) -> Ordering {
+    let (a, b) = (trim_zeros(a), trim_zeros(b));
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+/// Compares two strings in natural order, i.e. the runs of digits are compared
+/// as numbers, so `"file2"` goes before `"file10"`. The strings that differ
+/// only in leading zeros are compared as usual strings.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a_rest, mut b_rest) = (a.as_bytes(), b.as_bytes());
+    loop {
+        let (a_char, b_char) = match (a_rest.first(), b_rest.first()) {
+            (None, None) => break,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(&a_char), Some(&b_char)) => (a_char, b_char)
+        };
+        if a_char.is_ascii_digit() && b_char.is_ascii_digit() {
+            let (a_num, a_tail) = split_digits(a_rest);
+            let (b_num, b_tail) = split_digits(b_rest);
+            let ord = compare_numbers(a_num, b_num);
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            a_rest = a_tail;
+            b_rest = b_tail;
+        } else {
+            if a_char != b_char {
+                return a_char.cmp(&b_char);
+            }
+            a_rest = &a_rest[1..];
+            b_rest = &b_rest[1..];
+        }
+    }
+    a.cmp(b)
+}
+
+/// String wrapper that is sorted in natural order, as defined by
+/// `natural_cmp()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct NaturalStr(pub String);
+
+impl From<String> for NaturalStr {
+    fn from(s: String) -> NaturalStr {
+        NaturalStr(s)
+    }
+}
+
+impl PartialOrd for NaturalStr {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NaturalStr {
+    fn cmp(&self, other: &Self) -> Ordering {
+        natural_cmp(&self.0, &other.0)
+    }
+}
+
+impl IntoLine for NaturalStr {
+    fn line_len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn into_line(self) -> String {
+        self.0
+    }
+}
+
+impl FromLine for NaturalStr {
+    fn from_line(line: &str) -> io::Result<Self> {
+        Ok(NaturalStr(line.to_string()))
+    }
+}
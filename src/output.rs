@@ -0,0 +1,60 @@
+use std::io::{self, Write};
+use super::lines::IntoLine;
+use super::run::RunWriter;
+
+/// Compression of the sorted output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// The output is written as is
+    #[default]
+    None,
+    /// The output is compressed with gzip at the given level (from 0 to 9)
+    #[cfg(feature = "gzip")]
+    Gzip(u32),
+    /// The output is compressed with zstd at the given level (from 1 to 22,
+    /// or 0 for the default one)
+    #[cfg(feature = "zstd")]
+    Zstd(i32)
+}
+
+/// Writes the records from `iter` into `writer` as lines. Returns `writer`
+/// after all the lines are written.
+fn write_into<I, T, W>(iter: I, writer: W) -> io::Result<W>
+where
+    I: Iterator<Item = io::Result<T>>,
+    T: IntoLine,
+    W: Write
+{
+    let mut run_write = RunWriter::new(writer);
+    for maybe_data in iter {
+        run_write.write_line(&maybe_data?.into_line())?;
+    }
+    run_write.into_inner()
+}
+
+/// Writes the records from `iter` into `writer` as lines, compressing them
+/// with `compression`.
+pub(crate) fn write_lines<I, T, W>(iter: I, writer: W,
+                                   compression: Compression) -> io::Result<()>
+where
+    I: Iterator<Item = io::Result<T>>,
+    T: IntoLine,
+    W: Write
+{
+    match compression {
+        Compression::None => {
+            write_into(iter, writer)?.flush()
+        },
+        #[cfg(feature = "gzip")]
+        Compression::Gzip(level) => {
+            let level = flate2::Compression::new(level);
+            let encoder = flate2::write::GzEncoder::new(writer, level);
+            write_into(iter, encoder)?.finish()?.flush()
+        },
+        #[cfg(feature = "zstd")]
+        Compression::Zstd(level) => {
+            let encoder = zstd::Encoder::new(writer, level)?;
+            write_into(iter, encoder)?.finish()?.flush()
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use threadpool::ThreadPool;
+use std::io;
+use std::mem;
+use std::sync::{Mutex, Arc};
+use super::affinity::{CpuSet, pin_current_thread};
+
+type ResultCell = Arc<Mutex<io::Result<()>>>;
+
+/// Thread pool that runs the jobs and keeps track of their results.
+pub(crate) struct Pool {
+    /// Thread pool used to run the jobs
+    pool: ThreadPool,
+    /// A cell that contains the result of the jobs in the thread pool.
+    /// It contains `Ok(())` if all the jobs succeeded, and the first error
+    /// otherwise.
+    result_cell: ResultCell,
+    /// CPUs the threads are pinned to
+    cpus: CpuSet
+}
+
+impl Pool {
+    /// Creates a new pool with `num_threads` threads, which are pinned to
+    /// `cpus` while running the jobs.
+    pub fn new(num_threads: usize, cpus: CpuSet) -> Pool {
+        Pool {
+            pool: ThreadPool::new(num_threads),
+            result_cell: Arc::new(Mutex::new(Ok(()))),
+            cpus
+        }
+    }
+
+    /// Adds a job to the thread pool, updating `result_cell` accordingly.
+    pub fn add<F>(&self, f: F)
+    where
+        F: FnOnce() -> io::Result<()> + Send + 'static
+    {
+        let res_cell = self.result_cell.clone();
+        let cpus = self.cpus.clone();
+        self.pool.execute(move || {
+            let result = pin_current_thread(cpus.as_deref()).and_then(|_| f());
+            let error = match result {
+                Ok(_) => return,
+                Err(err) => err
+            };
+            let mut guard = match Mutex::try_lock(&res_cell) {
+                Ok(guard) => guard,
+                Err(_) => return
+            };
+            if guard.is_ok() {
+                *guard = Err(error);
+            }
+        });
+    }
+
+    /// Finishes all the currently added jobs in the thread pool. Returns the
+    /// first error that occurred in the jobs, if any.
+    pub fn join(&self) -> io::Result<()> {
+        self.pool.join();
+        if self.pool.panic_count() != 0 {
+            panic!("Some of the threads in the pool panicked.");
+        }
+        let mut result = Mutex::lock(&self.result_cell).unwrap();
+        mem::replace(&mut result, Ok(()))
+    }
+}
@@ -0,0 +1,70 @@
+use std::cmp;
+#[cfg(target_os = "linux")]
+use std::fs;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Maximum number of times the chunk size and the number of chunks in flight
+/// are halved under the memory pressure
+const MAX_LEVEL: u32 = 4;
+
+/// Number of bytes added to the chunk between the checks of the available
+/// memory
+pub(crate) const CHECK_INTERVAL: usize = 1 << 20;
+
+/// Returns the amount of memory available for starting new applications
+/// without swapping, in bytes, or `None` if it's unknown.
+#[cfg(target_os = "linux")]
+fn available_memory() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let kib = meminfo.lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim_end()
+        .parse::<u64>()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+/// Returns the amount of memory available for starting new applications
+/// without swapping. It's known only on Linux, so it's always `None` here.
+#[cfg(not(target_os = "linux"))]
+fn available_memory() -> Option<u64> {
+    None
+}
+
+/// Tracks the memory pressure during the split phase.
+///
+/// The level of pressure rises by one on each check that finds less than
+/// `min_available` bytes available, and falls by one on each check that
+/// finds enough, so the sorter backs off quickly and recovers gradually.
+pub(crate) struct Pressure {
+    /// Amount of available memory below which the pressure rises
+    min_available: u64,
+    /// Current level of pressure
+    level: AtomicU32
+}
+
+impl Pressure {
+    /// Creates a tracker that keeps `min_available` bytes of memory
+    /// available.
+    pub fn new(min_available: u64) -> Pressure {
+        Pressure { min_available, level: AtomicU32::new(0) }
+    }
+
+    /// Checks the available memory and updates the level of pressure. Returns
+    /// the new level, by which the chunk size and the number of chunks in
+    /// flight are shifted right.
+    pub fn check(&self) -> u32 {
+        let level = self.level.load(Ordering::Relaxed);
+        let level = match available_memory() {
+            Some(available) if available < self.min_available => {
+                cmp::min(level + 1, MAX_LEVEL)
+            },
+            Some(_) => level.saturating_sub(1),
+            None => level
+        };
+        self.level.store(level, Ordering::Relaxed);
+        level
+    }
+}
@@ -0,0 +1,251 @@
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write, Error};
+use std::io::ErrorKind;
+use std::cmp::Ordering;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use prost::Message;
+use super::compare::Compare;
+use super::lines::{FromLine, IntoLine};
+use super::run::{DecodedRecords, KeyRange, RecordWriter, RunCodec};
+use super::sort::{Sort, SortStats, Config, until_error};
+use super::throttle::Throttled;
+use super::tiers::{SpillReader, SpillWriter};
+
+/// Maximum length of the varint-encoded `u64`
+const MAX_VARINT_LEN: usize = 10;
+
+/// Maximum length of the message in bytes, which is the limit of the
+/// protobuf encoding
+const MAX_MESSAGE_LEN: u64 = 2 << 30;
+
+/// Protobuf message that can be sorted by `Sort`.
+///
+/// The messages have no line form, so the sorter must write the temporary
+/// files with `Sort::with_protobuf_runs()`, which stores them with the
+/// length-delimited framing. The line of the message is its debug form, which
+/// is only used to describe the runs in `SortStats::run_ranges`, and it can't
+/// be parsed back.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Proto<M>(pub M);
+
+impl<M: Message + Debug> IntoLine for Proto<M> {
+    fn line_len(&self) -> usize {
+        self.0.encoded_len()
+    }
+
+    fn into_line(self) -> String {
+        format!("{:?}", self.0)
+    }
+}
+
+impl<M> FromLine for Proto<M> {
+    fn from_line(_line: &str) -> io::Result<Self> {
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            "protobuf messages are stored only by Sort::with_protobuf_runs()"
+        ))
+    }
+}
+
+/// Converts the `prost` decoding error into `io::Error`.
+fn decode_error(err: prost::DecodeError) -> Error {
+    Error::new(ErrorKind::InvalidData, err)
+}
+
+/// Comparator that compares `Proto` wrappers by the messages inside.
+struct ByMessage<C>(C);
+
+impl<M, C: Compare<M>> Compare<Proto<M>> for ByMessage<C> {
+    fn compare(&self, a: &Proto<M>, b: &Proto<M>) -> Ordering {
+        self.0.compare(&a.0, &b.0)
+    }
+}
+
+/// Format of the run files that stores the messages with the length-delimited
+/// framing, like `write_delimited()` does.
+pub(crate) struct DelimitedRuns<M>(PhantomData<fn() -> M>);
+
+impl<M> DelimitedRuns<M> {
+    /// Creates the format for the messages of type `M`.
+    pub fn new() -> DelimitedRuns<M> {
+        DelimitedRuns(PhantomData)
+    }
+}
+
+/// Writer of the messages into a run file.
+struct DelimitedRunWriter<M> {
+    /// The underlying writer
+    writer: BufWriter<Throttled<SpillWriter>>,
+    /// Buffer with the frame of the last message written
+    buf: Vec<u8>,
+    /// Number of the bytes written
+    len: u64,
+    /// Number of the messages written
+    records: u64,
+    /// First message of the run as a line
+    first: Option<String>,
+    _marker: PhantomData<fn(M)>
+}
+
+impl<M> RecordWriter<Proto<M>> for DelimitedRunWriter<M>
+where
+    M: Message + Debug + Default
+{
+    fn write_record(&mut self, data: Proto<M>) -> io::Result<()> {
+        self.buf.clear();
+        data.0.encode_length_delimited(&mut self.buf).map_err(Error::other)?;
+        self.writer.write_all(&self.buf)?;
+        if self.first.is_none() {
+            self.first = Some(data.into_line());
+        }
+        self.len += self.buf.len() as u64;
+        self.records += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<(u64, Option<KeyRange>)> {
+        self.writer.flush()?;
+        let first = match self.first.take() {
+            Some(first) => first,
+            None => return Ok((self.len, None))
+        };
+        let last = M::decode_length_delimited(&self.buf[..])
+            .map_err(decode_error)?;
+        Ok((self.len, Some(KeyRange {
+            records: self.records,
+            first,
+            last: Proto(last).into_line()
+        })))
+    }
+}
+
+impl<M> RunCodec<Proto<M>> for DelimitedRuns<M>
+where
+    M: Message + Debug + Default + 'static
+{
+    fn writer(
+        &self,
+        file: Throttled<SpillWriter>
+    ) -> io::Result<Box<dyn RecordWriter<Proto<M>>>> {
+        Ok(Box::new(DelimitedRunWriter {
+            writer: BufWriter::new(file),
+            buf: Vec::new(),
+            len: 0,
+            records: 0,
+            first: None,
+            _marker: PhantomData
+        }))
+    }
+
+    fn records(
+        &self,
+        file: SpillReader
+    ) -> io::Result<DecodedRecords<Proto<M>>> {
+        let messages = read_delimited(BufReader::new(file));
+        Ok(Box::new(messages.map(|maybe_msg| maybe_msg.map(Proto))))
+    }
+}
+
+/// Reads the varint length prefix from `reader`. Returns `None` if the reader
+/// is at the end of the stream.
+fn read_varint<R: BufRead>(reader: &mut R) -> io::Result<Option<u64>> {
+    let mut value = 0;
+    for idx in 0..MAX_VARINT_LEN {
+        let byte = match reader.fill_buf()?.first() {
+            Some(&byte) => byte,
+            None if idx == 0 => return Ok(None),
+            None => return Err(Error::from(ErrorKind::UnexpectedEof))
+        };
+        reader.consume(1);
+        value |= u64::from(byte & 0x7f) << (7 * idx);
+        if byte & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err(Error::new(ErrorKind::InvalidData, "varint is too long"))
+}
+
+/// Iterates over the messages read from `reader`. Each message is prefixed
+/// with its length encoded as varint, as written by
+/// `Message::encode_length_delimited()`. The messages longer than 2 GiB,
+/// which protobuf doesn't allow, are rejected with `ErrorKind::InvalidData`.
+pub fn read_delimited<M, R>(
+    mut reader: R
+) -> impl Iterator<Item = io::Result<M>>
+where
+    M: Message + Default,
+    R: BufRead
+{
+    let mut buf = Vec::new();
+    let mut failed = false;
+    std::iter::from_fn(move || {
+        if failed {
+            return None;
+        }
+        let result = read_varint(&mut reader).and_then(|maybe_len| {
+            let len = match maybe_len {
+                Some(len) if len <= MAX_MESSAGE_LEN => len,
+                Some(len) => return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("message of {} bytes is too long", len)
+                )),
+                None => return Ok(None)
+            };
+            // The buffer grows as the data is read, so the corrupt length
+            // doesn't allocate the memory for the data that isn't there
+            buf.clear();
+            reader.by_ref().take(len).read_to_end(&mut buf)?;
+            if (buf.len() as u64) < len {
+                return Err(Error::from(ErrorKind::UnexpectedEof));
+            }
+            M::decode(&buf[..]).map(Some).map_err(decode_error)
+        });
+        failed = result.is_err();
+        result.transpose()
+    })
+}
+
+/// Writes the messages from `iter` into `writer`, prefixing each one with its
+/// length encoded as varint, so they can be read by `read_delimited()`.
+pub fn write_delimited<I, M, W>(iter: I, mut writer: W) -> io::Result<()>
+where
+    I: Iterator<Item = io::Result<M>>,
+    M: Message,
+    W: Write
+{
+    for maybe_msg in iter {
+        writer.write_all(&maybe_msg?.encode_length_delimited_to_vec())?;
+    }
+    writer.flush()
+}
+
+/// Sorts the length-delimited messages read from `reader` with `compare` and
+/// writes them into `writer` in the same framing. Returns the statistics
+/// collected while sorting, or the first error that occurred while reading,
+/// decoding or writing the messages.
+pub fn sort_protobuf<M, R, W, C>(
+    reader: R,
+    writer: W,
+    compare: C,
+    config: Config
+) -> io::Result<SortStats>
+where
+    M: Message + Debug + Default + 'static,
+    R: BufRead,
+    W: Write,
+    C: Compare<M> + 'static
+{
+    let mut error = None;
+    let messages = read_delimited(reader)
+        .map(|maybe_msg| maybe_msg.map(Proto));
+    let sorted = Sort::with_compare(config, ByMessage(compare))?
+        .with_protobuf_runs()
+        .sort(until_error(messages, &mut error))?;
+    if let Some(err) = error {
+        return Err(err);
+    }
+    let stats = sorted.stats();
+    let messages = sorted.map(|maybe_proto| maybe_proto.map(|proto| proto.0));
+    write_delimited(messages, writer)?;
+    Ok(stats)
+}
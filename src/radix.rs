@@ -0,0 +1,282 @@
+use std::convert::TryInto;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::marker::PhantomData;
+use super::lines::IntoLine;
+use super::run::{DecodedRecords, KeyRange, RecordWriter, RunCodec};
+use super::throttle::Throttled;
+use super::tiers::{SpillReader, SpillWriter};
+
+/// Number of bits in one digit of the radix sort
+const DIGIT_BITS: usize = 8;
+
+/// Number of buckets for one digit
+const NUM_BUCKETS: usize = 1 << DIGIT_BITS;
+
+/// Number of digits in the 64-bit key
+const NUM_DIGITS: usize = 64 / DIGIT_BITS;
+
+/// Fixed-width integer key that can be used in radix sort.
+pub trait RadixKey {
+    /// Converts the value into a 64-bit unsigned key, such that the order of
+    /// the keys is the same as the order of the values.
+    fn radix_key(&self) -> u64;
+}
+
+macro_rules! impl_radix_key_for_unsigned {
+    ($($t:ty),*) => {$(
+        impl RadixKey for $t {
+            fn radix_key(&self) -> u64 {
+                *self as u64
+            }
+        }
+    )*}
+}
+
+macro_rules! impl_radix_key_for_signed {
+    ($($t:ty),*) => {$(
+        impl RadixKey for $t {
+            fn radix_key(&self) -> u64 {
+                // Flipping the sign bit puts the negative values first
+                (*self as i64 as u64) ^ (1 << 63)
+            }
+        }
+    )*}
+}
+
+impl_radix_key_for_unsigned!(u8, u16, u32, u64, usize);
+impl_radix_key_for_signed!(i8, i16, i32, i64, isize);
+
+/// Value that is stored in the binary run files as a fixed number of bytes.
+pub trait FixedWidth: Sized {
+    /// Number of bytes in the stored value
+    const WIDTH: usize;
+
+    /// Appends the `WIDTH` bytes of the value to `buf`.
+    fn write_bytes(&self, buf: &mut Vec<u8>);
+
+    /// Restores the value from the `WIDTH` bytes written by `write_bytes()`.
+    fn from_bytes(bytes: &[u8]) -> Self;
+}
+
+macro_rules! impl_fixed_width {
+    ($($t:ty),*) => {$(
+        impl FixedWidth for $t {
+            const WIDTH: usize = std::mem::size_of::<$t>();
+
+            fn write_bytes(&self, buf: &mut Vec<u8>) {
+                buf.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn from_bytes(bytes: &[u8]) -> $t {
+                <$t>::from_le_bytes(bytes.try_into().unwrap())
+            }
+        }
+    )*}
+}
+
+impl_fixed_width!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+/// Sorts `data` by the keys extracted with `key_fn` using LSD radix sort.
+/// The digits that are equal for all the keys are skipped.
+fn lsd_sort<T, F>(data: &mut [T], key_fn: F)
+where
+    T: Copy,
+    F: Fn(&T) -> u64
+{
+    if data.len() < 2 {
+        return;
+    }
+    let mut counts = [[0usize; NUM_BUCKETS]; NUM_DIGITS];
+    for item in data.iter() {
+        let key = key_fn(item);
+        for (digit, count) in counts.iter_mut().enumerate() {
+            count[(key >> (digit * DIGIT_BITS)) as usize % NUM_BUCKETS] += 1;
+        }
+    }
+
+    let mut buffer = data.to_vec();
+    // Indicates whether the latest pass has written into `buffer`
+    let mut in_buffer = false;
+    for (digit, count) in counts.iter().enumerate() {
+        if count.contains(&data.len()) {
+            continue;
+        }
+        let mut offsets = [0usize; NUM_BUCKETS];
+        for bucket in 1..NUM_BUCKETS {
+            offsets[bucket] = offsets[bucket - 1] + count[bucket - 1];
+        }
+        let (src, dst) = if in_buffer {
+            (&buffer[..], &mut data[..])
+        } else {
+            (&data[..], &mut buffer[..])
+        };
+        let shift = digit * DIGIT_BITS;
+        for item in src {
+            let bucket = (key_fn(item) >> shift) as usize % NUM_BUCKETS;
+            dst[offsets[bucket]] = *item;
+            offsets[bucket] += 1;
+        }
+        in_buffer = !in_buffer;
+    }
+    if in_buffer {
+        data.copy_from_slice(&buffer);
+    }
+}
+
+/// Rearranges `data` in place, so the element at position `i` becomes the one
+/// that was at position `order[i]`.
+fn apply_order<T>(data: &mut [T], mut order: Vec<usize>) {
+    for start in 0..data.len() {
+        let mut cur = start;
+        loop {
+            let next = order[cur];
+            order[cur] = cur;
+            if next == start {
+                break;
+            }
+            data.swap(cur, next);
+            cur = next;
+        }
+    }
+}
+
+/// Sorts `data` by the key extracted with `key_fn` using LSD radix sort. The
+/// sort is stable and takes linear time. It's usually faster than the
+/// comparison sort on large chunks of integers with a narrow range, as the
+/// digits that are equal for all the keys are skipped.
+pub fn radix_sort_by_key<T, K, F>(data: &mut [T], key_fn: F)
+where
+    K: RadixKey,
+    F: Fn(&T) -> K
+{
+    let mut keys: Vec<_> = data.iter()
+        .enumerate()
+        .map(|(idx, item)| (key_fn(item).radix_key(), idx))
+        .collect();
+    lsd_sort(&mut keys, |&(key, _)| key);
+    apply_order(data, keys.into_iter().map(|(_, idx)| idx).collect());
+}
+
+/// Sorts `data` using LSD radix sort. Unlike `radix_sort_by_key()`, the
+/// elements are moved directly, without sorting their indices first.
+pub fn radix_sort<T: RadixKey + Copy>(data: &mut [T]) {
+    lsd_sort(data, T::radix_key);
+}
+
+/// Format of the run files that stores each record in `FixedWidth::WIDTH`
+/// bytes instead of a line, so the records are neither formatted nor parsed.
+pub(crate) struct BinaryRuns<T>(PhantomData<fn() -> T>);
+
+impl<T> BinaryRuns<T> {
+    /// Creates the format for the records of type `T`.
+    pub fn new() -> BinaryRuns<T> {
+        BinaryRuns(PhantomData)
+    }
+}
+
+/// Writer of the records into a binary run file.
+struct BinaryRunWriter<T> {
+    /// The underlying writer
+    writer: BufWriter<Throttled<SpillWriter>>,
+    /// Buffer for the bytes of the current record
+    buf: Vec<u8>,
+    /// Number of the records written
+    records: u64,
+    /// Bytes of the first record, if any
+    first: Vec<u8>,
+    _marker: PhantomData<fn(T)>
+}
+
+impl<T: FixedWidth + IntoLine> RecordWriter<T> for BinaryRunWriter<T> {
+    fn write_record(&mut self, data: T) -> io::Result<()> {
+        self.buf.clear();
+        data.write_bytes(&mut self.buf);
+        self.writer.write_all(&self.buf)?;
+        if self.records == 0 {
+            self.first = self.buf.clone();
+        }
+        self.records += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<(u64, Option<KeyRange>)> {
+        self.writer.flush()?;
+        let len = self.records * T::WIDTH as u64;
+        if self.records == 0 {
+            return Ok((len, None));
+        }
+        Ok((len, Some(KeyRange {
+            records: self.records,
+            first: T::from_bytes(&self.first).into_line(),
+            last: T::from_bytes(&self.buf).into_line()
+        })))
+    }
+}
+
+/// Iterator over the records in a binary run file.
+struct BinaryRecords<T> {
+    /// Reader of the file
+    reader: BufReader<SpillReader>,
+    /// Buffer for the bytes of the current record
+    buf: Vec<u8>,
+    _marker: PhantomData<fn() -> T>
+}
+
+impl<T: FixedWidth> BinaryRecords<T> {
+    /// Reads the bytes of the next record into the buffer. Returns `false` at
+    /// the end of the file.
+    fn read_bytes(&mut self) -> io::Result<bool> {
+        let mut filled = 0;
+        while filled < self.buf.len() {
+            match self.reader.read(&mut self.buf[filled..]) {
+                Ok(0) if filled == 0 => return Ok(false),
+                Ok(0) => return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "truncated record in the run file"
+                )),
+                Ok(len) => filled += len,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {},
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<T: FixedWidth> Iterator for BinaryRecords<T> {
+    type Item = io::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_bytes() {
+            Ok(true) => Some(Ok(T::from_bytes(&self.buf))),
+            Ok(false) => None,
+            Err(err) => Some(Err(err))
+        }
+    }
+}
+
+impl<T> RunCodec<T> for BinaryRuns<T>
+where
+    T: FixedWidth + IntoLine + Send + 'static
+{
+    fn writer(
+        &self,
+        file: Throttled<SpillWriter>
+    ) -> io::Result<Box<dyn RecordWriter<T>>> {
+        Ok(Box::new(BinaryRunWriter {
+            writer: BufWriter::new(file),
+            buf: Vec::with_capacity(T::WIDTH),
+            records: 0,
+            first: Vec::new(),
+            _marker: PhantomData
+        }))
+    }
+
+    fn records(&self, file: SpillReader) -> io::Result<DecodedRecords<T>> {
+        Ok(Box::new(BinaryRecords::<T> {
+            reader: BufReader::new(file),
+            buf: vec![0; T::WIDTH],
+            _marker: PhantomData
+        }))
+    }
+}
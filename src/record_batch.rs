@@ -0,0 +1,588 @@
+use std::io::{self, BufReader, BufWriter, Write, Error};
+use std::fs::File;
+use std::cmp::{self, Reverse};
+use std::collections::BinaryHeap;
+use std::env;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use arrow::array::{ArrayRef, StringArray, UInt32Array};
+use arrow::compute;
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::error::ArrowError;
+use arrow::ipc::reader::FileReader;
+use arrow::ipc::writer::{FileWriter, StreamWriter};
+use arrow::record_batch::RecordBatch;
+use arrow::row::{OwnedRow, RowConverter, Rows, SortField};
+#[cfg(feature = "parquet")]
+use parquet::arrow::ArrowWriter;
+#[cfg(feature = "parquet")]
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+#[cfg(feature = "parquet")]
+use parquet::errors::ParquetError;
+use super::lines::IntoLine;
+use super::long_path::extend_path;
+use super::retry::RetryPolicy;
+use tempfile::{Builder, TempDir};
+use super::sort::{DEFAULT_MEMORY, DEFAULT_MAX_OPEN_FILES};
+
+pub use arrow::compute::SortOptions;
+
+/// Default number of rows in the batches
+pub(crate) const DEFAULT_BATCH_SIZE: usize = 8192;
+
+/// Converts the Arrow error into `io::Error`.
+pub(crate) fn arrow_error(err: ArrowError) -> Error {
+    match err {
+        ArrowError::IoError(_, err) => err,
+        err => Error::other(err)
+    }
+}
+
+/// Converts the Parquet error into `io::Error`.
+#[cfg(feature = "parquet")]
+pub(crate) fn parquet_error(err: ParquetError) -> Error {
+    Error::other(err)
+}
+
+/// Reader of the batches from the temporary file.
+type BatchReader =
+    Box<dyn Iterator<Item = Result<RecordBatch, ArrowError>> + Send>;
+
+/// Format of the temporary files with the sorted runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpillFormat {
+    /// Arrow IPC file format
+    Ipc,
+    /// Parquet file format
+    #[cfg(feature = "parquet")]
+    Parquet
+}
+
+/// Column to sort the record batches by.
+#[derive(Clone, Debug)]
+pub struct BatchSortColumn {
+    /// Name of the column in the schema
+    pub name: String,
+    /// Order of the values in the column
+    pub options: SortOptions
+}
+
+impl BatchSortColumn {
+    /// Sorts by the column in ascending order, with the nulls first.
+    pub fn asc(name: &str) -> BatchSortColumn {
+        BatchSortColumn {
+            name: name.to_string(),
+            options: SortOptions { descending: false, nulls_first: true }
+        }
+    }
+
+    /// Sorts by the column in descending order, with the nulls first.
+    pub fn desc(name: &str) -> BatchSortColumn {
+        BatchSortColumn {
+            name: name.to_string(),
+            options: SortOptions { descending: true, nulls_first: true }
+        }
+    }
+}
+
+/// Struct that represents configuration of the record batch sorter.
+#[derive(Clone, Debug)]
+pub struct BatchSortConfig {
+    /// Total size of the batches (in bytes) kept in memory before they are
+    /// sorted and spilled into a temporary file
+    pub max_memory: usize,
+    /// Number of rows in the batches written into the temporary files and
+    /// returned by `SortedBatches`
+    pub batch_size: usize,
+    /// Format of the temporary files
+    pub spill_format: SpillFormat,
+    /// Maximum number of the temporary files merged at once (at least 2). If
+    /// there are more runs, they are merged in several passes
+    pub max_open_files: usize,
+    /// Policy of retrying the operations on the temporary files that failed
+    /// with a transient error
+    pub retry: RetryPolicy
+}
+
+impl Default for BatchSortConfig {
+    fn default() -> BatchSortConfig {
+        BatchSortConfig {
+            max_memory: DEFAULT_MEMORY,
+            batch_size: DEFAULT_BATCH_SIZE,
+            spill_format: SpillFormat::Ipc,
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            retry: RetryPolicy::default()
+        }
+    }
+}
+
+/// Sorted run in the temporary file, from which the rows are merged.
+struct RunCursor {
+    /// Reader of the temporary file
+    reader: BatchReader,
+    /// Current batch of the run
+    batch: RecordBatch,
+    /// Sort keys of the rows in `batch`
+    rows: Rows,
+    /// Number of the next row in `batch`
+    pos: usize,
+    /// Index of `batch` among the batches used to build the output batch
+    slot: usize
+}
+
+/// Merger of the sorted runs.
+struct RunMerger {
+    /// Runs that are not finished yet
+    cursors: Vec<Option<RunCursor>>,
+    /// Sort key of the next row of each run along with the run number. The
+    /// smallest key is on top, the earlier run wins the ties
+    heap: BinaryHeap<Reverse<(OwnedRow, usize)>>
+}
+
+/// Source of the sorted batches.
+enum Source {
+    /// All the rows fit into memory and are sorted in one batch
+    Memory {
+        /// The sorted rows
+        batch: RecordBatch,
+        /// Number of the first row that is not returned yet
+        pos: usize
+    },
+    /// The rows are merged from the temporary files
+    Runs(RunMerger)
+}
+
+/// The iterator over sorted record batches, created by
+/// `sort_record_batches()`.
+pub struct SortedBatches {
+    /// Temporary directory holder, which keeps the runs until the iteration
+    /// is finished
+    _tmpdir: TempDir,
+    /// Schema of the batches
+    schema: SchemaRef,
+    /// Converter of the sort columns into the comparable rows
+    sorter: KeySorter,
+    /// Number of rows in the returned batches
+    batch_size: usize,
+    /// Source of the sorted batches
+    source: Source
+}
+
+/// Computes the sort keys of the batches.
+struct KeySorter {
+    /// Converter of the sort columns into the comparable rows
+    converter: RowConverter,
+    /// Indices of the sort columns in the schema
+    columns: Vec<usize>
+}
+
+impl KeySorter {
+    /// Computes the sort keys of the rows in `batch`.
+    fn rows(&self, batch: &RecordBatch) -> io::Result<Rows> {
+        let columns: Vec<ArrayRef> = self.columns.iter()
+            .map(|&idx| batch.column(idx).clone())
+            .collect();
+        self.converter.convert_columns(&columns).map_err(arrow_error)
+    }
+
+    /// Sorts the rows of `batch`. The sort is stable.
+    fn sort(&self, batch: &RecordBatch) -> io::Result<RecordBatch> {
+        let rows = self.rows(batch)?;
+        let mut indices: Vec<u32> = (0..batch.num_rows() as u32).collect();
+        indices.sort_by(|&a, &b| {
+            rows.row(a as usize).cmp(&rows.row(b as usize))
+        });
+        compute::take_record_batch(batch, &UInt32Array::from(indices))
+            .map_err(arrow_error)
+    }
+}
+
+impl RunCursor {
+    /// Loads the next non-empty batch from the run. Returns `false` if the
+    /// run has ended.
+    fn load(&mut self, sorter: &KeySorter) -> io::Result<bool> {
+        for maybe_batch in &mut self.reader {
+            let batch = maybe_batch.map_err(arrow_error)?;
+            if batch.num_rows() != 0 {
+                self.rows = sorter.rows(&batch)?;
+                self.batch = batch;
+                self.pos = 0;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Returns the sort key of the next row.
+    fn key(&self) -> OwnedRow {
+        self.rows.row(self.pos).owned()
+    }
+}
+
+/// Splits `batch` into the slices of `batch_size` rows.
+fn slices(batch: &RecordBatch,
+          batch_size: usize) -> impl Iterator<Item = RecordBatch> + '_ {
+    (0..batch.num_rows()).step_by(batch_size).map(move |offset| {
+        batch.slice(offset, cmp::min(batch_size, batch.num_rows() - offset))
+    })
+}
+
+/// Writes the `batches` with `schema` into the file at `path` in the format
+/// set by `config`.
+fn write_batches<I>(path: &Path, schema: &SchemaRef, config: &BatchSortConfig,
+                    batches: I) -> io::Result<()>
+where
+    I: Iterator<Item = io::Result<RecordBatch>>
+{
+    let file = BufWriter::new(config.retry.run(|| File::create(path))?);
+    match config.spill_format {
+        SpillFormat::Ipc => {
+            let mut writer = FileWriter::try_new(file, schema)
+                .map_err(arrow_error)?;
+            for maybe_batch in batches {
+                writer.write(&maybe_batch?).map_err(arrow_error)?;
+            }
+            writer.finish().map_err(arrow_error)
+        },
+        #[cfg(feature = "parquet")]
+        SpillFormat::Parquet => {
+            let mut writer = ArrowWriter::try_new(file, schema.clone(), None)
+                .map_err(parquet_error)?;
+            for maybe_batch in batches {
+                writer.write(&maybe_batch?).map_err(parquet_error)?;
+            }
+            writer.close().map_err(parquet_error)?;
+            Ok(())
+        }
+    }
+}
+
+/// Sorts the `batches` with `schema` by `columns` and writes them into the
+/// temporary file at `path` as a run.
+fn write_run(batches: &[RecordBatch], schema: &SchemaRef, sorter: &KeySorter,
+             path: &Path, config: &BatchSortConfig) -> io::Result<()> {
+    let batch = compute::concat_batches(schema, batches).map_err(arrow_error)?;
+    let sorted = sorter.sort(&batch)?;
+    let batches = slices(&sorted, config.batch_size).map(Ok);
+    write_batches(path, schema, config, batches)
+}
+
+/// Opens the run written into the file at `path`.
+fn open_run(path: &Path, schema: &SchemaRef, sorter: &KeySorter,
+            config: &BatchSortConfig) -> io::Result<RunCursor> {
+    let file = config.retry.run(|| File::open(path))?;
+    let reader: BatchReader = match config.spill_format {
+        SpillFormat::Ipc => {
+            let file = BufReader::new(file);
+            Box::new(FileReader::try_new(file, None).map_err(arrow_error)?)
+        },
+        #[cfg(feature = "parquet")]
+        SpillFormat::Parquet => {
+            let reader = ParquetRecordBatchReaderBuilder::try_new(file)
+                .map_err(parquet_error)?;
+            Box::new(reader.with_batch_size(config.batch_size)
+                .build()
+                .map_err(parquet_error)?)
+        }
+    };
+    Ok(RunCursor {
+        reader,
+        rows: sorter.rows(&RecordBatch::new_empty(schema.clone()))?,
+        batch: RecordBatch::new_empty(schema.clone()),
+        pos: 0,
+        slot: 0
+    })
+}
+
+impl RunMerger {
+    /// Starts merging the runs from `cursors`.
+    fn new(cursors: Vec<RunCursor>,
+           sorter: &KeySorter) -> io::Result<RunMerger> {
+        let mut heap = BinaryHeap::new();
+        let mut live = Vec::new();
+        for (num, mut cursor) in cursors.into_iter().enumerate() {
+            if cursor.load(sorter)? {
+                heap.push(Reverse((cursor.key(), num)));
+                live.push(Some(cursor));
+            } else {
+                live.push(None);
+            }
+        }
+        Ok(RunMerger { cursors: live, heap })
+    }
+
+    /// Merges the next `batch_size` rows from the runs.
+    fn next_batch(&mut self, sorter: &KeySorter,
+                  batch_size: usize) -> io::Result<Option<RecordBatch>> {
+        let mut batches = Vec::new();
+        for cursor in self.cursors.iter_mut().flatten() {
+            cursor.slot = batches.len();
+            batches.push(cursor.batch.clone());
+        }
+        let mut indices = Vec::with_capacity(batch_size);
+        while indices.len() < batch_size {
+            let num = match self.heap.pop() {
+                Some(Reverse((_, num))) => num,
+                None => break
+            };
+            let cursor = self.cursors[num].as_mut().unwrap();
+            indices.push((cursor.slot, cursor.pos));
+            cursor.pos += 1;
+            if cursor.pos == cursor.batch.num_rows() {
+                if !cursor.load(sorter)? {
+                    self.cursors[num] = None;
+                    continue;
+                }
+                cursor.slot = batches.len();
+                batches.push(cursor.batch.clone());
+            }
+            self.heap.push(Reverse((cursor.key(), num)));
+        }
+        if indices.is_empty() {
+            return Ok(None);
+        }
+        let batches: Vec<_> = batches.iter().collect();
+        compute::interleave_record_batch(&batches, &indices)
+            .map(Some)
+            .map_err(arrow_error)
+    }
+}
+
+/// Merges the runs from the files at `paths` in several passes until at most
+/// `config.max_open_files` of them are left. Each pass merges the groups of
+/// `config.max_open_files` adjacent runs, so the rows with the equal keys keep
+/// their order.
+fn merge_runs(mut paths: Vec<PathBuf>, schema: &SchemaRef,
+              sorter: &KeySorter, tmpdir: &TempDir,
+              config: &BatchSortConfig) -> io::Result<Vec<PathBuf>> {
+    let max_files = config.max_open_files;
+    let mut next_num = paths.len();
+    while paths.len() > max_files {
+        let mut merged = Vec::new();
+        for group in paths.chunks(max_files) {
+            if group.len() == 1 {
+                merged.push(group[0].clone());
+                continue;
+            }
+            let cursors = group.iter()
+                .map(|path| open_run(path, schema, sorter, config))
+                .collect::<io::Result<_>>()?;
+            let mut merger = RunMerger::new(cursors, sorter)?;
+            let path = tmpdir.path().join(format!("run-{}", next_num));
+            next_num += 1;
+            write_batches(&path, schema, config, iter::from_fn(|| {
+                merger.next_batch(sorter, config.batch_size).transpose()
+            }))?;
+            drop(merger);
+            for path in group {
+                config.retry.remove_file(path)?;
+            }
+            merged.push(path);
+        }
+        paths = merged;
+    }
+    Ok(paths)
+}
+
+/// Sorts the record batches from `batches` with `schema` by `columns`, so the
+/// crate can serve as the external sort operator in Arrow-based engines. The
+/// sort is stable.
+///
+/// The batches are collected until their size exceeds `config.max_memory`,
+/// then they are sorted and spilled into a temporary file in the format set
+/// by `config.spill_format`.
+/// The spilled runs are merged on the fly by the returned iterator. If there
+/// are more than `config.max_open_files` runs, they are first merged into the
+/// larger ones in several passes. If all the batches fit into memory, nothing
+/// is spilled.
+pub fn sort_record_batches<I>(
+    batches: I,
+    schema: SchemaRef,
+    columns: &[BatchSortColumn],
+    mut config: BatchSortConfig
+) -> io::Result<SortedBatches>
+where
+    I: Iterator<Item = Result<RecordBatch, ArrowError>>
+{
+    config.batch_size = cmp::max(config.batch_size, 1);
+    config.max_open_files = cmp::max(config.max_open_files, 2);
+    let mut fields = Vec::new();
+    let mut indices = Vec::new();
+    for column in columns {
+        let idx = schema.index_of(&column.name).map_err(arrow_error)?;
+        let data_type = schema.field(idx).data_type().clone();
+        fields.push(SortField::new_with_options(data_type, column.options));
+        indices.push(idx);
+    }
+    let sorter = KeySorter {
+        converter: RowConverter::new(fields).map_err(arrow_error)?,
+        columns: indices
+    };
+    let tmpdir = Builder::new().prefix("extsort")
+        .tempdir_in(extend_path(&env::temp_dir())?)?;
+
+    let mut cur_batches = Vec::new();
+    let mut cur_size = 0;
+    let mut paths = Vec::new();
+    for maybe_batch in batches {
+        let batch = maybe_batch.map_err(arrow_error)?;
+        cur_size += batch.get_array_memory_size();
+        cur_batches.push(batch);
+        if cur_size > config.max_memory {
+            let path = tmpdir.path().join(format!("run-{}", paths.len()));
+            write_run(&cur_batches, &schema, &sorter, &path, &config)?;
+            paths.push(path);
+            cur_batches.clear();
+            cur_size = 0;
+        }
+    }
+
+    let source = if paths.is_empty() {
+        let batch = compute::concat_batches(&schema, &cur_batches)
+            .map_err(arrow_error)?;
+        Source::Memory { batch: sorter.sort(&batch)?, pos: 0 }
+    } else {
+        if !cur_batches.is_empty() {
+            let path = tmpdir.path().join(format!("run-{}", paths.len()));
+            write_run(&cur_batches, &schema, &sorter, &path, &config)?;
+            paths.push(path);
+        }
+        let paths = merge_runs(paths, &schema, &sorter, &tmpdir, &config)?;
+        let cursors = paths.iter()
+            .map(|path| open_run(path, &schema, &sorter, &config))
+            .collect::<io::Result<_>>()?;
+        Source::Runs(RunMerger::new(cursors, &sorter)?)
+    };
+    Ok(SortedBatches {
+        _tmpdir: tmpdir,
+        schema,
+        sorter,
+        batch_size: config.batch_size,
+        source
+    })
+}
+
+impl SortedBatches {
+    /// Returns the schema of the batches.
+    pub fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    /// Writes all the sorted batches into `writer` as a Parquet file.
+    #[cfg(feature = "parquet")]
+    pub fn write_parquet<W: Write + Send>(self, writer: W) -> io::Result<()> {
+        let mut writer = ArrowWriter::try_new(writer, self.schema(), None)
+            .map_err(parquet_error)?;
+        for maybe_batch in self {
+            writer.write(&maybe_batch?).map_err(parquet_error)?;
+        }
+        writer.close().map_err(parquet_error)?;
+        Ok(())
+    }
+
+    /// Writes all the sorted batches into `writer` as an Arrow IPC stream.
+    pub fn write_arrow_ipc<W: Write>(self, writer: W) -> io::Result<()> {
+        let schema = self.schema();
+        let mut writer = StreamWriter::try_new(writer, &schema)
+            .map_err(arrow_error)?;
+        for maybe_batch in self {
+            writer.write(&maybe_batch?).map_err(arrow_error)?;
+        }
+        writer.finish().map_err(arrow_error)
+    }
+}
+
+impl Iterator for SortedBatches {
+    type Item = io::Result<RecordBatch>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.source {
+            Source::Memory { batch, pos } => {
+                if *pos == batch.num_rows() {
+                    return None;
+                }
+                let len = cmp::min(self.batch_size, batch.num_rows() - *pos);
+                let result = batch.slice(*pos, len);
+                *pos += len;
+                Some(Ok(result))
+            },
+            Source::Runs(merger) => {
+                merger.next_batch(&self.sorter, self.batch_size).transpose()
+            }
+        }
+    }
+}
+
+/// Returns the schema of the batches built from the lines, which has one
+/// non-nullable string column `line`.
+fn line_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![Field::new("line", DataType::Utf8, false)]))
+}
+
+/// Converts the records from `iter` into the batches of `batch_size` rows,
+/// each containing the result of `IntoLine::into_line()`.
+fn line_batches<I, T>(
+    iter: I,
+    batch_size: usize
+) -> impl Iterator<Item = io::Result<RecordBatch>>
+where
+    I: Iterator<Item = io::Result<T>>,
+    T: IntoLine
+{
+    let schema = line_schema();
+    let batch_size = cmp::max(batch_size, 1);
+    let mut iter = iter.peekable();
+    iter::from_fn(move || {
+        iter.peek()?;
+        let lines = iter.by_ref()
+            .take(batch_size)
+            .map(|maybe_data| maybe_data.map(T::into_line))
+            .collect::<io::Result<Vec<_>>>();
+        Some(lines.and_then(|lines| {
+            let column = Arc::new(StringArray::from(lines));
+            RecordBatch::try_new(schema.clone(), vec![column])
+                .map_err(arrow_error)
+        }))
+    })
+}
+
+/// Writes the records from `iter` into `writer` as a Parquet file. The
+/// schema is derived from the line representation of the records, so the
+/// file has one non-nullable string column `line`, and each row contains the
+/// result of `IntoLine::into_line()`. It's intended to store the output of
+/// `Sort`, which keeps the elements as lines.
+#[cfg(feature = "parquet")]
+pub fn write_parquet_lines<I, T, W>(iter: I, writer: W,
+                                    batch_size: usize) -> io::Result<()>
+where
+    I: Iterator<Item = io::Result<T>>,
+    T: IntoLine,
+    W: Write + Send
+{
+    let mut writer = ArrowWriter::try_new(writer, line_schema(), None)
+        .map_err(parquet_error)?;
+    for maybe_batch in line_batches(iter, batch_size) {
+        writer.write(&maybe_batch?).map_err(parquet_error)?;
+    }
+    writer.close().map_err(parquet_error)?;
+    Ok(())
+}
+
+/// Writes the records from `iter` into `writer` as an Arrow IPC stream. Like
+/// in `write_parquet_lines()`, the stream has one non-nullable string column
+/// `line`.
+pub fn write_arrow_ipc_lines<I, T, W>(iter: I, writer: W,
+                                      batch_size: usize) -> io::Result<()>
+where
+    I: Iterator<Item = io::Result<T>>,
+    T: IntoLine,
+    W: Write
+{
+    let mut writer = StreamWriter::try_new(writer, &line_schema())
+        .map_err(arrow_error)?;
+    for maybe_batch in line_batches(iter, batch_size) {
+        writer.write(&maybe_batch?).map_err(arrow_error)?;
+    }
+    writer.finish().map_err(arrow_error)
+}
@@ -0,0 +1,91 @@
+use std::cmp;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// Code of the I/O error (`EIO`) on the Unix systems, which the network
+/// filesystems return on the short outages
+#[cfg(unix)]
+const EIO: i32 = 5;
+
+/// Policy of retrying the operations on the temporary files (creating,
+/// opening and removing them) that failed with a transient error, like an
+/// interrupted system call or an I/O error of a network filesystem.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts of each operation, including the first one.
+    /// With `1`, the operations are not retried
+    pub max_attempts: u32,
+    /// Delay before the first retry. It's doubled before each next retry
+    pub initial_backoff: Duration,
+    /// Maximum delay before a retry
+    pub max_backoff: Duration
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1)
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Creates the policy that makes up to `max_attempts` attempts with the
+    /// default delays.
+    pub fn with_attempts(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy { max_attempts, ..RetryPolicy::default() }
+    }
+
+    /// Performs the operation, retrying it while it fails with a transient
+    /// error and the attempts remain. Returns the last error otherwise.
+    pub(crate) fn run<R, F>(&self, mut op: F) -> io::Result<R>
+    where
+        F: FnMut() -> io::Result<R>
+    {
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(err) if attempt < self.max_attempts
+                    && is_transient(&err) => {
+                    thread::sleep(backoff);
+                    backoff = cmp::min(backoff * 2, self.max_backoff);
+                    attempt += 1;
+                },
+                result => return result
+            }
+        }
+    }
+
+    /// Removes the file, retrying the transient errors like `run()`. The file
+    /// is missing after a retry if the failed attempt has removed it anyway,
+    /// so that's not an error.
+    pub(crate) fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut retried = false;
+        self.run(|| match fs::remove_file(path) {
+            Err(err) if retried && err.kind() == ErrorKind::NotFound => Ok(()),
+            result => {
+                retried = true;
+                result
+            }
+        })
+    }
+}
+
+/// Checks whether the error is transient, so the operation may succeed if
+/// it's retried.
+fn is_transient(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    if err.raw_os_error() == Some(EIO) {
+        return true;
+    }
+    matches!(
+        err.kind(),
+        ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+    )
+}
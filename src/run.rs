@@ -0,0 +1,342 @@
+use std::io::{self, Read, Write, IoSlice, Error, ErrorKind};
+use std::marker::PhantomData;
+#[cfg(feature = "mmap")]
+use std::cmp;
+use std::str;
+#[cfg(feature = "mmap")]
+use std::fs::File;
+use memchr::memchr;
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+use super::lines::{FromLine, IntoLine};
+use super::throttle::Throttled;
+use super::tiers::{SpillReader, SpillWriter};
+
+/// Initial size of the buffer used by `RunReader`
+const BUF_SIZE: usize = 1 << 16;
+
+/// Size of the buffer in which `RunWriter` accumulates the lines
+const WRITE_BUF_SIZE: usize = 1 << 18;
+
+/// Length of the line after which `RunWriter` writes it directly, without
+/// copying into the buffer
+const LARGE_LINE: usize = WRITE_BUF_SIZE / 4;
+
+/// Source of the lines in a run file.
+pub trait LineReader {
+    /// Returns the next line without the trailing newline, or `None` if the
+    /// data has ended.
+    fn next_line(&mut self) -> io::Result<Option<&str>>;
+
+    /// Reads the next line and converts it into the element, or returns
+    /// `None` if the data has ended.
+    fn next_record<T: FromLine>(&mut self) -> Option<io::Result<T>> {
+        match self.next_line() {
+            Ok(Some(line)) => Some(T::from_line(line)),
+            Ok(None) => None,
+            Err(err) => Some(Err(err))
+        }
+    }
+}
+
+/// Converts the bytes of the line into `&str`.
+fn line_str(line: &[u8]) -> io::Result<&str> {
+    str::from_utf8(line).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+}
+
+/// Reader that splits the data into lines. The newlines are found with
+/// `memchr` over a large buffer, and the lines are returned as slices of this
+/// buffer, so no memory is allocated per line.
+///
+/// It reads the run files written by `RunWriter`, and can be used with it to
+/// build custom pipelines, like own merge scheduling, from the same
+/// primitives the sorter uses.
+pub struct RunReader<R> {
+    /// Source of the data
+    reader: R,
+    /// Buffer with the data read from `reader`
+    buf: Vec<u8>,
+    /// Position of the first unprocessed byte in `buf`
+    pos: usize,
+    /// Position after the last valid byte in `buf`
+    end: usize,
+    /// Indicates whether `reader` has reached the end
+    eof: bool
+}
+
+impl<R: Read> RunReader<R> {
+    /// Creates a new reader over `reader`.
+    pub fn new(reader: R) -> RunReader<R> {
+        RunReader {
+            reader,
+            buf: vec![0; BUF_SIZE],
+            pos: 0,
+            end: 0,
+            eof: false
+        }
+    }
+
+    /// Converts the reader into the iterator over the records of type `T`.
+    pub fn records<T: FromLine>(self) -> RunRecords<R, T> {
+        RunRecords { reader: self, _marker: PhantomData }
+    }
+
+    /// Moves the unprocessed data to the beginning of the buffer and reads
+    /// more data after it. The buffer grows if the unprocessed data occupies
+    /// all of it.
+    fn fill(&mut self) -> io::Result<()> {
+        self.buf.copy_within(self.pos..self.end, 0);
+        self.end -= self.pos;
+        self.pos = 0;
+        if self.end == self.buf.len() {
+            self.buf.resize(2 * self.buf.len(), 0);
+        }
+        match self.reader.read(&mut self.buf[self.end..]) {
+            Ok(0) => self.eof = true,
+            Ok(len) => self.end += len,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {},
+            Err(err) => return Err(err)
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> LineReader for RunReader<R> {
+    fn next_line(&mut self) -> io::Result<Option<&str>> {
+        let (start, len) = loop {
+            let data = &self.buf[self.pos..self.end];
+            if let Some(len) = memchr(b'\n', data) {
+                let start = self.pos;
+                self.pos += len + 1;
+                break (start, len);
+            }
+            if self.eof {
+                if data.is_empty() {
+                    return Ok(None);
+                }
+                let start = self.pos;
+                self.pos = self.end;
+                break (start, data.len());
+            }
+            self.fill()?;
+        };
+        line_str(&self.buf[start..start + len]).map(Some)
+    }
+}
+
+/// Iterator over the records read by `RunReader`, created by
+/// `RunReader::records()`.
+pub struct RunRecords<R, T> {
+    /// The underlying reader
+    reader: RunReader<R>,
+    _marker: PhantomData<T>
+}
+
+impl<R: Read, T: FromLine> Iterator for RunRecords<R, T> {
+    type Item = io::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.next_record()
+    }
+}
+
+/// Reader that splits the memory-mapped file into lines, so the data is read
+/// without syscalls and without copying it into a buffer.
+#[cfg(feature = "mmap")]
+pub(crate) struct MapReader {
+    /// Mapped contents of the file
+    map: Mmap,
+    /// Position of the first unprocessed byte
+    pos: usize
+}
+
+#[cfg(feature = "mmap")]
+impl MapReader {
+    /// Maps `file` into memory.
+    pub fn new(file: &File) -> io::Result<MapReader> {
+        // The run files are private to the sorter and are never modified
+        // while they are being read
+        let map = unsafe { Mmap::map(file)? };
+        Ok(MapReader { map, pos: 0 })
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl LineReader for MapReader {
+    fn next_line(&mut self) -> io::Result<Option<&str>> {
+        let data = &self.map[self.pos..];
+        if data.is_empty() {
+            return Ok(None);
+        }
+        let len = memchr(b'\n', data).unwrap_or(data.len());
+        // The last line may have no newline after it
+        self.pos += cmp::min(len + 1, data.len());
+        line_str(&data[..len]).map(Some)
+    }
+}
+
+/// Writes all the `slices` into `writer` with `write_vectored()`.
+fn write_all_vectored<W: Write>(writer: &mut W,
+                                mut slices: &mut [IoSlice]) -> io::Result<()> {
+    while !slices.is_empty() {
+        match writer.write_vectored(slices) {
+            Ok(0) => return Err(Error::from(ErrorKind::WriteZero)),
+            Ok(len) => IoSlice::advance_slices(&mut slices, len),
+            Err(err) if err.kind() == ErrorKind::Interrupted => {},
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(())
+}
+
+/// Range of the keys in a sorted run, given by its first and last lines, which
+/// are the minimum and the maximum keys. The readers looking for a range of
+/// keys can skip the runs that don't intersect it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyRange {
+    /// Number of records in the run
+    pub records: u64,
+    /// First line of the run
+    pub first: String,
+    /// Last line of the run
+    pub last: String
+}
+
+/// Writer that writes the lines into the run file. The lines are accumulated
+/// in a large buffer and are written in big batches, while the large lines are
+/// written together with the buffer by one `write_vectored()` call, so they
+/// are never copied.
+///
+/// The accumulated lines are written only by `flush()` or `into_inner()`, so
+/// one of them must be called after the last line.
+pub struct RunWriter<W: Write> {
+    /// Destination of the data
+    writer: W,
+    /// Lines that are not written yet, each followed by a newline
+    buf: Vec<u8>,
+    /// Range of the records written by `write_record()`
+    range: Option<KeyRange>
+}
+
+impl<W: Write> RunWriter<W> {
+    /// Creates a new writer into `writer`.
+    pub fn new(writer: W) -> RunWriter<W> {
+        RunWriter {
+            writer,
+            buf: Vec::with_capacity(WRITE_BUF_SIZE),
+            range: None
+        }
+    }
+
+    /// Writes the line followed by a newline. Returns the number of bytes
+    /// written. The line must not contain `'\n'`.
+    pub fn write_line(&mut self, line: &str) -> io::Result<u64> {
+        let line = line.as_bytes();
+        if line.len() >= LARGE_LINE {
+            let mut slices = [
+                IoSlice::new(&self.buf),
+                IoSlice::new(line),
+                IoSlice::new(b"\n")
+            ];
+            write_all_vectored(&mut self.writer, &mut slices)?;
+            self.buf.clear();
+        } else {
+            if self.buf.len() + line.len() >= WRITE_BUF_SIZE {
+                self.write_buf()?;
+            }
+            self.buf.extend_from_slice(line);
+            self.buf.push(b'\n');
+        }
+        Ok(line.len() as u64 + 1)
+    }
+
+    /// Writes the record as a line and extends the range of the written
+    /// records with it. Returns the number of bytes written.
+    pub fn write_record<T: IntoLine>(&mut self, data: T) -> io::Result<u64> {
+        let line = data.into_line();
+        let len = self.write_line(&line)?;
+        match &mut self.range {
+            Some(range) => {
+                range.records += 1;
+                range.last = line;
+            },
+            None => self.range = Some(KeyRange {
+                records: 1,
+                first: line.clone(),
+                last: line
+            })
+        }
+        Ok(len)
+    }
+
+    /// Returns the range of the records written by `write_record()`, or
+    /// `None` if there were no such records.
+    pub fn take_range(&mut self) -> Option<KeyRange> {
+        self.range.take()
+    }
+
+    /// Writes the accumulated lines.
+    fn write_buf(&mut self) -> io::Result<()> {
+        self.writer.write_all(&self.buf)?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Writes all the accumulated lines and returns the underlying writer.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.write_buf()?;
+        Ok(self.writer)
+    }
+
+    /// Writes all the accumulated lines and flushes the underlying writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.write_buf()?;
+        self.writer.flush()
+    }
+}
+
+/// Iterator over the records decoded from a run file by `RunCodec`.
+pub(crate) type DecodedRecords<T> =
+    Box<dyn Iterator<Item = io::Result<T>> + Send>;
+
+/// Format of the run files that stores the records other than as lines, such
+/// as Parquet.
+pub(crate) trait RunCodec<T>: Send + Sync {
+    /// Creates the writer of the records into `file`.
+    fn writer(
+        &self,
+        file: Throttled<SpillWriter>
+    ) -> io::Result<Box<dyn RecordWriter<T>>>;
+
+    /// Creates the iterator over the records stored in `file`.
+    fn records(&self, file: SpillReader) -> io::Result<DecodedRecords<T>>;
+}
+
+/// Writer of the records into a run file in the format of `RunCodec`.
+pub(crate) trait RecordWriter<T> {
+    /// Writes the record. The records may be buffered until `finish()`.
+    fn write_record(&mut self, data: T) -> io::Result<()>;
+
+    /// Writes the buffered records and completes the file. Returns the size
+    /// of the file and the range of the written records, or `None` if there
+    /// were no records.
+    fn finish(&mut self) -> io::Result<(u64, Option<KeyRange>)>;
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn maps_file_without_final_newline() {
+        use std::io::Write;
+        use super::{LineReader, MapReader};
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"first\nlast").unwrap();
+        let mut reader = MapReader::new(&file).unwrap();
+        assert_eq!(reader.next_line().unwrap(), Some("first"));
+        assert_eq!(reader.next_line().unwrap(), Some("last"));
+        assert_eq!(reader.next_line().unwrap(), None);
+        assert_eq!(reader.next_line().unwrap(), None);
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// Files not yet taken into the merge groups, as the ranges of their numbers.
+struct FreeRanges {
+    /// Ends of the free ranges by their starts
+    ranges: BTreeMap<usize, usize>,
+    /// Number of files in each full group
+    length: usize,
+    /// Number of full groups that still fit into the free ranges
+    capacity: usize
+}
+
+impl FreeRanges {
+    /// Creates the ranges with all the `count` files free.
+    fn new(count: usize, length: usize) -> FreeRanges {
+        let mut ranges = BTreeMap::new();
+        if count > 0 {
+            ranges.insert(0, count);
+        }
+        FreeRanges { ranges, length, capacity: count / length }
+    }
+
+    /// Takes `len` files starting from `start` if all of them are free, and
+    /// at least `reserve` full groups still fit into the remaining ranges.
+    /// Returns whether the files are taken.
+    fn take(&mut self, start: usize, len: usize, reserve: usize) -> bool {
+        let (from, to) = match self.ranges.range(..=start).next_back() {
+            Some((&from, &to)) => (from, to),
+            None => return false
+        };
+        let end = start + len;
+        if end > to {
+            return false;
+        }
+        let capacity = self.capacity - (to - from) / self.length
+            + (start - from) / self.length
+            + (to - end) / self.length;
+        if capacity < reserve {
+            return false;
+        }
+        self.ranges.remove(&from);
+        if start > from {
+            self.ranges.insert(from, start);
+        }
+        if to > end {
+            self.ranges.insert(end, to);
+        }
+        self.capacity = capacity;
+        true
+    }
+
+    /// Returns the start of the first free range that fits a full group.
+    fn first_fitting(&self) -> Option<usize> {
+        self.ranges.iter()
+            .find(|&(&from, &to)| to - from >= self.length)
+            .map(|(&from, _)| from)
+    }
+}
+
+/// Returns the starts of all the ranges of `len` adjacent files, the ranges
+/// with the smallest total size first.
+fn smallest_windows(sizes: &[u64], len: usize) -> Vec<usize> {
+    let mut sums = Vec::with_capacity(sizes.len() + 1);
+    sums.push(0);
+    for &size in sizes {
+        sums.push(sums.last().unwrap() + size);
+    }
+    let mut starts: Vec<_> = (0..(sizes.len() + 1).saturating_sub(len))
+        .collect();
+    starts.sort_by_key(|&start| (sums[start + len] - sums[start], start));
+    starts
+}
+
+/// Chooses the files to merge on one stage, where `sizes` are the sizes of
+/// the files in the order of their numbers. Returns `full_groups` ranges of
+/// `length` files and one range of `partial_len` files, if it's non-zero, in
+/// ascending order. The rest of the files are not merged on this stage.
+///
+/// Each group is a range of adjacent files, so the equal elements from the
+/// different files keep their order. The partial group is chosen first, and
+/// the ranges with the smallest total size are preferred, so the small files
+/// are merged with each other instead of being rewritten along with the
+/// large ones. The caller must ensure that all the groups fit into `sizes`.
+pub(crate) fn merge_groups(sizes: &[u64], length: usize, full_groups: usize,
+                           partial_len: usize) -> Vec<Range<usize>> {
+    let mut free = FreeRanges::new(sizes.len(), length);
+    let mut groups = Vec::with_capacity(full_groups + 1);
+    if partial_len > 0 {
+        let start = smallest_windows(sizes, partial_len).into_iter()
+            .find(|&start| free.take(start, partial_len, full_groups))
+            .expect("the partial group must fit");
+        groups.push(start..start + partial_len);
+    }
+    let mut left = full_groups;
+    for start in smallest_windows(sizes, length) {
+        if left == 0 {
+            break;
+        }
+        if free.take(start, length, left - 1) {
+            groups.push(start..start + length);
+            left -= 1;
+        }
+    }
+    // The windows skipped above may have become the only ones that fit, but
+    // a group at the start of a free range always fits
+    while left > 0 {
+        let start = free.first_fitting().expect("the full group must fit");
+        free.take(start, length, left - 1);
+        groups.push(start..start + length);
+        left -= 1;
+    }
+    groups.sort_unstable_by_key(|group| group.start);
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::merge_groups;
+
+    #[test]
+    fn merges_adjacent_smallest_files() {
+        let sizes = [5, 1, 9, 2, 2, 7];
+        assert_eq!(merge_groups(&sizes, 2, 0, 2), vec![3..5]);
+        assert_eq!(merge_groups(&sizes, 3, 1, 0), vec![3..6]);
+    }
+
+    #[test]
+    fn keeps_room_for_all_groups() {
+        // Taking the smallest range in the middle leaves no room for the
+        // second group of three
+        let sizes = [9, 9, 1, 1, 1, 9];
+        assert_eq!(merge_groups(&sizes, 3, 2, 0), vec![0..3, 3..6]);
+        let sizes = [1; 17];
+        let groups = merge_groups(&sizes, 4, 3, 3);
+        assert_eq!(groups.len(), 4);
+        for pair in groups.windows(2) {
+            assert!(pair[0].end <= pair[1].start);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use std::io;
+use std::cmp::{self, Ordering};
+use std::fs::File;
+use std::mem;
+use std::path::Path;
+use super::compare::Compare;
+use super::lines::{FromLine, IntoLine};
+use super::retry::RetryPolicy;
+use super::run::RunWriter;
+use super::sort::{Records, open_records};
+
+/// Number of the lines sampled from the candidates to choose the pivots
+const SAMPLE_SIZE: usize = 4096;
+
+/// Distance (in the sample) from the expected position of the target to the
+/// pivots, so the target falls between them with a high probability
+const PIVOT_MARGIN: usize = 64;
+
+/// Candidates for the target element, which are kept either in memory or in
+/// a temporary file.
+enum Candidates<T> {
+    /// The candidates fit into memory
+    Memory(Vec<T>),
+    /// The candidates are written into the file. Contains their number and
+    /// the uniform sample of their lines
+    File(usize, Vec<String>)
+}
+
+/// Collects the lines into the uniform sample of fixed size.
+struct Sampler {
+    /// The sampled lines
+    sample: Vec<String>,
+    /// Number of the lines seen so far
+    seen: u64,
+    /// State of the xorshift generator
+    state: u64
+}
+
+impl Sampler {
+    /// Creates an empty sampler.
+    fn new() -> Sampler {
+        Sampler { sample: Vec::new(), seen: 0, state: 0x2545_f491_4f6c_dd1d }
+    }
+
+    /// Offers the line to the sample.
+    fn add(&mut self, line: &str) {
+        self.seen += 1;
+        if self.sample.len() < SAMPLE_SIZE {
+            self.sample.push(line.to_string());
+            return;
+        }
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        let pos = (self.state % self.seen) as usize;
+        if pos < SAMPLE_SIZE {
+            self.sample[pos] = line.to_string();
+        }
+    }
+}
+
+/// Writer of the candidates, which keeps them in memory until they exceed
+/// `max_memory` bytes, and spills them into the file at `path` afterwards.
+struct CandidateWriter<'a, T> {
+    /// The candidates kept in memory
+    data: Vec<T>,
+    /// Total size of the candidates kept in memory
+    size: usize,
+    /// Maximum size of the candidates kept in memory
+    max_memory: usize,
+    /// Path to the file to spill the candidates into
+    path: &'a Path,
+    /// Policy of retrying the creation of the file
+    retry: &'a RetryPolicy,
+    /// Writer into the file, if the candidates are spilled
+    writer: Option<RunWriter<File>>,
+    /// Number of the candidates written into the file
+    count: usize,
+    /// Sample of the lines written into the file
+    sampler: Sampler
+}
+
+impl<'a, T: IntoLine> CandidateWriter<'a, T> {
+    /// Creates a new writer.
+    fn new(max_memory: usize, path: &'a Path,
+           retry: &'a RetryPolicy) -> CandidateWriter<'a, T> {
+        CandidateWriter {
+            data: Vec::new(),
+            size: 0,
+            max_memory,
+            path,
+            retry,
+            writer: None,
+            count: 0,
+            sampler: Sampler::new()
+        }
+    }
+
+    /// Writes the line into the file and offers it to the sample.
+    fn write_line(&mut self, line: String) -> io::Result<()> {
+        self.writer.as_mut().unwrap().write_line(&line)?;
+        self.sampler.add(&line);
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Adds the candidate.
+    fn push(&mut self, data: T) -> io::Result<()> {
+        if self.writer.is_some() {
+            return self.write_line(data.into_line());
+        }
+        self.size += data.line_len();
+        self.data.push(data);
+        if self.size > self.max_memory {
+            let file = self.retry.run(|| File::create(self.path))?;
+            self.writer = Some(RunWriter::new(file));
+            for data in mem::take(&mut self.data) {
+                self.write_line(data.into_line())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finishes writing the candidates.
+    fn finish(self) -> io::Result<Candidates<T>> {
+        Ok(match self.writer {
+            Some(mut writer) => {
+                writer.flush()?;
+                Candidates::File(self.count, self.sampler.sample)
+            },
+            None => Candidates::Memory(self.data)
+        })
+    }
+}
+
+/// Opens the file with the candidates, retrying it with `retry`.
+fn read_candidates<T>(path: &Path,
+                      retry: &RetryPolicy) -> io::Result<Records<T>> {
+    open_records(retry.run(|| File::open(path))?.into(), false)
+}
+
+/// Returns the part the element falls into relative to the pivots: `0` if
+/// it's less than `low`, `2` if it's greater than `high` and `1` otherwise.
+fn locate<T>(compare: &dyn Compare<T>, data: &T, low: &T, high: &T) -> usize {
+    if compare.compare(data, low) == Ordering::Less {
+        0
+    } else if compare.compare(data, high) == Ordering::Greater {
+        2
+    } else {
+        1
+    }
+}
+
+/// Finds the element that would be at position `n` (counting from zero) if
+/// the elements of `iter` were sorted with `compare`, or `None` if there are
+/// not more than `n` elements. The candidates that don't fit into
+/// `max_memory` bytes are kept in the temporary files in `dir`, and the
+/// operations on them are retried with `retry`.
+///
+/// The candidates are narrowed down by passes over the file: two pivots are
+/// chosen from the sample around the expected position of the target, and
+/// only the candidates in the part that contains the target are kept. When
+/// the candidates fit into memory, the target is selected in memory.
+pub(crate) fn select_nth<T, It>(
+    iter: It,
+    mut n: usize,
+    compare: &dyn Compare<T>,
+    max_memory: usize,
+    dir: &Path,
+    retry: &RetryPolicy
+) -> io::Result<Option<T>>
+where
+    T: FromLine + IntoLine,
+    It: Iterator<Item = T>
+{
+    let mut pass = 0;
+    let mut path = dir.join(format!("select-{}.txt", pass));
+    let mut writer = CandidateWriter::new(max_memory, &path, retry);
+    for data in iter {
+        writer.push(data)?;
+    }
+    let mut candidates = writer.finish()?;
+    loop {
+        let (count, sample) = match candidates {
+            Candidates::Memory(mut data_vec) => {
+                if n >= data_vec.len() {
+                    return Ok(None);
+                }
+                data_vec.select_nth_unstable_by(n, |a, b| {
+                    compare.compare(a, b)
+                });
+                return Ok(Some(data_vec.swap_remove(n)));
+            },
+            Candidates::File(count, sample) => (count, sample)
+        };
+        if n >= count {
+            return Ok(None);
+        }
+        let mut sample = sample.iter()
+            .map(|line| T::from_line(line))
+            .collect::<io::Result<Vec<_>>>()?;
+        sample.sort_by(|a, b| compare.compare(a, b));
+        let pos = n * sample.len() / count;
+        let low_pos = pos.saturating_sub(PIVOT_MARGIN);
+        let high_pos = cmp::min(pos + PIVOT_MARGIN, sample.len() - 1);
+
+        // Count the candidates in each part. If all the candidates fall
+        // between the pivots, the only pivot is used instead, so the next
+        // part is always smaller
+        let count_parts = |low: &T, high: &T| -> io::Result<[usize; 3]> {
+            let mut counts = [0; 3];
+            for maybe_data in read_candidates::<T>(&path, retry)? {
+                counts[locate(compare, &maybe_data?, low, high)] += 1;
+            }
+            Ok(counts)
+        };
+        let mut counts = count_parts(&sample[low_pos], &sample[high_pos])?;
+        let (low_pos, high_pos) = if counts[1] == count {
+            counts = count_parts(&sample[pos], &sample[pos])?;
+            if n >= counts[0] && n < counts[0] + counts[1] {
+                retry.remove_file(&path)?;
+                return Ok(Some(sample.swap_remove(pos)));
+            }
+            (pos, pos)
+        } else {
+            (low_pos, high_pos)
+        };
+        let (low, high) = (&sample[low_pos], &sample[high_pos]);
+        let target = if n < counts[0] {
+            0
+        } else if n < counts[0] + counts[1] {
+            n -= counts[0];
+            1
+        } else {
+            n -= counts[0] + counts[1];
+            2
+        };
+
+        pass += 1;
+        let next_path = dir.join(format!("select-{}.txt", pass));
+        let mut writer = CandidateWriter::new(max_memory, &next_path,
+                                             retry);
+        for maybe_data in read_candidates::<T>(&path, retry)? {
+            let data = maybe_data?;
+            if locate(compare, &data, low, high) == target {
+                writer.push(data)?;
+            }
+        }
+        candidates = writer.finish()?;
+        retry.remove_file(&path)?;
+        path = next_path;
+    }
+}
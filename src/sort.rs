@@ -1,319 +1,1849 @@
-use threadpool::ThreadPool;
-use tempfile::{Builder, TempDir};
-use std::io::{self, BufRead, BufReader, Write, BufWriter};
-use std::fs::{self, File};
+use tempfile::Builder;
+use std::io::{self, BufRead, Error, ErrorKind, Write};
+use std::fs::File;
 use std::path::{Path, PathBuf};
-use std::marker;
-use std::cell::{RefCell};
+use std::env;
+use std::error;
+use std::fmt;
+use std::iter;
 use std::mem;
-use std::sync::{Mutex, Arc};
-use std::collections::{BinaryHeap};
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::panic;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::vec;
 use std::cmp::{self, Reverse};
+use memchr::memchr;
+use std::collections::BinaryHeap;
+use super::affinity::{CpuSet, pin_current_thread};
+use super::blob::{BlobIter, BlobRef, BlobWriter};
+use super::buffer::{BufferPool, InFlight};
+use super::compact::{SortedFile, write_sorted_file};
+use super::compare::{Compare, ByOrd};
+use super::executor::Executor;
+use super::job::{Job, Task, ChunkSorter, Filter, create_run, write_live,
+                 verify_run};
+use super::kv::KeyValue;
 use super::lines::{FromLine, IntoLine};
+use super::long_path::extend_path;
+use super::merge::{MergeIter, Duplicates, Combiner};
+#[cfg(feature = "metrics")]
+use super::metrics::Metrics;
+use super::output::{Compression, write_lines};
+use super::pressure::{self, Pressure};
+use super::radix::{BinaryRuns, FixedWidth, RadixKey, radix_sort};
+#[cfg(feature = "protobuf")]
+use prost::Message;
+#[cfg(feature = "protobuf")]
+use super::protobuf::{DelimitedRuns, Proto};
+#[cfg(feature = "parquet")]
+use super::arrow_codec::{ArrowCodec, ParquetRuns, write_parquet_records};
+#[cfg(feature = "arrow")]
+use super::record_batch::{DEFAULT_BATCH_SIZE, write_arrow_ipc_lines};
+#[cfg(feature = "mmap")]
+use super::run::MapReader;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use super::uring::{self, UringFile};
+use super::retry::RetryPolicy;
+use super::schedule::merge_groups;
+use super::select::select_nth;
+use super::source::{MergeSource, MergedIter, merge_with_duplicates};
+use super::tiers::{SpillDir, SpillMemory, SpillReader, SpillStore, Tiers};
+use super::tracker::{TempFileEvent, TrackedTempDir, Tracker};
+use super::tune::Strategy;
+use super::throttle::{Counter, Throttle};
+use super::run::{DecodedRecords, KeyRange, LineReader, RunCodec, RunReader};
+
+/// Total size of the data (in bytes) kept in memory during the split phase
+/// with the default configuration.
+pub(crate) const DEFAULT_MEMORY: usize = 10_000_000;
+
+/// Number of the first records sampled by `Sort::sort_auto()` to estimate
+/// the average size of a record.
+const AUTO_SAMPLE_LEN: usize = 1024;
+
+/// Maximum number of files opened at once by a merge with the default
+/// configuration. It's well below the default limits on the open files on
+/// the common systems.
+pub(crate) const DEFAULT_MAX_OPEN_FILES: usize = 256;
+
+/// Returns the default number of threads, which is the number of CPUs, or one
+/// if the `threads` feature is disabled.
+pub(crate) fn default_num_threads() -> usize {
+    #[cfg(feature = "threads")]
+    return num_cpus::get();
+    #[cfg(not(feature = "threads"))]
+    return 1;
+}
 
 /// Struct that represents configuration of the sorter.
+#[derive(Clone, Debug)]
 pub struct Config {
-    /// Number of files to merge at one time
+    /// Number of files to merge at one time. It must be at least 2
     pub num_merge: usize,
     /// Number of threads to sort in parallel
     pub num_threads: usize,
     /// Maximum size of the file during the split phase
-    pub max_split_size: usize
+    pub max_split_size: usize,
+    /// Indicates whether the duplicate elements must be dropped
+    pub unique: bool,
+    /// Maximum number of chunks that are sorted or wait to be sorted in the
+    /// thread pool while the next one is built. Reading the input blocks when
+    /// it's reached, so the memory used by the split phase stays bounded
+    pub max_in_flight_chunks: usize,
+    /// Maximum number of merge jobs that read and write the files at once.
+    /// Setting it below `num_threads` avoids thrashing the spinning disks,
+    /// while the split phase still uses all the threads
+    pub io_concurrency: usize,
+    /// Maximum rate of reading and writing the temporary files in bytes per
+    /// second, or `None` for no limit. It keeps a background sort from
+    /// starving the other processes of the disk bandwidth
+    pub max_io_rate: Option<u64>,
+    /// Amount of the available system memory in bytes, below which the
+    /// split phase shrinks the chunks and the number of chunks in flight, or
+    /// `None` to ignore the memory pressure. They are restored gradually when
+    /// the memory is available again. The available memory is known only on
+    /// Linux, and the option is ignored on the other systems
+    pub min_available_memory: Option<u64>,
+    /// Maximum number of files opened at once by a merge. If `num_merge` is
+    /// greater, each merge is performed as a tree of smaller merges, so the
+    /// number of merge passes stays the same
+    pub max_open_files: usize,
+    /// Numbers of the CPUs the worker threads are pinned to, or `None` to
+    /// leave them unpinned. The pinning is supported only on Linux with the
+    /// `affinity` feature, and the option is ignored otherwise
+    pub cpu_affinity: Option<Vec<usize>>,
+    /// Indicates whether each temporary file is read back after it's written
+    /// to check that it's sorted. The output of the last merge performed on
+    /// the fly by `Sort::count_distinct()` and `Sort::top_frequent()` is
+    /// checked as it's read. It slows down sorting, but catches broken
+    /// comparators and `IntoLine`/`FromLine` implementations early
+    pub verify: bool,
+    /// Policy of retrying the operations on the temporary files that failed
+    /// with a transient error. By default, they are not retried
+    pub retry: RetryPolicy,
+    /// Maximum size of a record (the length of its line) in bytes, or `None`
+    /// for no limit. Sorting fails with `RecordTooLarge` on a larger record.
+    /// `Sort::sort_lines()` stops reading such a line at the limit, so a huge
+    /// line doesn't exhaust memory
+    pub max_record_size: Option<usize>,
+    /// Limits of keeping the small temporary files in memory, or `None` to
+    /// always write them into the files. Each temporary file goes to the first
+    /// tier whose limits fit its expected size: the memory, then
+    /// `spill_dirs`, then `spill_store`, or to the last tier if there is no
+    /// such tier
+    pub spill_memory: Option<SpillMemory>,
+    /// Directories the temporary files are spilled to, in the order of
+    /// preference. If it's empty, the files that are not kept in memory or in
+    /// the store are written into the system temporary directory
+    pub spill_dirs: Vec<SpillDir>,
+    /// Remote storage the temporary files that don't fit into the memory and
+    /// into `spill_dirs` overflow to, or `None` for no such storage
+    pub spill_store: Option<Arc<dyn SpillStore>>
 }
 
 impl Default for Config {
     fn default() -> Config {
-        let num_threads = num_cpus::get();
+        let num_threads = default_num_threads();
         Config {
             num_merge: 16,
             num_threads,
-            max_split_size: 10_000_000 / num_threads
+            max_split_size: DEFAULT_MEMORY / num_threads,
+            unique: false,
+            max_in_flight_chunks: num_threads,
+            io_concurrency: num_threads,
+            max_io_rate: None,
+            min_available_memory: None,
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            cpu_affinity: None,
+            verify: false,
+            retry: RetryPolicy::default(),
+            max_record_size: None,
+            spill_memory: None,
+            spill_dirs: Vec::new(),
+            spill_store: None
         }
     }
 }
 
-type Lines = io::Lines<BufReader<File>>;
+impl Config {
+    /// Returns the CPUs the worker threads are pinned to.
+    fn cpu_set(&self) -> CpuSet {
+        self.cpu_affinity.as_deref().map(Arc::from)
+    }
+}
 
-type ResultCell = Arc<Mutex<io::Result<()>>>;
+/// Statistics collected during sorting.
+#[derive(Clone, Debug, Default)]
+pub struct SortStats {
+    /// Number of records taken from the input iterator
+    pub input_records: u64,
+    /// Number of sorted runs created during the split phase
+    pub runs: usize,
+    /// Number of merge passes performed
+    pub merge_passes: usize,
+    /// Total number of bytes written into the temporary files
+    pub temp_bytes_written: u64,
+    /// Time spent in the split phase
+    pub split_time: Duration,
+    /// Time spent in the merge phase
+    pub merge_time: Duration,
+    /// Key ranges of the non-empty runs created during the split phase, in
+    /// the order of their creation
+    pub run_ranges: Vec<KeyRange>,
+    /// Strategy chosen by `Sort::sort_auto()`, or `None` if the data was
+    /// sorted by the other methods
+    pub strategy: Option<Strategy>
+}
+
+/// Error that is returned if the record exceeds `Config::max_record_size`. It
+/// is wrapped into `io::Error` with `ErrorKind::InvalidData`.
+#[derive(Clone, Debug)]
+pub struct RecordTooLarge {
+    /// Position of the record in the input, counting from zero
+    pub index: u64,
+    /// Maximum size of a record (in bytes)
+    pub max_size: usize
+}
+
+impl fmt::Display for RecordTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "record {} exceeds the maximum size of {} bytes",
+               self.index, self.max_size)
+    }
+}
+
+impl error::Error for RecordTooLarge {}
+
+impl From<RecordTooLarge> for Error {
+    fn from(err: RecordTooLarge) -> Error {
+        Error::new(ErrorKind::InvalidData, err)
+    }
+}
+
+/// Reader that splits the file into lines.
+enum FileReader {
+    /// The file is read into a buffer
+    Buffered(RunReader<SpillReader>),
+    /// The file is mapped into memory
+    #[cfg(feature = "mmap")]
+    Mapped(MapReader),
+    /// The file is read through io_uring together with the other files
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    Uring(RunReader<UringFile>)
+}
+
+/// Source of the elements stored in the file.
+enum RecordSource<T> {
+    /// The file is split into lines, which are parsed into the elements
+    Lines(FileReader),
+    /// The elements are decoded by the run codec. The reading of such files
+    /// is not throttled
+    Decoded(DecodedRecords<T>)
+}
+
+/// Iterator over the elements stored in the file.
+pub(crate) struct Records<T> {
+    /// Source of the elements
+    source: RecordSource<T>,
+    /// Counter of the read bytes, if the rate is limited
+    counter: Option<Counter>
+}
+
+impl<T: FromLine> Iterator for Records<T> {
+    type Item = io::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let reader = match &mut self.source {
+            RecordSource::Lines(reader) => reader,
+            RecordSource::Decoded(records) => return records.next()
+        };
+        let maybe_line = match reader {
+            FileReader::Buffered(reader) => reader.next_line(),
+            #[cfg(feature = "mmap")]
+            FileReader::Mapped(reader) => reader.next_line(),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            FileReader::Uring(reader) => reader.next_line()
+        };
+        match maybe_line {
+            Ok(Some(line)) => {
+                if let Some(counter) = &mut self.counter {
+                    counter.add(line.len() as u64 + 1);
+                }
+                Some(T::from_line(line))
+            },
+            Ok(None) => None,
+            Err(err) => Some(Err(err))
+        }
+    }
+}
+
+impl<T> Records<T> {
+    /// Limits the rate of reading with `throttle` if it's not `None`.
+    pub(crate) fn with_throttle(
+        mut self,
+        throttle: Option<&Arc<Throttle>>
+    ) -> Records<T> {
+        self.counter = throttle.cloned().map(Counter::new);
+        self
+    }
+}
 
 /// The sorter structure.
 pub struct Sort<T> {
     /// Sorter configuration
     config: Config,
-    /// Thread pool use to run the jobs
-    pool: ThreadPool,
+    /// Comparator that defines the order of the elements
+    compare: Arc<dyn Compare<T>>,
+    /// Function that combines the equal elements, if any
+    combine: Option<Combiner<T>>,
+    /// Function that sorts the chunks, if it's set by the user
+    sorter: Option<ChunkSorter<T>>,
+    /// Executor used to run the jobs
+    executor: Executor<T>,
     /// Temporary directory holder
-    tmpdir: TempDir,
+    tmpdir: TrackedTempDir,
     /// Current number of sorting stage
-    stage_num: RefCell<usize>,
+    stage_num: AtomicUsize,
     /// Number of the files on the current sorting stage
-    file_num: RefCell<usize>,
-    /// A `RefCell` that contains the result of the operation in the thread pool
-    /// It contains `Ok(())` if all the operations succeeded, and the first
-    /// error otherwise.
-    result_cell: ResultCell,
-    _marker: marker::PhantomData<T>
+    file_num: AtomicUsize,
+    /// Statistics collected so far
+    stats: Mutex<SortStats>,
+    /// Number of bytes written into the temporary files by the jobs
+    bytes_written: Arc<AtomicU64>,
+    /// Pool of the chunk vectors reused during the split phase
+    chunks: Arc<BufferPool<T>>,
+    /// Bound on the number of chunks in flight during the split phase
+    in_flight: Arc<InFlight>,
+    /// Bound on the number of merge jobs that perform I/O at once
+    io_limit: Arc<InFlight>,
+    /// Throttle of reading and writing the temporary files, if the rate is
+    /// limited
+    throttle: Option<Arc<Throttle>>,
+    /// Tracker of the memory pressure, if it's monitored
+    pressure: Option<Pressure>,
+    /// Indicates whether the files are mapped into memory while merging
+    mmap: bool,
+    /// Format of the run files, if they are not written as lines
+    codec: Option<Arc<dyn RunCodec<T>>>,
+    /// Function that filters the output, if any
+    filter: Option<Filter<T>>,
+    /// Function that transforms the output, if any
+    map: Option<Mapper<T>>,
+    /// Function that checks whether the element is expired, if any
+    expired: Option<Filter<T>>,
+    /// Key ranges of the runs written by the split jobs
+    run_ranges: Arc<Mutex<Vec<(usize, KeyRange)>>>
 }
 
+/// Function that transforms the element in the output.
+type Mapper<T> = Arc<dyn Fn(T) -> T + Send + Sync>;
+
 /// The iterator over sorted data.
+///
+/// It doesn't keep the sorter with its thread pool, so it's `Send` if `T` is
+/// `Send` and can be consumed on another thread.
 pub struct SortedIter<T> {
-    /// The sorted structure. It's kept here because we the temporary files
-    /// will be dropped when `Sort` drops, and we don't want it to happen
-    /// while iterating over the results.
-    _sort: Sort<T>,
-    /// `Lines` iterator over the resulting file
-    lines: Option<Lines>
+    /// Temporary directory holder. It's kept here because the temporary files
+    /// will be dropped when it drops, and we don't want it to happen while
+    /// iterating over the results.
+    _tmpdir: TrackedTempDir,
+    /// Statistics collected while sorting
+    stats: SortStats,
+    /// Source of the sorted elements, or `None` if there are no elements
+    iter: Option<Sorted<T>>,
+    /// Comparator that defines the order of the elements
+    compare: Arc<dyn Compare<T>>,
+    /// Handling of the equal elements by the sort
+    duplicates: Duplicates<T>,
+    /// Function that filters the output, if any
+    filter: Option<Filter<T>>,
+    /// Function that transforms the output, if any
+    map: Option<Mapper<T>>,
+    /// Function that checks whether the element is expired, if any
+    expired: Option<Filter<T>>
+}
+
+/// Source of the sorted elements of `SortedIter`.
+enum Sorted<T> {
+    /// The elements are read from the resulting file
+    Files(MergeIter<Records<T>, T>),
+    /// The elements are sorted in memory
+    Memory(vec::IntoIter<T>)
+}
+
+/// Checks at compile time that `SortedIter` can be sent to another thread,
+/// and that `Sort` can be shared between threads.
+#[allow(dead_code)]
+fn assert_thread_safety<T: Send + Sync>() {
+    fn is_send<S: Send>() {}
+    fn is_sync<S: Sync>() {}
+    is_send::<SortedIter<T>>();
+    is_send::<Sort<T>>();
+    is_sync::<Sort<T>>();
+}
+
+/// Make a `Records` iterator from the file. If `mmap` is set, the file is
+/// mapped into memory instead of being read into a buffer. The path may be
+/// given by the user, so it's extended to allow the long paths.
+pub(crate) fn file_records<T, P>(path: P,
+                                 mmap: bool) -> io::Result<Records<T>>
+where
+    P: AsRef<Path>
+{
+    open_records(File::open(extend_path(path.as_ref())?)?.into(), mmap)
+}
+
+/// Make a `Records` iterator from the opened file, like `file_records()`. The
+/// files kept in memory are never mapped.
+pub(crate) fn open_records<T>(file: SpillReader,
+                              mmap: bool) -> io::Result<Records<T>> {
+    let reader = match (mmap, file) {
+        #[cfg(feature = "mmap")]
+        (true, SpillReader::Disk(file)) => {
+            FileReader::Mapped(MapReader::new(&file)?)
+        },
+        (_, file) => FileReader::Buffered(RunReader::new(file))
+    };
+    Ok(Records { source: RecordSource::Lines(reader), counter: None })
+}
+
+/// Make `Records` iterators from the temporary files that are merged
+/// together, opened with `tracker`. The files are decoded with `codec` if it's
+/// not `None`. Otherwise, with the `io-uring` feature on Linux, the files are
+/// read through one ring unless `mmap` is set or some of them are kept in
+/// memory.
+pub(crate) fn merge_records<T, P>(
+    paths: &[P],
+    mmap: bool,
+    codec: Option<&Arc<dyn RunCodec<T>>>,
+    tracker: &Tracker
+) -> io::Result<Vec<Records<T>>>
+where
+    P: AsRef<Path>
+{
+    if let Some(codec) = codec {
+        return paths.iter()
+            .map(|path| {
+                let records = codec.records(tracker.open(path.as_ref())?)?;
+                Ok(Records {
+                    source: RecordSource::Decoded(records),
+                    counter: None
+                })
+            })
+            .collect();
+    }
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if !mmap && tracker.fetch_all(paths)? {
+        if let Some(files) = tracker.retry.run(|| uring::open_files(paths))? {
+            return Ok(files.into_iter()
+                .map(|file| Records {
+                    source: RecordSource::Lines(
+                        FileReader::Uring(RunReader::new(file))
+                    ),
+                    counter: None
+                })
+                .collect());
+        }
+    }
+    paths.iter()
+        .map(|path| open_records(tracker.open(path.as_ref())?, mmap))
+        .collect()
+}
+
+/// The iterator over sorted data that yields the elements directly, created
+/// by `SortedIter::assume_valid()`.
+pub struct AssumeValid<T> {
+    /// The underlying iterator
+    inner: SortedIter<T>
 }
 
-/// Make a `Lines` iterator from the file
-fn file_as_lines<P: AsRef<Path>>(path: P) -> io::Result<Lines> {
-    Ok(BufReader::new(File::open(path)?).lines())
+/// The iterator over sorted data that yields the elements in batches, created
+/// by `SortedIter::chunks()`.
+pub struct Chunks<T> {
+    /// The underlying iterator
+    inner: SortedIter<T>,
+    /// Maximum number of elements in a batch
+    size: usize,
+    /// Error that occurred after the last batch was filled partially
+    error: Option<io::Error>
+}
+
+impl<T> SortedIter<T> {
+    /// Returns the statistics collected while sorting.
+    pub fn stats(&self) -> SortStats {
+        self.stats.clone()
+    }
+
+    /// Converts the iterator into the one that yields `T` instead of
+    /// `io::Result<T>`. Use it when the data is trusted to round-trip through
+    /// `IntoLine` and `FromLine` and the I/O errors are not expected.
+    ///
+    /// The returned iterator panics if an error occurs.
+    pub fn assume_valid(self) -> AssumeValid<T> {
+        AssumeValid { inner: self }
+    }
+
+    /// Converts the iterator into the one that yields the elements in batches
+    /// of `size`, except the last batch that may be shorter. It's convenient
+    /// for the consumers that insert the data into a database in batches.
+    ///
+    /// If an error occurs, the elements read before it are yielded first, and
+    /// then the error itself.
+    ///
+    /// Panics if `size` is zero.
+    pub fn chunks(self, size: usize) -> Chunks<T> {
+        assert!(size != 0, "chunk size must be non-zero");
+        Chunks { inner: self, size, error: None }
+    }
+}
+
+impl<T: FromLine + IntoLine> SortedIter<T> {
+    /// Writes the sorted data into `writer` as lines, compressing them with
+    /// `compression`. Returns the first error that occurred while reading the
+    /// sorted data or writing it.
+    pub fn write_to<W: Write>(self, writer: W,
+                              compression: Compression) -> io::Result<()> {
+        write_lines(self, writer, compression)
+    }
+
+    /// Merges the result lazily with the result of another sort, so the
+    /// results of the independent sorts are combined without sorting them
+    /// again. Both must be sorted in the order defined by the comparator of
+    /// `self`, which is not checked. The equal elements of `self` come first.
+    /// They are dropped or combined across both results if the sort of
+    /// `self` drops or combines the duplicates.
+    pub fn merge_with<'a>(
+        self,
+        other: SortedIter<T>
+    ) -> io::Result<MergedIter<'a, T>>
+    where
+        T: 'a
+    {
+        let compare = self.compare.clone();
+        let duplicates = self.duplicates.clone();
+        let sources = vec![MergeSource::from(self), MergeSource::from(other)];
+        merge_with_duplicates(sources, compare, duplicates)
+    }
+
+    /// Same as `merge_with()`, but writes the merged result into the file at
+    /// `path`, which is kept after the sorts end.
+    pub fn merge_into_file<P>(self, other: SortedIter<T>,
+                              path: P) -> io::Result<SortedFile>
+    where
+        P: AsRef<Path>
+    {
+        write_sorted_file(self.merge_with(other)?, path)
+    }
 }
 
 impl<T: FromLine> Iterator for SortedIter<T> {
     type Item = io::Result<T>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.lines.as_mut()?.next() {
-            Some(Ok(line)) => Some(T::from_line(&line)),
-            Some(Err(err)) => Some(Err(err)),
-            None => None
+        loop {
+            let maybe_data = match self.iter.as_mut()? {
+                Sorted::Files(iter) => iter.next()?,
+                Sorted::Memory(iter) => Ok(iter.next()?)
+            };
+            let data = match maybe_data {
+                Ok(data) => data,
+                Err(err) => return Some(Err(err))
+            };
+            if self.expired.as_ref().is_some_and(|expired| expired(&data)) {
+                continue;
+            }
+            if self.filter.as_ref().is_none_or(|filter| filter(&data)) {
+                return Some(Ok(match &self.map {
+                    Some(map) => map(data),
+                    None => data
+                }));
+            }
+        }
+    }
+}
+
+impl<T> Chunks<T> {
+    /// Returns the statistics collected while sorting.
+    pub fn stats(&self) -> SortStats {
+        self.inner.stats()
+    }
+}
+
+impl<T: FromLine> Iterator for Chunks<T> {
+    type Item = io::Result<Vec<T>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(err) = self.error.take() {
+            return Some(Err(err));
+        }
+        let mut chunk = Vec::with_capacity(self.size);
+        while chunk.len() < self.size {
+            match self.inner.next() {
+                Some(Ok(data)) => chunk.push(data),
+                Some(Err(err)) if chunk.is_empty() => return Some(Err(err)),
+                Some(Err(err)) => {
+                    self.error = Some(err);
+                    break;
+                },
+                None => break
+            }
+        }
+        if chunk.is_empty() {
+            None
+        } else {
+            Some(Ok(chunk))
+        }
+    }
+}
+
+impl<T> AssumeValid<T> {
+    /// Returns the statistics collected while sorting.
+    pub fn stats(&self) -> SortStats {
+        self.inner.stats()
+    }
+}
+
+impl<T: FromLine> Iterator for AssumeValid<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        match self.inner.next()? {
+            Ok(data) => Some(data),
+            Err(err) => panic!("Cannot read the sorted data: {}", err)
         }
     }
 }
 
 impl<T: FromLine + IntoLine + Ord + Send + 'static> Sort<T> {
-    /// Indicates that we create the next file on the current stage.
-    fn next_file(&self) {
-        *self.file_num.borrow_mut() += 1;
+    /// Creates a new `Sort` struct from the given configuration. The elements
+    /// are sorted in their natural order.
+    pub fn new(config: Config) -> io::Result<Sort<T>> {
+        Self::with_compare(config, T::cmp)
+    }
+}
+
+impl<T: FromLine + IntoLine + Ord + Send> Sort<T> {
+    /// Creates a new `Sort` struct that runs the jobs on scoped threads. The
+    /// elements are sorted in their natural order.
+    ///
+    /// Unlike `new()`, it doesn't require the elements to be `'static`, so the
+    /// types that contain references can be sorted.
+    pub fn scoped(config: Config) -> io::Result<Sort<T>> {
+        Self::scoped_with_compare(config, ByOrd)
+    }
+}
+
+impl<T: FromLine + IntoLine + Ord> Sort<T> {
+    /// Creates a new `Sort` struct that performs all the work on the calling
+    /// thread. The elements are sorted in their natural order.
+    ///
+    /// It doesn't require the elements to be `Send`, so the types like `Rc`
+    /// can be sorted. `Config::num_threads` is ignored.
+    pub fn single_threaded(config: Config) -> io::Result<Sort<T>> {
+        Self::single_threaded_with_compare(config, ByOrd)
+    }
+}
+
+impl<T: FromLine + IntoLine> Sort<T> {
+    /// Takes the name of the next file on the current stage without the
+    /// directory. The files may be taken from several threads at once.
+    fn next_base_file_name(&self) -> String {
+        let num = self.file_num.fetch_add(1, Ordering::Relaxed);
+        Self::get_base_file_name(self.stage_num(), num)
+    }
+
+    /// Takes the name of the next file on the current stage, which is
+    /// expected to take `size` bytes, and places it into a storage tier.
+    fn next_file_name(&self, size: u64) -> PathBuf {
+        let name = self.next_base_file_name();
+        match &self.tmpdir.tracker.tiers {
+            Some(tiers) => tiers.place(&name, size),
+            None => self.tmpdir.path().join(name)
+        }
+    }
+
+    /// Takes the number of the next run created during the split phase.
+    fn next_run(&self) -> usize {
+        let mut stats = self.stats();
+        stats.runs += 1;
+        stats.runs - 1
+    }
+
+    /// Returns the current number of sorting stage.
+    fn stage_num(&self) -> usize {
+        self.stage_num.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of the files on the current sorting stage.
+    fn file_num(&self) -> usize {
+        self.file_num.load(Ordering::Relaxed)
+    }
+
+    /// Gives access to the statistics collected so far.
+    fn stats(&self) -> MutexGuard<'_, SortStats> {
+        self.stats.lock().unwrap()
     }
 
     /// Indicates that the sorting stage has changed
     fn next_stage(&self) {
-        *self.file_num.borrow_mut() = 0;
-        *self.stage_num.borrow_mut() += 1;
+        self.file_num.store(0, Ordering::Relaxed);
+        self.stage_num.fetch_add(1, Ordering::Relaxed);
     }
 
-    /// Constucts the name of the temporary file based on the base directory,
-    /// the stage number and the file number.
-    fn get_dir_file_name(dir: &Path, stage: usize, num: usize) -> PathBuf {
-        let filename = format!("f{}-{}.txt", stage, num);
-        dir.join(filename)
+    /// Constucts the name of the temporary file without the directory based
+    /// on the stage number and the file number.
+    fn get_base_file_name(stage: usize, num: usize) -> String {
+        format!("f{}-{}.txt", stage, num)
     }
 
     /// Constucts the name of the temporary file based on the stage number and
-    /// the file number. The base directory is taken from `self`.
+    /// the file number. The base directory is the storage tier the file is
+    /// placed into, or the temporary directory of `self`.
     fn get_file_name(&self, stage: usize, num: usize) -> PathBuf {
-        Self::get_dir_file_name(self.tmpdir.path(), stage, num)
+        let name = Self::get_base_file_name(stage, num);
+        self.tmpdir.tracker.tiers.as_ref()
+            .and_then(|tiers| tiers.find(&name))
+            .unwrap_or_else(|| self.tmpdir.path().join(name))
     }
 
-    /// Constructs the name of the current file to work on.
-    fn get_cur_file_name(&self) -> PathBuf {
-        self.get_file_name(*self.stage_num.borrow(), *self.file_num.borrow())
+    /// Defines what happens with the equal elements, based on the combiner
+    /// and the configuration.
+    fn duplicates(&self) -> Duplicates<T> {
+        match &self.combine {
+            Some(combine) => Duplicates::Combine(combine.clone()),
+            None if self.config.unique => Duplicates::Drop,
+            None => Duplicates::Keep
+        }
     }
 
-    /// Adds a job to the thread pool, updating `result_cell` accordingly.
-    fn add_to_pool<F>(&self, f: F)
-    where
-        F: FnOnce() -> io::Result<()> + Send + 'static
-    {
-        let res_cell = self.result_cell.clone();
-        self.pool.execute(move || {
-            let error = match f() {
-                Ok(_) => return,
-                Err(err) => err
-            };
-            let mut guard = match Mutex::try_lock(&res_cell) {
-                Ok(guard) => guard,
-                Err(_) => return
-            };
-            if let Ok(_) = *guard {
-                *guard = Err(error);
-            }
-        });
+    /// Creates a job that writes its result into the next file, which is
+    /// expected to take `size` bytes.
+    fn new_job(&self, task: Task<T>, size: u64) -> Job<T> {
+        let out_filename = self.next_file_name(size);
+        Job {
+            task,
+            out_filename,
+            compare: self.compare.clone(),
+            duplicates: self.duplicates(),
+            sorter: self.sorter.clone(),
+            bytes_written: self.bytes_written.clone(),
+            chunks: self.chunks.clone(),
+            mmap: self.mmap,
+            codec: self.codec.clone(),
+            run_ranges: self.run_ranges.clone(),
+            expired: self.expired.clone(),
+            max_open_files: self.config.max_open_files,
+            slot: None,
+            io_limit: self.io_limit.clone(),
+            throttle: self.throttle.clone(),
+            tracker: self.tmpdir.tracker.clone(),
+            verify: self.config.verify
+        }
     }
 
     /// This function is called from `split_invoke`. It adds one job to sort
-    /// `data_vec` and write the results into a new temporary file.
-    fn split_add_file(&self, mut data_vec: Vec<T>) -> io::Result<()> {
+    /// `data_vec` of `size` bytes and write the results into a new temporary
+    /// file.
+    fn split_add_file(&self, data_vec: Vec<T>, size: usize) -> io::Result<()> {
         if data_vec.is_empty() {
             return Ok(());
         }
 
-        let out_filename = self.get_cur_file_name();
-        self.next_file();
+        let run = self.next_run();
+        let mut job = self.new_job(Task::Split(run, data_vec), size as u64);
+        if self.executor.runs_in_background() {
+            job.slot = Some(self.in_flight.acquire());
+        }
+        self.executor.add(job);
+        Ok(())
+    }
 
-        self.add_to_pool(move || {
-            let mut buf_write = BufWriter::new(File::create(out_filename)?);
+    /// This function is called from `split_invoke` when the first chunk is
+    /// already sorted. It writes the non-empty `head` of `size` bytes and the
+    /// following elements of `iter` into a new temporary file while they
+    /// remain sorted, without keeping them in memory. Returns the first
+    /// element that breaks the order, or `None` if the input has ended.
+    fn split_sorted_prefix<It>(&self, head: Vec<T>, size: usize,
+                               iter: &mut It) -> io::Result<Option<T>>
+    where
+        It: Iterator<Item = T>
+    {
+        let out_filename = self.next_file_name(size as u64);
+        let run = self.next_run();
+        let mut buf_write = create_run(&out_filename, self.codec.as_ref(),
+                                       self.throttle.as_ref(),
+                                       &self.tmpdir.tracker)?;
 
-            data_vec.sort();
-            for data in data_vec {
-                let line = data.into_line() + "\n";
-                buf_write.write_all(line.as_bytes())?;
+        let duplicates = self.duplicates();
+        let expired = self.expired.as_ref();
+        let mut head = duplicates.apply(&*self.compare, head);
+        let mut prev = head.pop().unwrap();
+        let mut total_len = 0;
+        for data in head {
+            total_len += write_live(&mut buf_write, data, expired)?;
+        }
+        let mut rest = None;
+        for data in iter {
+            self.count_input(&data)?;
+            match self.compare.compare(&prev, &data) {
+                cmp::Ordering::Greater => {
+                    rest = Some(data);
+                    break;
+                },
+                cmp::Ordering::Equal if !duplicates.keep() => {
+                    prev = duplicates.join(prev, data);
+                    continue;
+                },
+                _ => ()
             }
-            buf_write.flush()?;
-            Ok(())
-        });
-
-        Ok(())
+            let data = mem::replace(&mut prev, data);
+            total_len += write_live(&mut buf_write, data, expired)?;
+        }
+        total_len += write_live(&mut buf_write, prev, expired)?;
+        let (len, range) = buf_write.finish()?;
+        total_len += len;
+        if self.config.verify {
+            let records = range.as_ref().map_or(0, |range| range.records);
+            verify_run(&out_filename, &*self.compare, records,
+                       self.codec.as_ref(), &self.tmpdir.tracker)?;
+        }
+        if let Some(range) = range {
+            self.run_ranges.lock().unwrap().push((run, range));
+        }
+        self.bytes_written.fetch_add(total_len, Ordering::Relaxed);
+        self.tmpdir.tracker.created(&out_filename, total_len)?;
+        Ok(rest)
     }
 
     /// Adds jobs to split the data into chunks. The jobs are added into the
-    /// thread pool, and `join_pool()` needs to be invoked before processing
+    /// executor, and `join_jobs()` needs to be invoked before processing
     /// further data.
-    fn split_invoke<It>(&self, iter: It) -> io::Result<()>
+    ///
+    /// If the first chunk turns out to be already sorted, the sorted prefix of
+    /// the input is streamed into one file instead, so the sorted input is
+    /// written only once and never merged.
+    fn split_invoke<It>(&self, mut iter: It) -> io::Result<()>
     where
         It: Iterator<Item = T>
     {
         let mut cur_size = 0;
-        let mut cur_vec = Vec::<T>::new();
-        for data in iter {
+        let mut cur_vec = self.chunks.take();
+        let mut max_size = self.check_pressure();
+        // Size of the chunk when the memory pressure was checked last time
+        let mut checked_size = 0;
+        // Indicates whether all the data read so far is sorted
+        let mut presorted = true;
+        while let Some(data) = iter.next() {
+            self.count_input(&data)?;
+            if self.pressure.is_some()
+                && cur_size >= checked_size + pressure::CHECK_INTERVAL
+            {
+                max_size = self.check_pressure();
+                checked_size = cur_size;
+            }
+            presorted = presorted && cur_vec.last().is_none_or(|last| {
+                self.compare.compare(last, &data) != cmp::Ordering::Greater
+            });
             let size = data.line_len();
-            if cur_size + size > self.config.max_split_size {
-                self.split_add_file(mem::replace(&mut cur_vec, vec![data]))?;
+            if presorted && cur_size + size > max_size {
+                presorted = false;
+                let mut head = mem::take(&mut cur_vec);
+                head.push(data);
+                match self.split_sorted_prefix(head, cur_size + size,
+                                               &mut iter)? {
+                    Some(data) => {
+                        cur_size = data.line_len();
+                        cur_vec.push(data);
+                    },
+                    None => return Ok(())
+                }
+                continue;
+            }
+            if cur_size + size > max_size {
+                let full_vec = mem::replace(&mut cur_vec, self.chunks.take());
+                self.split_add_file(full_vec, cur_size)?;
+                max_size = self.check_pressure();
+                checked_size = 0;
+                cur_vec.push(data);
                 cur_size = size;
                 continue;
             }
             cur_vec.push(data);
             cur_size += size;
         }
-        self.split_add_file(cur_vec)?;
+        self.split_add_file(cur_vec, cur_size)?;
         Ok(())
     }
 
-    /// This function is called from `merge_invoke`. It adds one job to merge
-    /// the files on stage `stage` that have numbers from `first` to `last`.
-    fn merge_add_files(&self, stage: usize, first: usize,
-                       last: usize) -> io::Result<()> {
-        if first == last {
-            return Ok(());
+    /// Counts the record taken from the input, and checks that it doesn't
+    /// exceed the maximum size.
+    fn count_input(&self, data: &T) -> io::Result<()> {
+        let index = {
+            let mut stats = self.stats();
+            stats.input_records += 1;
+            stats.input_records - 1
+        };
+        self.tmpdir.tracker.add_records(1);
+        match self.config.max_record_size {
+            Some(max_size) if data.line_len() > max_size => {
+                Err(RecordTooLarge { index, max_size }.into())
+            },
+            _ => Ok(())
         }
+    }
 
-        let out_filename = self.get_cur_file_name();
-        self.next_file();
-        let dir = self.tmpdir.path().to_path_buf();
-
-        self.add_to_pool(move || {
-            let mut buf_write = BufWriter::new(File::create(out_filename)?);
-
-            let mut iters_vec = Vec::with_capacity(last - first + 1);
-            for num in first..last {
-                let filename = Self::get_dir_file_name(&dir, stage, num);
-                let lines = file_as_lines(filename)?;
-                iters_vec.push(lines.map(|maybe_line| {
-                    match maybe_line {
-                        Ok(line) => T::from_line(&line),
-                        Err(err) => Err(err)
-                    }
-                }));
-            }
-
-            let mut heap = BinaryHeap::new();
-            for (idx, iter) in iters_vec.iter_mut().enumerate() {
-                match iter.next() {
-                    Some(maybe_data) => heap.push(Reverse((maybe_data?, idx))),
-                    None => continue
-                }
-            }
-
-            while !heap.is_empty() {
-                let (data, idx) = heap.pop().unwrap().0;
-                let line = data.into_line() + "\n";
-                buf_write.write_all(line.as_bytes())?;
-                if let Some(maybe_data) = iters_vec[idx].next() {
-                    heap.push(Reverse((maybe_data?, idx)));
-                }
-            }
-            buf_write.flush()?;
+    /// Checks the memory pressure, if it's monitored, and shrinks or restores
+    /// the number of chunks in flight accordingly. Returns the maximum size
+    /// of the chunk under the current pressure.
+    fn check_pressure(&self) -> usize {
+        let level = match &self.pressure {
+            Some(pressure) => pressure.check(),
+            None => return self.config.max_split_size
+        };
+        self.in_flight.set_max_len(self.config.max_in_flight_chunks >> level);
+        self.config.max_split_size >> level
+    }
 
-            mem::drop(iters_vec);
-            for num in first..last {
-                let filename = Self::get_dir_file_name(&dir, stage, num);
-                fs::remove_file(filename)?;
-            }
+    /// This function is called from `merge_invoke`. It adds one job to merge
+    /// the files on stage `stage` that have numbers from `nums` and take
+    /// `size` bytes. A single file is just moved to the next stage without
+    /// rewriting.
+    fn merge_add_files(&self, stage: usize, nums: Range<usize>,
+                       size: u64) -> io::Result<()> {
+        if nums.is_empty() {
+            return Ok(());
+        }
 
-            Ok(())
-        });
+        if nums.len() == 1 {
+            let filename = self.get_file_name(stage, nums.start);
+            let out_filename = filename.with_file_name(
+                self.next_base_file_name()
+            );
+            return self.tmpdir.tracker.rename(filename, &out_filename);
+        }
+        let filenames = nums
+            .map(|num| self.get_file_name(stage, num))
+            .collect();
+        self.executor.add(self.new_job(Task::Merge(filenames), size));
         Ok(())
     }
 
     /// Adds jobs to perform one stage of file merging. The jobs are added into
-    /// the thread pool, and `join_pool()` needs to be invoked before processing
+    /// the executor, and `join_jobs()` needs to be invoked before processing
     /// further data.
-    fn merge_invoke(&self) -> io::Result<()> {
-        let count = *self.file_num.borrow();
-        let prev_stage = *self.stage_num.borrow();
+    ///
+    /// Each group is a range of adjacent files, and the files on the next stage
+    /// follow in the same order, so the equal elements keep their order of
+    /// the input. Among such ranges, the ones with the smallest total size are
+    /// merged, so the small files are merged with each other instead of being
+    /// rewritten along with the large ones, which reduces the total number of
+    /// bytes written on skewed inputs.
+    ///
+    /// Only as many files are merged as needed to reach `max_files` in the
+    /// minimal number of stages, and the rest are carried over to the next
+    /// stage. For example, with 17 files and `num_merge = 16` only the two
+    /// adjacent files with the smallest total size are merged here instead of
+    /// rewriting all the data.
+    fn merge_invoke(&self, max_files: usize) -> io::Result<()> {
+        let count = self.file_num();
+        let prev_stage = self.stage_num();
+        let sizes = (0..count)
+            .map(|num| {
+                let filename = self.get_file_name(prev_stage, num);
+                self.tmpdir.tracker.len(&filename)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
         self.next_stage();
-        let mut first = 0;
+        self.stats().merge_passes += 1;
+
+        // Find the number of files to leave after this stage, so the remaining
+        // stages can merge them down to `max_files`
         let length = self.config.num_merge;
-        while first != count {
-            let last = cmp::min(count, first + length);
-            self.merge_add_files(prev_stage, first, last)?;
-            first = last;
+        let mut target = cmp::max(max_files, 1);
+        while target.saturating_mul(length) < count {
+            target *= length;
+        }
+        let reduce = count.saturating_sub(target);
+        let full_groups = reduce / (length - 1);
+        let partial_len = match reduce % (length - 1) {
+            0 => 0,
+            extra => extra + 1
+        };
+
+        // The partial group takes the smallest files, so the leftovers are
+        // combined early and the large files are rewritten as rarely as
+        // possible, like in the Huffman coding. The files outside the groups
+        // are moved to the next stage in their order
+        let groups = merge_groups(&sizes, length, full_groups, partial_len);
+        let mut next = 0;
+        for group in groups.into_iter().chain(iter::once(count..count)) {
+            for (num, &size) in sizes.iter().enumerate()
+                .take(group.start)
+                .skip(next)
+            {
+                self.merge_add_files(prev_stage, num..num + 1, size)?;
+            }
+            let size = sizes[group.clone()].iter().sum();
+            next = group.end;
+            self.merge_add_files(prev_stage, group, size)?;
         }
         Ok(())
     }
 
-    /// Finishes all the currently added jobs in the thread pool.
-    fn join_pool(&self) -> io::Result<()> {
-        self.pool.join();
-        if self.pool.panic_count() != 0 {
-            panic!("Some of the threads in the pool panicked.");
-        }
-        let mut result = Mutex::lock(&self.result_cell).unwrap();
-        mem::replace(&mut result, Ok(()))
+    /// Finishes all the currently added jobs.
+    fn join_jobs(&self) -> io::Result<()> {
+        let result = self.executor.join();
+        self.stats().temp_bytes_written =
+            self.bytes_written.load(Ordering::Relaxed);
+        result
+    }
+
+    /// Opens all the files on the last stage.
+    fn last_stage_records(&self) -> io::Result<Vec<Records<T>>> {
+        let stage = self.stage_num();
+        let paths: Vec<_> = (0..self.file_num())
+            .map(|num| self.get_file_name(stage, num))
+            .collect();
+        let codec = self.codec.as_ref();
+        Ok(merge_records(&paths, self.mmap, codec, &self.tmpdir.tracker)?
+            .into_iter()
+            .map(|records| records.with_throttle(self.throttle.as_ref()))
+            .collect())
     }
 
     /// Constructs a `SortedIter` after the sorting was finished.
     ///
     /// This functions panics if more than one file is present on the last
     /// stage.
-    fn as_iter(self) -> io::Result<SortedIter<T>> {
-        let lines = match *self.file_num.borrow() {
+    fn into_sorted_iter(self) -> io::Result<SortedIter<T>> {
+        let iter = match self.file_num() {
             0 => None,
             1 => {
-                let filename = self.get_file_name(*self.stage_num.borrow(), 0);
-                Some(file_as_lines(filename)?)
+                let records = self.last_stage_records()?;
+                let compare = self.compare.clone();
+                let iter = MergeIter::new(records, compare, self.duplicates())?;
+                Some(Sorted::Files(iter))
             },
             _ => panic!("More than one file exists on the last stage")
         };
-        Ok(SortedIter {_sort: self, lines})
+        Ok(self.finish(iter))
     }
 
-    /// Creates a new `Sort` struct from the given configuration.
-    pub fn new(config: Config) -> io::Result<Sort<T>> {
-        let num_threads = config.num_threads;
+    /// Constructs a `SortedIter` over the elements from `iter`.
+    fn finish(self, iter: Option<Sorted<T>>) -> SortedIter<T> {
+        SortedIter {
+            duplicates: self.duplicates(),
+            _tmpdir: self.tmpdir,
+            stats: self.stats.into_inner().unwrap(),
+            iter,
+            compare: self.compare,
+            filter: self.filter,
+            map: self.map,
+            expired: self.expired
+        }
+    }
+
+    /// Sorts the elements in memory, dropping the expired ones and the
+    /// duplicates.
+    fn sort_in_memory(&self, mut data_vec: Vec<T>) -> Vec<T> {
+        if let Some(expired) = &self.expired {
+            data_vec.retain(|data| !expired(data));
+        }
+        match &self.sorter {
+            Some(sorter) => sorter(&mut data_vec, &*self.compare),
+            None => data_vec.sort_by(|a, b| self.compare.compare(a, b))
+        }
+        self.duplicates().apply(&*self.compare, data_vec)
+    }
+
+    /// Creates a new `Sort` struct from the given configuration. The elements
+    /// are sorted in the order defined by `compare`.
+    ///
+    /// If the `threads` feature is disabled, all the work is performed on the
+    /// calling thread.
+    pub fn with_compare<C>(config: Config, compare: C) -> io::Result<Sort<T>>
+    where
+        T: Send + 'static,
+        C: Compare<T> + 'static
+    {
+        #[cfg(feature = "threads")]
+        let executor = Executor::pool(config.num_threads, config.cpu_set());
+        #[cfg(not(feature = "threads"))]
+        let executor = Executor::inline();
+        Self::with_executor(config, Arc::new(compare), executor)
+    }
+
+    /// Creates a new `Sort` struct that runs the jobs on scoped threads. The
+    /// elements are sorted in the order defined by `compare`.
+    ///
+    /// See `scoped()` for details.
+    pub fn scoped_with_compare<C>(config: Config,
+                                  compare: C) -> io::Result<Sort<T>>
+    where
+        T: Send,
+        C: Compare<T> + 'static
+    {
+        let executor = Executor::scoped(config.num_threads, config.cpu_set());
+        Self::with_executor(config, Arc::new(compare), executor)
+    }
+
+    /// Creates a new `Sort` struct that performs all the work on the calling
+    /// thread. The elements are sorted in the order defined by `compare`.
+    ///
+    /// See `single_threaded()` for details.
+    pub fn single_threaded_with_compare<C>(config: Config,
+                                           compare: C) -> io::Result<Sort<T>>
+    where
+        C: Compare<T> + 'static
+    {
+        Self::with_executor(config, Arc::new(compare), Executor::inline())
+    }
+
+    /// Creates a new `Sort` struct that runs the jobs with `executor`.
+    fn with_executor(config: Config, compare: Arc<dyn Compare<T>>,
+                     executor: Executor<T>) -> io::Result<Sort<T>> {
+        if config.num_merge < 2 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Config::num_merge must be at least 2"
+            ));
+        }
+        // One vector is being filled while the others are sorted
+        let chunks = BufferPool::new(config.max_in_flight_chunks + 1);
+        let in_flight = InFlight::new(config.max_in_flight_chunks);
+        let io_limit = InFlight::new(config.io_concurrency);
+        let throttle = config.max_io_rate
+            .map(|rate| Arc::new(Throttle::new(rate)));
+        let pressure = config.min_available_memory.map(Pressure::new);
+        let mut tmpdir = TrackedTempDir::new(
+            Builder::new().prefix("extsort")
+                .tempdir_in(extend_path(&env::temp_dir())?)?
+        );
+        tmpdir.tracker.retry = config.retry.clone();
+        if config.spill_memory.is_some() || !config.spill_dirs.is_empty()
+            || config.spill_store.is_some()
+        {
+            let tiers = Tiers::new(config.spill_memory, &config.spill_dirs,
+                                   config.spill_store.clone(),
+                                   &env::temp_dir(), config.retry.clone())?;
+            tmpdir.tracker.tiers = Some(Arc::new(tiers));
+        }
         Ok(Sort {
             config,
-            pool: ThreadPool::new(num_threads),
-            tmpdir: Builder::new().prefix("extsort").tempdir()?,
-            stage_num: RefCell::new(0),
-            file_num: RefCell::new(0),
-            result_cell: Arc::new(Mutex::new(Ok(()))),
-            _marker: marker::PhantomData
+            compare,
+            combine: None,
+            sorter: None,
+            executor,
+            tmpdir,
+            stage_num: AtomicUsize::new(0),
+            file_num: AtomicUsize::new(0),
+            stats: Mutex::new(SortStats::default()),
+            bytes_written: Arc::new(AtomicU64::new(0)),
+            chunks: Arc::new(chunks),
+            in_flight: Arc::new(in_flight),
+            io_limit: Arc::new(io_limit),
+            throttle,
+            pressure,
+            mmap: false,
+            codec: None,
+            filter: None,
+            map: None,
+            expired: None,
+            run_ranges: Arc::new(Mutex::new(Vec::new()))
         })
     }
 
+    /// Splits the data into sorted files.
+    fn split(&self, iter: impl Iterator<Item = T>) -> io::Result<()> {
+        self.split_with(|| self.split_invoke(iter))
+    }
+
+    /// Splits the data into sorted files with `split_invoke`, which adds the
+    /// jobs, and waits for the jobs to finish.
+    fn split_with<F>(&self, split_invoke: F) -> io::Result<()>
+    where
+        F: FnOnce() -> io::Result<()>
+    {
+        let start = Instant::now();
+        let result = split_invoke();
+        self.join_jobs()?;
+        self.chunks.clear();
+        result?;
+        let mut run_ranges = mem::take(&mut *self.run_ranges.lock().unwrap());
+        run_ranges.sort_unstable_by_key(|&(run, _)| run);
+        let mut stats = self.stats();
+        stats.run_ranges = run_ranges.into_iter()
+            .map(|(_, range)| range)
+            .collect();
+        stats.split_time = start.elapsed();
+        Ok(())
+    }
+
+    /// Merges the files until no more than `max_files` remain.
+    fn merge(&self, max_files: usize) -> io::Result<()> {
+        let start = Instant::now();
+        while self.file_num() > max_files {
+            let result = self.merge_invoke(max_files);
+            self.join_jobs()?;
+            result?;
+        }
+        self.stats().merge_time = start.elapsed();
+        Ok(())
+    }
+
     /// Performs external sorting, converting the sorter into `SortedIter`.
     pub fn sort<It>(self, iter: It) -> io::Result<SortedIter<T>>
     where
         It: Iterator<Item = T>
     {
         // First, split the data
-        let result = self.split_invoke(iter);
-        self.join_pool()?;
-        if let Err(err) = result {
+        self.split(iter)?;
+        // Then, merge the files until only one remains
+        self.merge(1)?;
+        // Finally, transform the sorter into iterator
+        self.into_sorted_iter()
+    }
+
+    /// Sorts the data like `sort()`, but chooses the strategy from the
+    /// estimated size of the input, which is `iter.size_hint()` multiplied by
+    /// the average size of the first records. The data that fits into the
+    /// memory budget of the split phase (`Config::max_split_size` for each
+    /// thread) is sorted in memory without the temporary files. Otherwise,
+    /// `Config::num_merge` is chosen to merge the runs in the minimal number
+    /// of passes. The chosen strategy is reported in `SortStats::strategy`.
+    ///
+    /// If the data turns out to exceed the memory budget, the records read so
+    /// far are written as one run, and the rest is sorted externally with
+    /// the strategy reported after merging.
+    pub fn sort_auto<It>(mut self, mut iter: It) -> io::Result<SortedIter<T>>
+    where
+        It: Iterator<Item = T>
+    {
+        let start = Instant::now();
+        let head: Vec<_> = iter.by_ref().take(AUTO_SAMPLE_LEN).collect();
+        let mut head_size: usize = head.iter().map(T::line_len).sum();
+        let (lower, upper) = iter.size_hint();
+        let rest_len = upper.unwrap_or(lower) as f64;
+        let avg_size = head_size as f64 / cmp::max(head.len(), 1) as f64;
+        let total_size = head_size as u64 + (avg_size * rest_len) as u64;
+        let strategy = self.config.plan(total_size);
+        self.stats().strategy = Some(strategy);
+        if strategy != Strategy::InMemory {
+            self.split(head.into_iter().chain(iter))?;
+            self.merge(1)?;
+            return self.into_sorted_iter();
+        }
+
+        let budget = self.config.memory_budget();
+        let mut data_vec = head;
+        for data in &data_vec {
+            self.count_input(data)?;
+        }
+        let mut rest = None;
+        for data in iter.by_ref() {
+            self.count_input(&data)?;
+            let size = data.line_len();
+            if head_size + size > budget {
+                rest = Some(data);
+                break;
+            }
+            head_size += size;
+            data_vec.push(data);
+        }
+        if let Some(data) = rest {
+            // The estimate was too low, so the data is sorted externally, and
+            // as the size is unknown, the runs are merged as many at once as
+            // the open files allow
+            self.config.num_merge = cmp::max(self.config.max_open_files, 2);
+            self.split_with(|| {
+                self.split_add_file(data_vec, head_size)?;
+                self.split_invoke(iter::once(data).chain(iter))
+            })?;
+            self.merge(1)?;
+            let passes = self.stats().merge_passes;
+            self.stats().strategy = Some(match passes {
+                0 | 1 => Strategy::SinglePass,
+                _ => Strategy::MultiPass
+            });
+            return self.into_sorted_iter();
+        }
+        let data_vec = self.sort_in_memory(data_vec);
+        self.stats().split_time = start.elapsed();
+        Ok(self.finish(Some(Sorted::Memory(data_vec.into_iter()))))
+    }
+
+    /// Sorts the data and writes the result into the file at `path`,
+    /// compressing it with `compression`. Returns the statistics collected
+    /// while sorting.
+    pub fn sort_to_file<It, P>(
+        self,
+        iter: It,
+        path: P,
+        compression: Compression
+    ) -> io::Result<SortStats>
+    where
+        It: Iterator<Item = T>,
+        P: AsRef<Path>
+    {
+        let sorted = self.sort(iter)?;
+        let stats = sorted.stats();
+        let file = File::create(extend_path(path.as_ref())?)?;
+        sorted.write_to(file, compression)?;
+        Ok(stats)
+    }
+
+    /// Sets the function that combines the equal elements into one. It's
+    /// applied whenever the equal elements meet in a chunk or in a merge, so
+    /// the aggregation happens on the fly and the temporary data stays small.
+    /// The first argument always comes earlier in the input, as long as the
+    /// function set with `with_chunk_sorter()`, if any, is stable. For
+    /// `sort_many()`, it's only guaranteed for the elements of the same input.
+    ///
+    /// The combiner takes precedence over `Config::unique`, and is ignored by
+    /// `top_frequent()`.
+    pub fn with_combiner<F>(mut self, combine: F) -> Sort<T>
+    where
+        F: Fn(T, T) -> T + Send + Sync + 'static
+    {
+        self.combine = Some(Arc::new(combine));
+        self
+    }
+
+    /// Sets the function used to sort each chunk in memory during the split
+    /// phase, instead of `slice::sort_by()`. It allows to use radix sort,
+    /// counting sort or other algorithms specific to the elements.
+    ///
+    /// The function must sort the chunk in the order defined by the
+    /// comparator passed to it. If the sort must be stable, the function must
+    /// be stable as well.
+    pub fn with_chunk_sorter<F>(mut self, sorter: F) -> Sort<T>
+    where
+        F: Fn(&mut [T], &dyn Compare<T>) + Send + Sync + 'static
+    {
+        self.sorter = Some(Arc::new(sorter));
+        self
+    }
+
+    /// Sets the function that decides whether the element is kept in the
+    /// output. It's applied to the elements as they are read from the final
+    /// merge, so the sorted data doesn't need another pass to be filtered.
+    ///
+    /// The filter is ignored by `count_distinct()` and `top_frequent()`.
+    pub fn with_filter<F>(mut self, filter: F) -> Sort<T>
+    where
+        F: Fn(&T) -> bool + Send + Sync + 'static
+    {
+        self.filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Sets the function that transforms the elements read from the final
+    /// merge, after they pass the filter set by `with_filter()`. The output
+    /// is not sorted again, so the function must preserve the order of the
+    /// elements for it to remain sorted.
+    ///
+    /// The function is ignored by `count_distinct()` and `top_frequent()`.
+    pub fn with_map<F>(mut self, map: F) -> Sort<T>
+    where
+        F: Fn(T) -> T + Send + Sync + 'static
+    {
+        self.map = Some(Arc::new(map));
+        self
+    }
+
+    /// Sets the function that checks whether the element is expired, for
+    /// example, by comparing its timestamp with the current time. The expired
+    /// elements are dropped whenever the data is written into the temporary
+    /// files and when it's read from the final merge, so they don't take space
+    /// in the temporary files. The function is called again on each pass, so
+    /// the elements that expire while sorting are dropped as well.
+    pub fn with_expiry<F>(mut self, expired: F) -> Sort<T>
+    where
+        F: Fn(&T) -> bool + Send + Sync + 'static
+    {
+        self.expired = Some(Arc::new(expired));
+        self
+    }
+
+    /// Sets the metrics updated while sorting, which may be shared with the
+    /// other sorters and exported with `Metrics::write_prometheus()`.
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Sort<T> {
+        self.tmpdir.tracker.metrics = Some(metrics);
+        self
+    }
+
+    /// Sets the hook called when the temporary files are created, merged and
+    /// deleted, with their paths and sizes, so they can be accounted by the
+    /// caller. The hook is called from the worker threads.
+    pub fn with_temp_file_hook<F>(mut self, hook: F) -> Sort<T>
+    where
+        F: Fn(&TempFileEvent) + Send + Sync + 'static
+    {
+        self.tmpdir.tracker.hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Enables reading the temporary files through memory mapping during the
+    /// merge phase. The records are then taken directly from the mapped
+    /// memory, without read syscalls and without copying the data into
+    /// buffers, which speeds up the merges of many files at once.
+    #[cfg(feature = "mmap")]
+    pub fn with_mmap(mut self) -> Sort<T> {
+        self.mmap = true;
+        self
+    }
+
+    /// Sorts the data, but leaves the last merge to be performed on the fly
+    /// by the returned iterator, so its result is never written. The runs are
+    /// merged beforehand only if there are more of them than
+    /// `Config::max_open_files` (but at least two), which the last merge
+    /// opens at most.
+    fn sort_lazy<It>(&self, iter: It) -> io::Result<MergeIter<Records<T>, T>>
+    where
+        It: Iterator<Item = T>
+    {
+        self.split(iter)?;
+        self.merge(cmp::max(self.config.max_open_files, 2))?;
+        MergeIter::new(self.last_stage_records()?, self.compare.clone(),
+                       self.duplicates())
+    }
+
+    /// Checks that `data` doesn't go before `prev` in the output of the last
+    /// merge performed on the fly if `Config::verify` is set. Returns
+    /// `ErrorKind::InvalidData` error with `index` of `data` in the output
+    /// otherwise.
+    fn verify_merged(&self, prev: Option<&T>, data: &T,
+                     index: u64) -> io::Result<()> {
+        let sorted = !self.config.verify || prev.is_none_or(|prev| {
+            self.compare.compare(prev, data) != cmp::Ordering::Greater
+        });
+        if !sorted {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("the merged record {} is less than the previous one",
+                        index)
+            ));
+        }
+        Ok(())
+    }
+
+    /// Counts the number of distinct elements in `iter`.
+    ///
+    /// The duplicates are dropped while merging, and the last merge is
+    /// performed on the fly, so the deduplicated data is never written.
+    pub fn count_distinct<It>(mut self, iter: It) -> io::Result<u64>
+    where
+        It: Iterator<Item = T>
+    {
+        self.config.unique = true;
+        let mut count = 0;
+        let mut prev = None;
+        for maybe_data in self.sort_lazy(iter)? {
+            let data = maybe_data?;
+            self.verify_merged(prev.as_ref(), &data, count)?;
+            prev = Some(data);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Finds `k` most frequent elements in `iter`. Returns the elements along
+    /// with the number of their occurrences, the most frequent ones first.
+    ///
+    /// The elements are sorted externally to count the occurrences, and only
+    /// `k` best candidates are kept in memory.
+    pub fn top_frequent<It>(mut self, iter: It,
+                            k: usize) -> io::Result<Vec<(T, u64)>>
+    where
+        It: Iterator<Item = T>
+    {
+        self.config.unique = false;
+        self.combine = None;
+        // The candidates are kept in `best`, and `heap` contains their counts
+        // along with their indices in `best`, the smallest count on top
+        let mut best = Vec::with_capacity(k);
+        let mut heap = BinaryHeap::with_capacity(k);
+        let mut push = |data: T, count: u64| {
+            if best.len() < k {
+                heap.push(Reverse((count, best.len())));
+                best.push((data, count));
+                return;
+            }
+            match heap.peek() {
+                Some(&Reverse((min_count, idx))) if min_count < count => {
+                    heap.pop();
+                    heap.push(Reverse((count, idx)));
+                    best[idx] = (data, count);
+                },
+                _ => {}
+            }
+        };
+        let mut cur: Option<(T, u64)> = None;
+        for (index, maybe_data) in self.sort_lazy(iter)?.enumerate() {
+            let data = maybe_data?;
+            let prev = cur.as_ref().map(|(cur_data, _)| cur_data);
+            self.verify_merged(prev, &data, index as u64)?;
+            cur = match cur {
+                Some((cur_data, count))
+                        if self.compare.compare(&cur_data, &data) ==
+                            cmp::Ordering::Equal => {
+                    Some((cur_data, count + 1))
+                },
+                Some((cur_data, count)) => {
+                    push(cur_data, count);
+                    Some((data, 1))
+                },
+                None => Some((data, 1))
+            };
+        }
+        if let Some((cur_data, count)) = cur {
+            push(cur_data, count);
+        }
+        best.sort_by_key(|&(_, count)| Reverse(count));
+        Ok(best)
+    }
+}
+
+impl<T: FromLine + IntoLine> Sort<T> {
+    /// Finds the element that would be at position `n` (counting from zero)
+    /// if the elements of `iter` were sorted, or `None` if `iter` has not
+    /// more than `n` elements. For example, the median of `len` elements is
+    /// at position `len / 2`.
+    ///
+    /// It's much cheaper than sorting the data. The candidates that don't fit
+    /// into `Config::max_split_size` bytes are written into a temporary file,
+    /// which is narrowed down by the passes that keep only the candidates
+    /// between two pivots chosen from a random sample, so each pass usually
+    /// leaves a small fraction of the candidates. The elements are selected
+    /// in memory once they fit.
+    pub fn select_nth<It>(self, iter: It, n: usize) -> io::Result<Option<T>>
+    where
+        It: Iterator<Item = T>
+    {
+        select_nth(iter, n, &*self.compare, self.config.max_split_size,
+                   self.tmpdir.path(), &self.config.retry)
+    }
+}
+
+impl<T: FromLine + IntoLine + Send> Sort<T> {
+    /// Adds jobs to split the data from several inputs. The inputs are taken
+    /// by up to `Config::num_threads` workers, and each of them is split as
+    /// by `split_invoke()`. Returns the first error that occurred.
+    fn split_invoke_many<It>(&self, inputs: Vec<It>) -> io::Result<()>
+    where
+        It: Iterator<Item = T> + Send
+    {
+        if let Executor::Inline(_) = self.executor {
+            return inputs.into_iter()
+                .try_for_each(|input| self.split_invoke(input));
+        }
+        let num_workers = cmp::min(inputs.len(),
+                                   cmp::max(self.config.num_threads, 1));
+        let inputs = Mutex::new(inputs.into_iter());
+        let cpus = self.config.cpu_set();
+        let work = || {
+            pin_current_thread(cpus.as_deref())?;
+            loop {
+                let input = inputs.lock().unwrap().next();
+                match input {
+                    Some(input) => self.split_invoke(input)?,
+                    None => return Ok(())
+                }
+            }
+        };
+        thread::scope(|scope| {
+            let handles: Vec<_> = (0..num_workers)
+                .map(|_| scope.spawn(work))
+                .collect();
+            let mut result = Ok(());
+            for handle in handles {
+                let worker_result = match handle.join() {
+                    Ok(worker_result) => worker_result,
+                    Err(payload) => panic::resume_unwind(payload)
+                };
+                if result.is_ok() {
+                    result = worker_result;
+                }
+            }
+            result
+        })
+    }
+
+    /// Sorts the data from several inputs, like the files the input is
+    /// already split into. The inputs are read in parallel by up to
+    /// `Config::num_threads` workers, which split them into runs
+    /// concurrently, and then all the runs are merged together. The sorter
+    /// created with `single_threaded()` reads the inputs one after another.
+    ///
+    /// Each worker builds its own chunk, so the split phase may keep up to
+    /// `Config::num_threads` more chunks in memory than `sort()` does.
+    ///
+    /// The equal elements of each input keep their order, but the order of
+    /// the equal elements from the different inputs is unspecified.
+    pub fn sort_many<It>(self, inputs: Vec<It>) -> io::Result<SortedIter<T>>
+    where
+        It: Iterator<Item = T> + Send
+    {
+        self.split_with(|| self.split_invoke_many(inputs))?;
+        self.merge(1)?;
+        self.into_sorted_iter()
+    }
+}
+
+impl<K: FromLine + IntoLine> Sort<KeyValue<K, BlobRef>> {
+    /// Sorts the pairs by their keys, keeping the values out of line: the
+    /// values are streamed into a blob file in the temporary directory, and
+    /// only the keys with the offsets and the lengths of the values are
+    /// sorted. The values are read back from the blob file when the result is
+    /// iterated, so the large values are never copied by the merge passes.
+    ///
+    /// Returns the first error that occurred while writing the values.
+    pub fn sort_blobs<V, It>(self, iter: It) -> io::Result<BlobIter<K, V>>
+    where
+        V: FromLine + IntoLine,
+        It: Iterator<Item = KeyValue<K, V>>
+    {
+        let path = self.tmpdir.path().join("blobs.bin");
+        let tracker = self.tmpdir.tracker.clone();
+        let mut writer = BlobWriter::create(&path)?;
+        let mut error = None;
+        let pairs = iter.map(|pair| writer.push(pair));
+        let sorted = self.sort(until_error(pairs, &mut error))?;
+        if let Some(err) = error {
             return Err(err);
         }
-        // Then, merge the files until only one remains
-        while *self.file_num.borrow() > 1 {
-            let result = self.merge_invoke();
-            self.join_pool()?;
-            if let Err(err) = result {
-                return Err(err);
+        tracker.created(&path, writer.finish()?)?;
+        BlobIter::open(sorted, &path)
+    }
+}
+
+impl<K, V> Sort<KeyValue<K, V>>
+where
+    KeyValue<K, V>: FromLine + IntoLine
+{
+    /// Sets the function that combines the values of the pairs with equal
+    /// keys, like a combiner in map-reduce. See `with_combiner()` for details.
+    pub fn with_value_combiner<F>(self, combine: F) -> Sort<KeyValue<K, V>>
+    where
+        F: Fn(&K, V, V) -> V + Send + Sync + 'static
+    {
+        self.with_combiner(move |a: KeyValue<K, V>, b: KeyValue<K, V>| {
+            let value = combine(&a.key, a.value, b.value);
+            KeyValue { key: a.key, value }
+        })
+    }
+}
+
+impl<T: FromLine + IntoLine + RadixKey + Copy + 'static> Sort<T> {
+    /// Sorts the chunks with radix sort instead of the comparison sort. The
+    /// comparator is not used to sort the chunks, so it must define the same
+    /// order as `RadixKey`, like the natural order of the integers does.
+    pub fn with_radix_sort(self) -> Sort<T> {
+        self.with_chunk_sorter(|chunk: &mut [T], _: &dyn Compare<T>| {
+            radix_sort(chunk)
+        })
+    }
+}
+
+impl<T: FromLine + IntoLine + FixedWidth + Send + 'static> Sort<T> {
+    /// Writes the temporary run files with each record stored in
+    /// `FixedWidth::WIDTH` bytes instead of a line, so the fixed-width
+    /// integers are neither formatted nor parsed. Combined with
+    /// `with_radix_sort()`, it's the fast path for sorting the integers.
+    ///
+    /// The binary runs are never mapped into memory or read through io_uring,
+    /// and reading them is not limited by `Config::max_io_rate`.
+    pub fn with_binary_runs(mut self) -> Sort<T> {
+        self.codec = Some(Arc::new(BinaryRuns::new()));
+        self
+    }
+}
+
+#[cfg(feature = "arrow")]
+impl<T: FromLine + IntoLine> Sort<T> {
+    /// Sorts the data and writes the result into `writer` as an Arrow IPC
+    /// stream with one string column `line`, so it can be read directly by
+    /// pandas, polars and other Arrow consumers. Returns the statistics
+    /// collected while sorting.
+    pub fn sort_to_arrow_ipc<It, W>(self, iter: It,
+                                    writer: W) -> io::Result<SortStats>
+    where
+        It: Iterator<Item = T>,
+        W: Write
+    {
+        let sorted = self.sort(iter)?;
+        let stats = sorted.stats();
+        write_arrow_ipc_lines(sorted, writer, DEFAULT_BATCH_SIZE)?;
+        Ok(stats)
+    }
+}
+
+#[cfg(feature = "protobuf")]
+impl<M: Message + fmt::Debug + Default + 'static> Sort<Proto<M>> {
+    /// Writes the temporary run files with each message prefixed by its
+    /// length encoded as varint, like `write_delimited()` does. The messages
+    /// have no line form, so sorting them requires it unless all of them fit
+    /// into memory.
+    ///
+    /// The runs are never mapped into memory or read through io_uring, and
+    /// reading them is not limited by `Config::max_io_rate`.
+    pub fn with_protobuf_runs(mut self) -> Sort<Proto<M>> {
+        self.codec = Some(Arc::new(DelimitedRuns::new()));
+        self
+    }
+}
+
+#[cfg(feature = "parquet")]
+impl<T: FromLine + IntoLine + ArrowCodec + Send + 'static> Sort<T> {
+    /// Writes the temporary run files in the Parquet format with the schema
+    /// derived from `ArrowCodec` instead of lines. The records are buffered
+    /// and written in batches, and the runs are compressed by columns.
+    ///
+    /// The Parquet runs are never mapped into memory or read through
+    /// io_uring, and reading them is not limited by `Config::max_io_rate`.
+    pub fn with_parquet_runs(mut self) -> Sort<T> {
+        self.codec = Some(Arc::new(ParquetRuns::new()));
+        self
+    }
+
+    /// Sorts the data and writes the result into `writer` as a Parquet file
+    /// with the schema derived from `ArrowCodec`, so it's read by the data
+    /// lake tools without a conversion step. Returns the statistics collected
+    /// while sorting.
+    pub fn sort_to_parquet<It, W>(self, iter: It,
+                                  writer: W) -> io::Result<SortStats>
+    where
+        It: Iterator<Item = T>,
+        W: Write + Send
+    {
+        let sorted = self.sort(iter)?;
+        let stats = sorted.stats();
+        write_parquet_records(sorted, writer, DEFAULT_BATCH_SIZE)?;
+        Ok(stats)
+    }
+}
+
+impl Sort<String> {
+    /// Sorts the lines read from `reader`. Unless the sorter was created with
+    /// a custom comparator, the lines are compared bytewise.
+    ///
+    /// The lines are split right in the buffer of `reader`, and each of them
+    /// is allocated once with its exact size. A `String` is written into the
+    /// temporary files as is, so no conversion happens on the way out.
+    ///
+    /// Returns the first error that occurred while reading the lines.
+    pub fn sort_lines<R: BufRead>(
+        self,
+        mut reader: R
+    ) -> io::Result<SortedIter<String>> {
+        let max_size = self.config.max_record_size;
+        let mut index = 0;
+        let mut error = None;
+        let lines = iter::from_fn(|| {
+            match read_line_limited(&mut reader, index, max_size) {
+                Ok(line) => {
+                    index += 1;
+                    line
+                },
+                Err(err) => {
+                    error = Some(err);
+                    None
+                }
             }
+        });
+        let sorted = self.sort(lines)?;
+        match error {
+            Some(err) => Err(err),
+            None => Ok(sorted)
+        }
+    }
+}
+
+/// Yields the values from `iter` until the first error, which is stored into
+/// `error` instead. It lets the fallible input be sorted by `Sort::sort()`,
+/// after which the caller returns the stored error, if any.
+pub(crate) fn until_error<'a, I, T, E>(
+    iter: I,
+    error: &'a mut Option<E>
+) -> impl Iterator<Item = T> + 'a
+where
+    I: Iterator<Item = Result<T, E>> + 'a
+{
+    iter.map_while(move |maybe_data| match maybe_data {
+        Ok(data) => Some(data),
+        Err(err) => {
+            *error = Some(err);
+            None
+        }
+    })
+}
+
+/// Reads the next line from `reader` like `BufRead::lines()`, but stops
+/// reading and fails with `RecordTooLarge` if the line at position `index`
+/// exceeds `max_size` bytes.
+///
+/// The line is looked up in the buffer of `reader` with `memchr`, and the
+/// line that fits into the buffer is copied out of it with one allocation of
+/// the exact size, instead of growing the string byte by byte.
+fn read_line_limited<R: BufRead>(
+    reader: &mut R,
+    index: u64,
+    max_size: Option<usize>
+) -> io::Result<Option<String>> {
+    // The limit leaves the room for the carriage return
+    let limit = max_size.map_or(usize::MAX, |max_size| max_size + 1);
+    let mut buf = Vec::new();
+    let mut found = false;
+    let mut done = false;
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err)
+        };
+        if available.is_empty() {
+            if !found {
+                return Ok(None);
+            }
+            break;
+        }
+        found = true;
+        let len = match memchr(b'\n', available) {
+            Some(len) => {
+                done = true;
+                len
+            },
+            None => available.len()
+        };
+        if buf.is_empty() {
+            buf = available[..len].to_vec();
+        } else {
+            buf.extend_from_slice(&available[..len]);
+        }
+        reader.consume(if done { len + 1 } else { len });
+        if done || buf.len() > limit {
+            break;
+        }
+    }
+    if done && buf.last() == Some(&b'\r') {
+        buf.pop();
+    }
+    if let Some(max_size) = max_size {
+        if buf.len() > max_size {
+            return Err(RecordTooLarge { index, max_size }.into());
         }
-        // Finally, transform the sorter into iterator
-        self.as_iter()
     }
+    String::from_utf8(buf)
+        .map(Some)
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))
+}
+
+/// Sorts the lines read from `reader` bytewise with the default
+/// configuration.
+pub fn sort_lines<R: BufRead>(reader: R) -> io::Result<SortedIter<String>> {
+    Sort::new(Config::default())?.sort_lines(reader)
 }
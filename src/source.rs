@@ -0,0 +1,117 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use super::compare::Compare;
+use super::lines::FromLine;
+use super::merge::{MergeIter, Duplicates};
+use super::sort::{SortedIter, file_records};
+
+/// Boxed iterator over the elements of one source.
+type SourceIter<'a, T> = Box<dyn Iterator<Item = io::Result<T>> + 'a>;
+
+/// Source of the sorted data merged by `merge_sources()`.
+pub enum MergeSource<'a, T> {
+    /// Iterator over the sorted elements, for example, the ones kept in memory
+    Iter(SourceIter<'a, T>),
+    /// File with the sorted elements, written one per line
+    File(PathBuf),
+    /// Result of the previous sort
+    Sorted(Box<SortedIter<T>>)
+}
+
+impl<'a, T: 'a> MergeSource<'a, T> {
+    /// Creates the source from the iterator over the sorted elements.
+    pub fn iter<I>(iter: I) -> MergeSource<'a, T>
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: 'a
+    {
+        MergeSource::Iter(Box::new(iter.into_iter().map(Ok)))
+    }
+
+    /// Opens the source, so the elements can be read from it.
+    fn open(self) -> io::Result<SourceIter<'a, T>>
+    where
+        T: FromLine
+    {
+        Ok(match self {
+            MergeSource::Iter(iter) => iter,
+            MergeSource::File(path) => Box::new(file_records(path, false)?),
+            MergeSource::Sorted(sorted) => Box::new(sorted)
+        })
+    }
+}
+
+impl<'a, T> From<SortedIter<T>> for MergeSource<'a, T> {
+    fn from(sorted: SortedIter<T>) -> MergeSource<'a, T> {
+        MergeSource::Sorted(Box::new(sorted))
+    }
+}
+
+/// The iterator over the merged data, created by `merge_sources()`.
+pub struct MergedIter<'a, T> {
+    /// The underlying iterator
+    inner: MergeIter<SourceIter<'a, T>, T>
+}
+
+impl<'a, T> Iterator for MergedIter<'a, T> {
+    type Item = io::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+/// Opens the sources and merges them, handling the equal elements as defined
+/// by `duplicates`.
+pub(crate) fn merge_with_duplicates<'a, T>(
+    sources: Vec<MergeSource<'a, T>>,
+    compare: Arc<dyn Compare<T>>,
+    duplicates: Duplicates<T>
+) -> io::Result<MergedIter<'a, T>>
+where
+    T: FromLine + 'a
+{
+    let iters = sources.into_iter()
+        .map(MergeSource::open)
+        .collect::<io::Result<_>>()?;
+    let inner = MergeIter::new(iters, compare, duplicates)?;
+    Ok(MergedIter { inner })
+}
+
+/// Merges the sorted sources into one sorted sequence. The sources may be
+/// of different kinds: the sorted elements in memory, the sorted files on disk
+/// and the results of the previous sorts. Each source must be sorted in the
+/// order defined by `compare`, which is not checked.
+///
+/// The equal elements are returned in the order of their sources.
+pub fn merge_sources<'a, T, C>(
+    sources: Vec<MergeSource<'a, T>>,
+    compare: C
+) -> io::Result<MergedIter<'a, T>>
+where
+    T: FromLine + 'a,
+    C: Compare<T> + 'static
+{
+    merge_with_duplicates(sources, Arc::new(compare), Duplicates::Keep)
+}
+
+/// Same as `merge_sources()`, but the sources are ranked by their position,
+/// with the later sources considered newer, and only the newest of the equal
+/// elements is returned. It's useful to reconcile a snapshot with the deltas
+/// applied after it: the snapshot goes first, and each delta overrides the
+/// elements with the same keys.
+///
+/// If the newest source contains several equal elements, the last one of them
+/// is returned.
+pub fn merge_newest<'a, T, C>(
+    sources: Vec<MergeSource<'a, T>>,
+    compare: C
+) -> io::Result<MergedIter<'a, T>>
+where
+    T: FromLine + 'a,
+    C: Compare<T> + 'static
+{
+    let newest = Duplicates::Combine(Arc::new(|_, second| second));
+    merge_with_duplicates(sources, Arc::new(compare), newest)
+}
@@ -1,78 +1,274 @@
-use std::io::{self, Lines, BufWriter, BufReader, BufRead, Write, Seek, SeekFrom};
+use std::io::{self, BufWriter, Write, Seek, SeekFrom};
+use std::io::Error;
+use super::aggregate::Aggregate;
 use super::lines::{FromLine, IntoLine};
-use std::marker;
-use tempfile::SpooledTempFile;
+use super::run::{LineReader, RunReader};
+#[cfg(feature = "threads")]
+use super::buffer::InFlight;
+#[cfg(feature = "threads")]
+use super::pool::Pool;
+#[cfg(feature = "threads")]
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::mem;
+#[cfg(feature = "threads")]
+use std::sync::Arc;
+use std::vec;
+
+/// Struct that represents configuration of the splitter.
+pub struct SplitConfig {
+    /// Maximum size of the group (in bytes) that is kept in memory. Larger
+    /// groups are moved into a temporary file
+    pub spool_threshold: usize,
+    /// Maximum number of elements in the group
+    pub max_group_len: Option<usize>,
+    /// Maximum size of the group (in bytes)
+    pub max_group_size: Option<usize>
+}
+
+impl Default for SplitConfig {
+    fn default() -> SplitConfig {
+        SplitConfig {
+            spool_threshold: 1 << 13,
+            max_group_len: None,
+            max_group_size: None
+        }
+    }
+}
+
+impl SplitConfig {
+    /// Checks whether the group with `len` elements and `size` bytes exceeds
+    /// the limits.
+    fn exceeds(&self, len: usize, size: usize) -> bool {
+        self.max_group_len.is_some_and(|max_len| len > max_len) ||
+            self.max_group_size.is_some_and(|max_size| size > max_size)
+    }
+}
+
+/// Error that is returned by `SplitIter` if the group exceeds the limits set
+/// in `SplitConfig`. It is wrapped into `io::Error` with `ErrorKind::Other`.
+#[derive(Clone, Debug)]
+pub struct GroupTooLarge {
+    /// Number of elements in the group
+    pub len: usize,
+    /// Size of the group (in bytes)
+    pub size: usize
+}
+
+impl fmt::Display for GroupTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "group of {} elements ({} bytes) exceeds the limits",
+               self.len, self.size)
+    }
+}
+
+impl error::Error for GroupTooLarge {}
+
+impl From<GroupTooLarge> for Error {
+    fn from(err: GroupTooLarge) -> Error {
+        Error::other(err)
+    }
+}
+
+/// Source of the elements in the group.
+enum GroupSource<T> {
+    /// The group is small enough to be kept in memory
+    Memory(vec::IntoIter<T>),
+    /// The group is kept in the temporary file
+    File(RunReader<File>)
+}
 
 /// Iterator to iterate over the group of equal elements.
 pub struct SameSplitIter<T> {
-    /// Lines iterator from which the elements are taken
-    lines: Lines<BufReader<SpooledTempFile>>,
-    _marker: marker::PhantomData<T>
+    /// Source from which the elements are taken
+    source: GroupSource<T>,
+    /// Element that was taken from `source` by `peek()`, but not yet returned
+    /// by `next()`
+    peeked: Option<Option<io::Result<T>>>
 }
 
+/// Predicate that checks whether two elements are equal.
+type EqFn<T> = fn(&T, &T) -> bool;
+
 /// Iterator to split the source iterator onto groups of equal elements.
-pub struct SplitIter<Iter, T> {
+pub struct SplitIter<Iter, T, F = EqFn<T>> {
     /// Source iterator
     iter: Iter,
     /// Last value taken from the source iterator
     last: Option<T>,
-    _marker: marker::PhantomData<T>
+    /// Predicate that checks whether two elements belong to the same group
+    same: F,
+    /// Splitter configuration
+    config: SplitConfig
+}
+
+impl<T: FromLine> SameSplitIter<T> {
+    /// Returns a reference to the next element of the group without consuming
+    /// it. Before the iteration starts, the returned element is the first one
+    /// in the group, so it can be used as the group key to decide whether the
+    /// group needs to be processed at all.
+    pub fn peek(&mut self) -> Option<&io::Result<T>> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.take_next());
+        }
+        self.peeked.as_ref().unwrap().as_ref()
+    }
+
+    /// Takes the next element from the source.
+    fn take_next(&mut self) -> Option<io::Result<T>> {
+        match &mut self.source {
+            GroupSource::Memory(iter) => iter.next().map(Ok),
+            GroupSource::File(reader) => reader.next_record()
+        }
+    }
 }
 
 impl<T: FromLine> Iterator for SameSplitIter<T> {
     type Item = io::Result<T>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.lines.next() {
-            Some(maybe_line) => Some(match maybe_line {
-                Ok(ln) => T::from_line(&ln),
-                Err(err) => Err(err)
-            }),
-            None => None
+        match self.peeked.take() {
+            Some(peeked) => peeked,
+            None => self.take_next()
         }
     }
 }
 
-impl<Iter, T> Iterator for SplitIter<Iter, T>
+/// Writes `data` as a line into `writer`.
+fn write_data<T: IntoLine, W: Write>(writer: &mut W, data: T) -> io::Result<()> {
+    let line = data.into_line() + "\n";
+    writer.write_all(line.as_bytes())
+}
+
+impl<Iter, T, F> SplitIter<Iter, T, F>
 where
     Iter: Iterator<Item = T>,
-    T: FromLine + IntoLine + Eq
+    T: FromLine + IntoLine,
+    F: FnMut(&T, &T) -> bool
 {
-    type Item = io::Result<SameSplitIter<T>>;
+    /// Takes the next element from the source iterator. Returns the element
+    /// and the flag indicating whether it's the last one in the group.
+    fn take_next(&mut self) -> (T, bool) {
+        let next = self.iter.next();
+        let finish = match next.as_ref() {
+            None => true,
+            Some(val) => !(self.same)(self.last.as_ref().unwrap(), val)
+        };
+        (mem::replace(&mut self.last, next).unwrap(), finish)
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.last.is_none() {
-            return None;
+    /// Drops the remaining elements of the current group. Returns the number
+    /// of dropped elements and their size.
+    fn drop_group(&mut self) -> (usize, usize) {
+        let mut len = 0;
+        let mut size = 0;
+        while self.last.is_some() {
+            let (data, finish) = self.take_next();
+            len += 1;
+            size += data.line_len() + 1;
+            if finish {
+                break;
+            }
         }
-        let mut file = tempfile::spooled_tempfile(1 << 13);
-        {
-            let mut writer = BufWriter::new(&mut file);
-            loop {
-                let next = self.iter.next();
-                let last_ref = self.last.as_ref().unwrap();
-                let finish = match next.as_ref() {
-                    None => true,
-                    Some(val) => val != last_ref
-                };
-                if let Some(data) = self.last.take() {
-                    let line = data.into_line() + "\n";
-                    if let Err(err) = writer.write_all(line.as_bytes()) {
-                        return Some(Err(err));
+        (len, size)
+    }
+
+    /// Skips the next group without storing its elements. Returns the number
+    /// of skipped elements, which is zero if no groups are left.
+    pub fn skip_group(&mut self) -> usize {
+        self.drop_group().0
+    }
+
+    /// Takes the next group and adds the values obtained from its elements
+    /// with `value` to `agg` without storing the elements. Returns the first
+    /// element of the group, or `None` if no groups are left.
+    pub(crate) fn aggregate_group<V, A, ValueFn>(&mut self, value: &mut ValueFn,
+                                                 agg: &mut A) -> Option<T>
+    where
+        A: Aggregate<V>,
+        ValueFn: FnMut(&T) -> V
+    {
+        self.last.as_ref()?;
+        let (first, mut finish) = self.take_next();
+        agg.add(value(&first));
+        while !finish {
+            let (data, last) = self.take_next();
+            agg.add(value(&data));
+            finish = last;
+        }
+        Some(first)
+    }
+
+    /// Returns a reference to the first element of the next group without
+    /// taking the group from the iterator.
+    pub fn peek(&self) -> Option<&T> {
+        self.last.as_ref()
+    }
+
+    /// Takes the next group of equal elements from the source iterator. The
+    /// group is kept in memory until its size exceeds `spool_threshold`, and
+    /// is moved into a temporary file after that.
+    ///
+    /// If the group exceeds the limits, the rest of it is skipped, and
+    /// `GroupTooLarge` error is returned.
+    fn take_group(&mut self) -> io::Result<SameSplitIter<T>> {
+        let mut group = Vec::new();
+        let mut group_len = 0;
+        let mut group_size = 0;
+        let mut writer = None;
+        loop {
+            let (data, finish) = self.take_next();
+            group_len += 1;
+            group_size += data.line_len() + 1;
+            if self.config.exceeds(group_len, group_size) {
+                let (len, size) = if finish { (0, 0) } else { self.drop_group() };
+                return Err(GroupTooLarge {
+                    len: group_len + len,
+                    size: group_size + size
+                }.into());
+            }
+            match writer.as_mut() {
+                Some(writer) => write_data(writer, data)?,
+                None => {
+                    group.push(data);
+                    if group_size > self.config.spool_threshold {
+                        let mut new_writer = BufWriter::new(tempfile::tempfile()?);
+                        for data in group.drain(..) {
+                            write_data(&mut new_writer, data)?;
+                        }
+                        writer = Some(new_writer);
                     }
                 }
-                self.last = next;
-                if finish {
-                    break;
-                }
+            }
+            if finish {
+                break;
             }
         }
-        if let Err(err) = file.seek(SeekFrom::Start(0)) {
-            return Some(Err(err));
-        }
-        Some(Ok(SameSplitIter {
-            lines: BufReader::new(file).lines(),
-            _marker: marker::PhantomData
-        }))
+        let source = match writer {
+            Some(writer) => {
+                let mut file = writer.into_inner().map_err(|err| err.into_error())?;
+                file.seek(SeekFrom::Start(0))?;
+                GroupSource::File(RunReader::new(file))
+            },
+            None => GroupSource::Memory(group.into_iter())
+        };
+        Ok(SameSplitIter { source, peeked: None })
+    }
+}
+
+impl<Iter, T, F> Iterator for SplitIter<Iter, T, F>
+where
+    Iter: Iterator<Item = T>,
+    T: FromLine + IntoLine,
+    F: FnMut(&T, &T) -> bool
+{
+    type Item = io::Result<SameSplitIter<T>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.last.as_ref()?;
+        Some(self.take_group())
     }
 }
 
@@ -81,11 +277,119 @@ where
 ///
 /// To perform the split, the iterator will use external memory if it's
 /// necessary.
-pub fn split<Iter, T>(mut iter: Iter) -> SplitIter<Iter, T>
+pub fn split<Iter, T>(iter: Iter) -> SplitIter<Iter, T>
 where
     Iter: Iterator<Item = T>,
     T: FromLine + IntoLine + Eq
+{
+    split_with_config(iter, SplitConfig::default())
+}
+
+/// Same as `split()`, but uses the given configuration.
+pub fn split_with_config<Iter, T>(mut iter: Iter,
+                                  config: SplitConfig) -> SplitIter<Iter, T>
+where
+    Iter: Iterator<Item = T>,
+    T: FromLine + IntoLine + Eq
+{
+    let last = iter.next();
+    SplitIter { iter, last, same: T::eq as EqFn<T>, config }
+}
+
+/// Creates an iterator that splits all the items from `iter` into the groups
+/// of elements with equal keys. The key of each element is obtained with
+/// `key_fn`.
+///
+/// To perform the split, the iterator will use external memory if it's
+/// necessary.
+pub fn split_by_key<Iter, T, K, KeyFn>(
+    iter: Iter,
+    key_fn: KeyFn
+) -> SplitIter<Iter, T, impl FnMut(&T, &T) -> bool>
+where
+    Iter: Iterator<Item = T>,
+    T: FromLine + IntoLine,
+    K: Eq,
+    KeyFn: FnMut(&T) -> K
+{
+    split_by_key_with_config(iter, key_fn, SplitConfig::default())
+}
+
+/// Same as `split_by_key()`, but uses the given configuration.
+pub fn split_by_key_with_config<Iter, T, K, KeyFn>(
+    mut iter: Iter,
+    mut key_fn: KeyFn,
+    config: SplitConfig
+) -> SplitIter<Iter, T, impl FnMut(&T, &T) -> bool>
+where
+    Iter: Iterator<Item = T>,
+    T: FromLine + IntoLine,
+    K: Eq,
+    KeyFn: FnMut(&T) -> K
 {
     let last = iter.next();
-    SplitIter { iter, last, _marker: marker::PhantomData }
+    // The elements are compared with the previous ones in order, so the key
+    // of `b` is kept for the next call, where it's the key of `a`
+    let mut last_key = None;
+    let same = move |a: &T, b: &T| {
+        let key = last_key.take().unwrap_or_else(|| key_fn(a));
+        let next_key = key_fn(b);
+        let same = key == next_key;
+        last_key = Some(next_key);
+        same
+    };
+    SplitIter { iter, last, same, config }
+}
+
+/// Takes the groups from `groups` (which is usually a `SplitIter`) and calls
+/// `f` for each of them. While the groups are taken sequentially, `f` is run
+/// in the thread pool of `num_threads` threads, so the groups are processed
+/// in parallel. If the `threads` feature is disabled, `f` is run on the
+/// calling thread.
+///
+/// No more than `2 * num_threads` groups are processed or wait for a thread
+/// at once, and taking the next group blocks until one of them is done, so
+/// the groups kept in memory don't pile up when `f` is slower than splitting.
+///
+/// Returns the first error that occurred either during splitting or in `f`.
+pub fn for_each_group_parallel<Groups, T, F>(groups: Groups, num_threads: usize,
+                                             f: F) -> io::Result<()>
+where
+    Groups: Iterator<Item = io::Result<SameSplitIter<T>>>,
+    T: Send + 'static,
+    F: Fn(SameSplitIter<T>) -> io::Result<()> + Send + Sync + 'static
+{
+    #[cfg(feature = "threads")]
+    {
+        let num_threads = cmp::max(num_threads, 1);
+        let pool = Pool::new(num_threads, None);
+        let in_flight = Arc::new(InFlight::new(2 * num_threads));
+        let f = Arc::new(f);
+        let mut result = Ok(());
+        for maybe_group in groups {
+            let group = match maybe_group {
+                Ok(group) => group,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            };
+            let slot = in_flight.acquire();
+            let f = f.clone();
+            pool.add(move || {
+                let _slot = slot;
+                f(group)
+            });
+        }
+        pool.join()?;
+        result
+    }
+    #[cfg(not(feature = "threads"))]
+    {
+        let _ = num_threads;
+        for maybe_group in groups {
+            f(maybe_group?)?;
+        }
+        Ok(())
+    }
 }
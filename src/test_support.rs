@@ -0,0 +1,105 @@
+use std::cmp::Ordering;
+use std::fmt::Debug;
+use std::sync::Arc;
+use super::compare::{Compare, ByOrd};
+use super::lines::{FromLine, IntoLine};
+use super::retry::RetryPolicy;
+use super::sort::{Sort, Config};
+
+/// Comparator shared between the sorter and the checker.
+struct Shared<C>(Arc<C>);
+
+impl<T, C: Compare<T>> Compare<T> for Shared<C> {
+    fn compare(&self, a: &T, b: &T) -> Ordering {
+        self.0.compare(a, b)
+    }
+}
+
+/// Checks that each value survives the conversion into the line and back,
+/// and that the line contains no characters forbidden by `IntoLine`. Panics
+/// with the offending value otherwise.
+pub fn assert_line_roundtrip<T, I>(values: I)
+where
+    T: FromLine + IntoLine + Clone + PartialEq + Debug,
+    I: IntoIterator<Item = T>
+{
+    for value in values {
+        let line = value.clone().into_line();
+        let forbidden = |ch: &char| matches!(ch, '\r' | '\n' | '\0');
+        if let Some(ch) = line.chars().find(forbidden) {
+            panic!("line {:?} of value {:?} contains {:?}", line, value, ch);
+        }
+        match T::from_line(&line) {
+            Ok(parsed) => assert_eq!(
+                parsed, value,
+                "value {:?} is parsed back from line {:?} as {:?}",
+                value, line, parsed
+            ),
+            Err(err) => {
+                panic!("cannot parse line {:?} of value {:?}: {}",
+                       line, value, err)
+            }
+        }
+    }
+}
+
+/// Returns the configuration that makes the sorter create a temporary file
+/// for almost every element and merge them two at a time, with the temporary
+/// files verified after they're written. It's slow, but it exercises all the
+/// phases of sorting even on a few elements.
+pub fn tiny_config() -> Config {
+    Config {
+        num_merge: 2,
+        num_threads: 1,
+        max_split_size: 64,
+        unique: false,
+        max_in_flight_chunks: 1,
+        io_concurrency: 1,
+        max_io_rate: None,
+        min_available_memory: None,
+        max_open_files: 2,
+        cpu_affinity: None,
+        verify: true,
+        retry: RetryPolicy::default(),
+        max_record_size: None,
+        spill_memory: None,
+        spill_dirs: Vec::new(),
+        spill_store: None
+    }
+}
+
+/// Sorts `values` on a single thread with `tiny_config()` and checks that the
+/// result matches the in-memory sort by their `Ord` implementation. Panics if
+/// the results differ or an error occurs.
+pub fn assert_sorts<T>(values: Vec<T>)
+where
+    T: FromLine + IntoLine + Ord + Clone + Debug
+{
+    assert_sorts_by(values, ByOrd);
+}
+
+/// Same as `assert_sorts()`, but sorts the values with `compare`. The sort is
+/// stable, so the equal values must keep their order. The values are checked
+/// by their lines, as they are not required to implement `PartialEq`.
+pub fn assert_sorts_by<T, C>(values: Vec<T>, compare: C)
+where
+    T: FromLine + IntoLine + Clone + Debug,
+    C: Compare<T> + 'static
+{
+    let compare = Arc::new(compare);
+    let mut expected = values.clone();
+    expected.sort_by(|a, b| compare.compare(a, b));
+    let sorted = Sort::single_threaded_with_compare(
+        tiny_config(),
+        Shared(compare.clone())
+    ).and_then(|sort| sort.sort(values.into_iter()))
+        .and_then(|sorted| sorted.collect::<Result<Vec<_>, _>>())
+        .unwrap_or_else(|err| panic!("cannot sort the values: {}", err));
+    assert_eq!(sorted.len(), expected.len(),
+               "{} values are sorted into {}", expected.len(), sorted.len());
+    for (pos, (a, b)) in sorted.into_iter().zip(expected).enumerate() {
+        assert!(compare.compare(&a, &b) == Ordering::Equal
+                    && a.clone().into_line() == b.clone().into_line(),
+                "value at position {} is {:?}, but must be {:?}", pos, a, b);
+    }
+}
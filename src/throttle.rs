@@ -0,0 +1,124 @@
+use std::io::{self, IoSlice, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Number of bytes accumulated by `Counter` before they are taken from the
+/// throttle, so the throttle is not locked for every line
+const BATCH_SIZE: u64 = 1 << 16;
+
+/// State of the token bucket.
+struct Bucket {
+    /// Number of bytes that can be transferred without waiting. It becomes
+    /// negative when the transfers get ahead of the rate
+    available: f64,
+    /// Time when `available` was updated
+    updated: Instant
+}
+
+/// Limits the rate of reading and writing the temporary files. It's shared by
+/// all the jobs of the sorter, and works as a token bucket that holds up to
+/// one second worth of bytes, so short bursts are allowed.
+pub(crate) struct Throttle {
+    /// Number of bytes per second
+    rate: f64,
+    /// The token bucket
+    bucket: Mutex<Bucket>
+}
+
+impl Throttle {
+    /// Creates a throttle that allows `rate` bytes per second.
+    pub fn new(rate: u64) -> Throttle {
+        let rate = rate.max(1) as f64;
+        Throttle {
+            rate,
+            bucket: Mutex::new(Bucket {
+                available: rate,
+                updated: Instant::now()
+            })
+        }
+    }
+
+    /// Takes `bytes` from the bucket, sleeping until the transfer fits into
+    /// the rate.
+    pub fn take(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.available =
+                (bucket.available + elapsed * self.rate).min(self.rate);
+            bucket.updated = now;
+            bucket.available -= bytes as f64;
+            -bucket.available / self.rate
+        };
+        if wait > 0.0 {
+            thread::sleep(Duration::from_secs_f64(wait));
+        }
+    }
+}
+
+/// Counts the bytes transferred by one reader or writer, and takes them from
+/// the throttle in batches. The last incomplete batch is not taken.
+pub(crate) struct Counter {
+    /// The throttle shared by the jobs
+    throttle: Arc<Throttle>,
+    /// Number of bytes not taken from the throttle yet
+    pending: u64
+}
+
+impl Counter {
+    /// Creates a counter that takes the bytes from `throttle`.
+    pub fn new(throttle: Arc<Throttle>) -> Counter {
+        Counter { throttle, pending: 0 }
+    }
+
+    /// Counts `bytes` transferred, waiting if the rate is exceeded.
+    pub fn add(&mut self, bytes: u64) {
+        self.pending += bytes;
+        if self.pending >= BATCH_SIZE {
+            self.throttle.take(mem::take(&mut self.pending));
+        }
+    }
+}
+
+/// Writer that keeps the rate of writing within the throttle, if any.
+pub(crate) struct Throttled<W> {
+    /// The underlying writer
+    inner: W,
+    /// Counter of the written bytes, if the rate is limited
+    counter: Option<Counter>
+}
+
+impl<W> Throttled<W> {
+    /// Wraps `inner`, limiting its rate with `throttle` if it's not `None`.
+    pub fn new(inner: W, throttle: Option<&Arc<Throttle>>) -> Throttled<W> {
+        Throttled { inner, counter: throttle.cloned().map(Counter::new) }
+    }
+
+    /// Counts the written bytes.
+    fn count(&mut self, len: usize) {
+        if let Some(counter) = &mut self.counter {
+            counter.add(len as u64);
+        }
+    }
+}
+
+impl<W: Write> Write for Throttled<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.count(len);
+        Ok(len)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let len = self.inner.write_vectored(bufs)?;
+        self.count(len);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
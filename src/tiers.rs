@@ -0,0 +1,503 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Cursor, IoSlice, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tempfile::{Builder, TempDir};
+use super::long_path::extend_path;
+use super::retry::RetryPolicy;
+
+/// Directory the temporary files can be spilled to, like the one on a
+/// memory-backed filesystem (`/dev/shm` on Linux) or on the local disk.
+#[derive(Clone, Debug)]
+pub struct SpillDir {
+    /// Directory the temporary files are created in. The sorter creates its
+    /// own subdirectory there, which is removed after sorting
+    pub dir: PathBuf,
+    /// Maximum expected size of a file kept in the directory in bytes, or
+    /// `None` for no limit. The larger files go to the next tiers
+    pub max_run_size: Option<u64>,
+    /// Maximum total size of the files kept in the directory at once in bytes,
+    /// or `None` for no limit
+    pub capacity: Option<u64>
+}
+
+impl SpillDir {
+    /// Creates the spill directory in `dir` without limits.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> SpillDir {
+        SpillDir { dir: dir.into(), max_run_size: None, capacity: None }
+    }
+}
+
+/// Limits of the tier that keeps the small temporary files in the memory of
+/// the process, before the spill directories.
+///
+/// The files in this tier are reported to the temp file hook with the paths
+/// in a directory of their own, but they are never written there.
+#[derive(Clone, Copy, Debug)]
+pub struct SpillMemory {
+    /// Maximum expected size of a file kept in memory in bytes. The larger
+    /// files go to the next tiers
+    pub max_run_size: u64,
+    /// Maximum total size of the files kept in memory at once in bytes
+    pub capacity: u64
+}
+
+/// Remote storage, like an object store, that takes the temporary files which
+/// don't fit into the other tiers. It's the last tier, so it has no limits.
+///
+/// Each file is written into the local temporary directory first, and is put
+/// into the store after it's complete. The file is got back into the local
+/// directory before it's read, and is removed from the store along with its
+/// local copy.
+pub trait SpillStore: fmt::Debug + Send + Sync {
+    /// Stores the file under `key` with the data read from `data`. The
+    /// previous data under `key`, if any, is replaced.
+    fn put(&self, key: &str, data: &mut dyn Read) -> io::Result<()>;
+
+    /// Writes the data stored under `key` into `out`.
+    fn get(&self, key: &str, out: &mut dyn Write) -> io::Result<()>;
+
+    /// Removes the data stored under `key`.
+    fn remove(&self, key: &str) -> io::Result<()>;
+}
+
+/// Data of a temporary file kept in memory.
+#[derive(Clone)]
+pub(crate) struct MemoryData(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for MemoryData {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Temporary file opened for writing.
+pub(crate) enum SpillWriter {
+    /// The file is written on the disk
+    Disk(File),
+    /// The file is accumulated in memory
+    Memory(Arc<Mutex<Vec<u8>>>)
+}
+
+impl SpillWriter {
+    /// Indicates whether the file is written on the disk.
+    pub fn on_disk(&self) -> bool {
+        matches!(self, SpillWriter::Disk(_))
+    }
+}
+
+impl Write for SpillWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            SpillWriter::Disk(file) => file.write(buf),
+            SpillWriter::Memory(data) => {
+                data.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        match self {
+            SpillWriter::Disk(file) => file.write_vectored(bufs),
+            SpillWriter::Memory(data) => {
+                let mut data = data.lock().unwrap();
+                let len = data.len();
+                for buf in bufs {
+                    data.extend_from_slice(buf);
+                }
+                Ok(data.len() - len)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            SpillWriter::Disk(file) => file.flush(),
+            SpillWriter::Memory(_) => Ok(())
+        }
+    }
+}
+
+/// Temporary file opened for reading.
+pub(crate) enum SpillReader {
+    /// The file is read from the disk
+    Disk(File),
+    /// The file is read from memory
+    Memory(Cursor<MemoryData>)
+}
+
+impl From<File> for SpillReader {
+    fn from(file: File) -> SpillReader {
+        SpillReader::Disk(file)
+    }
+}
+
+impl Read for SpillReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            SpillReader::Disk(file) => file.read(buf),
+            SpillReader::Memory(data) => data.read(buf)
+        }
+    }
+}
+
+/// Kind of storage of a tier.
+enum Storage {
+    /// The files are kept in memory
+    Memory,
+    /// The files are kept in the directory of the tier
+    Disk,
+    /// The files are kept in the store, with the local copies in the
+    /// directory of the tier while they're written or read
+    Store(Arc<dyn SpillStore>)
+}
+
+/// Storage tier with its own temporary directory.
+struct Tier {
+    /// Kind of storage
+    storage: Storage,
+    /// The directory of the tier, which is removed when dropped
+    dir: TempDir,
+    /// Maximum expected size of a file in the tier, if limited
+    max_run_size: Option<u64>,
+    /// Maximum total size of the files in the tier, if limited
+    capacity: Option<u64>
+}
+
+/// Location of the data of a file placed into a tier.
+enum Data {
+    /// The file is on the disk
+    Disk,
+    /// The file is being written into memory
+    Writing(Arc<Mutex<Vec<u8>>>),
+    /// The file is written into memory
+    Memory(MemoryData),
+    /// The file is stored under `key`, and has a local copy if `local` is set
+    Stored { key: String, local: bool }
+}
+
+impl Data {
+    /// Indicates whether the file can be read from the disk.
+    fn on_disk(&self) -> bool {
+        matches!(self, Data::Disk | Data::Stored { local: true, .. })
+    }
+}
+
+/// File placed into a tier by `Tiers::place()`.
+struct Placed {
+    /// Index of the tier
+    tier: usize,
+    /// Location of the data
+    data: Data,
+    /// Size of the file once it's written
+    size: u64
+}
+
+/// Accounting of the files in the tiers.
+#[derive(Default)]
+struct State {
+    /// Number of bytes taken in each tier, including the expected sizes of
+    /// the files being written
+    used: Vec<u64>,
+    /// Files placed by `Tiers::place()`, by their names
+    placement: HashMap<OsString, Placed>,
+    /// Expected sizes of the files being written, by their paths
+    pending: HashMap<PathBuf, u64>
+}
+
+/// Storage tiers of the sorter, in the order of preference: the memory, the
+/// spill directories and the store.
+pub(crate) struct Tiers {
+    /// The tiers
+    tiers: Vec<Tier>,
+    /// Prefix of the keys of the files in the store, unique for the sorter
+    key_prefix: String,
+    /// Policy of retrying the failed operations with the files
+    retry: RetryPolicy,
+    /// Accounting of the files in the tiers
+    state: Mutex<State>
+}
+
+impl Tiers {
+    /// Creates the temporary directories of the tiers. The directories of the
+    /// memory and the store, and of the local disk if there are no spill
+    /// directories and no store, are created in `temp_dir`.
+    pub fn new(memory: Option<SpillMemory>, dirs: &[SpillDir],
+               store: Option<Arc<dyn SpillStore>>, temp_dir: &Path,
+               retry: RetryPolicy) -> io::Result<Tiers> {
+        let tier = |storage, dir: &Path, max_run_size,
+                    capacity| -> io::Result<Tier> {
+            let dir = Builder::new().prefix("extsort")
+                .tempdir_in(extend_path(dir)?)?;
+            Ok(Tier { storage, dir, max_run_size, capacity })
+        };
+        let mut tiers = Vec::new();
+        if let Some(memory) = memory {
+            tiers.push(tier(Storage::Memory, temp_dir,
+                            Some(memory.max_run_size),
+                            Some(memory.capacity))?);
+        }
+        for dir in dirs {
+            tiers.push(tier(Storage::Disk, &dir.dir, dir.max_run_size,
+                            dir.capacity)?);
+        }
+        match store {
+            Some(store) => tiers.push(tier(Storage::Store(store), temp_dir,
+                                           None, None)?),
+            None if dirs.is_empty() => {
+                tiers.push(tier(Storage::Disk, temp_dir, None, None)?);
+            },
+            None => ()
+        }
+        let key_prefix = tiers[0].dir.path().file_name()
+            .map_or_else(String::new, |name| name.to_string_lossy().into());
+        let state = State { used: vec![0; tiers.len()], ..State::default() };
+        Ok(Tiers { tiers, key_prefix, retry, state: Mutex::new(state) })
+    }
+
+    /// Gives access to the accounting of the files.
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Returns the paths to the temporary directories of the tiers.
+    pub fn dirs(&self) -> impl Iterator<Item = &Path> {
+        self.tiers.iter().map(|tier| tier.dir.path())
+    }
+
+    /// Returns the tier containing the file at `path`.
+    fn tier_of(&self, path: &Path) -> Option<usize> {
+        self.dirs().position(|dir| path.starts_with(dir))
+    }
+
+    /// Chooses the tier for the new file named `name`, which is expected to
+    /// take `size` bytes, and returns the path to it. The file goes to the
+    /// first tier whose limits it fits into, or to the last tier if there is
+    /// no such tier.
+    pub fn place(&self, name: &str, size: u64) -> PathBuf {
+        let mut state = self.state();
+        let idx = self.tiers.iter()
+            .zip(&state.used)
+            .position(|(tier, &used)| {
+                tier.max_run_size.is_none_or(|max| size <= max)
+                    && tier.capacity.is_none_or(|cap| used + size <= cap)
+            })
+            .unwrap_or(self.tiers.len() - 1);
+        let path = self.tiers[idx].dir.path().join(name);
+        let data = match self.tiers[idx].storage {
+            Storage::Memory => Data::Writing(Arc::default()),
+            Storage::Disk => Data::Disk,
+            Storage::Store(_) => Data::Stored {
+                key: format!("{}/{}", self.key_prefix, name),
+                local: true
+            }
+        };
+        state.used[idx] += size;
+        let placed = Placed { tier: idx, data, size: 0 };
+        state.placement.insert(OsString::from(name), placed);
+        state.pending.insert(path.clone(), size);
+        path
+    }
+
+    /// Returns the path to the file named `name` if it's placed into one of
+    /// the tiers.
+    pub fn find(&self, name: &str) -> Option<PathBuf> {
+        let tier = self.state().placement.get(OsStr::new(name))?.tier;
+        Some(self.tiers[tier].dir.path().join(name))
+    }
+
+    /// Opens the file at `path` for writing if it's placed into memory.
+    /// Returns `None` if it's written on the disk.
+    pub fn create(&self, path: &Path) -> Option<SpillWriter> {
+        let name = path.file_name()?;
+        let mut state = self.state();
+        if !state.placement.contains_key(name) {
+            // The files that are not placed, like the outputs of the
+            // sub-merges, are kept in memory if they're created in its
+            // directory
+            let tier = self.tier_of(path)?;
+            if !matches!(self.tiers[tier].storage, Storage::Memory) {
+                return None;
+            }
+            let data = Data::Writing(Arc::default());
+            state.placement.insert(name.to_owned(),
+                                   Placed { tier, data, size: 0 });
+        }
+        match &state.placement[name].data {
+            Data::Writing(data) => Some(SpillWriter::Memory(data.clone())),
+            _ => None
+        }
+    }
+
+    /// Returns the data of the file at `path` if it's placed into memory, or
+    /// `None` if it's read from the disk. The file is got back from the store
+    /// first if it's there.
+    pub fn fetch(&self, path: &Path) -> io::Result<Option<MemoryData>> {
+        let name = match path.file_name() {
+            Some(name) => name,
+            None => return Ok(None)
+        };
+        let (store, key) = {
+            let mut state = self.state();
+            let placed = match state.placement.get_mut(name) {
+                Some(placed) => placed,
+                None => return Ok(None)
+            };
+            match &mut placed.data {
+                Data::Writing(data) => {
+                    let data = mem::take(&mut *data.lock().unwrap());
+                    let data = MemoryData(Arc::new(data));
+                    placed.data = Data::Memory(data.clone());
+                    return Ok(Some(data));
+                },
+                Data::Memory(data) => return Ok(Some(data.clone())),
+                Data::Stored { key, local: false } => {
+                    (self.store(placed.tier), key.clone())
+                },
+                _ => return Ok(None)
+            }
+        };
+        self.retry.run(|| store.get(&key, &mut File::create(path)?))?;
+        self.set_local(name, true);
+        Ok(None)
+    }
+
+    /// Sets whether the file named `name` in the store has a local copy.
+    fn set_local(&self, name: &OsStr, has_copy: bool) {
+        let mut state = self.state();
+        if let Some(Placed { data: Data::Stored { local, .. }, .. }) =
+            state.placement.get_mut(name)
+        {
+            *local = has_copy;
+        }
+    }
+
+    /// Returns the store of the tier `idx`. Panics if it has no store.
+    fn store(&self, idx: usize) -> Arc<dyn SpillStore> {
+        match &self.tiers[idx].storage {
+            Storage::Store(store) => store.clone(),
+            _ => unreachable!("the tier has no store")
+        }
+    }
+
+    /// Returns the size of the file at `path` if it's not on the disk.
+    pub fn len(&self, path: &Path) -> Option<u64> {
+        let state = self.state();
+        let placed = state.placement.get(path.file_name()?)?;
+        if placed.data.on_disk() {
+            return None;
+        }
+        Some(placed.size)
+    }
+
+    /// Counts the file at `path` written with `size` bytes, replacing its
+    /// expected size. The file written into memory can't be written anymore,
+    /// and the file in the store tier is put into the store, removing its
+    /// local copy.
+    pub fn written(&self, path: &Path, size: u64) -> io::Result<()> {
+        let tier = match self.tier_of(path) {
+            Some(tier) => tier,
+            None => return Ok(())
+        };
+        let key = {
+            let mut state = self.state();
+            let expected = state.pending.remove(path).unwrap_or(0);
+            state.used[tier] = state.used[tier].saturating_sub(expected) + size;
+            let placed = match path.file_name()
+                .and_then(|name| state.placement.get_mut(name))
+            {
+                Some(placed) => placed,
+                None => return Ok(())
+            };
+            placed.size = size;
+            match &placed.data {
+                Data::Writing(data) => {
+                    let data = mem::take(&mut *data.lock().unwrap());
+                    placed.data = Data::Memory(MemoryData(Arc::new(data)));
+                    return Ok(());
+                },
+                Data::Stored { key, local: true } => key.clone(),
+                _ => return Ok(())
+            }
+        };
+        let store = self.store(tier);
+        self.retry.run(|| store.put(&key, &mut File::open(path)?))?;
+        self.retry.remove_file(path)?;
+        if let Some(name) = path.file_name() {
+            self.set_local(name, false);
+        }
+        Ok(())
+    }
+
+    /// Renames the file at `input` into `output` in the same tier.
+    pub fn rename(&self, input: &Path, output: &Path) -> io::Result<()> {
+        let (input_name, output_name) = match (input.file_name(),
+                                               output.file_name()) {
+            (Some(input_name), Some(output_name)) => (input_name, output_name),
+            _ => return fs::rename(input, output)
+        };
+        let mut state = self.state();
+        let on_disk = state.placement.get(input_name)
+            .is_none_or(|placed| placed.data.on_disk());
+        if on_disk {
+            fs::rename(input, output)?;
+        }
+        if let Some(placed) = state.placement.remove(input_name) {
+            state.placement.insert(output_name.to_owned(), placed);
+        }
+        Ok(())
+    }
+
+    /// Removes the file at `path` with `size` bytes, along with its data in
+    /// memory or in the store.
+    pub fn remove(&self, path: &Path, size: u64) -> io::Result<()> {
+        let tier = self.tier_of(path);
+        let placed = path.file_name()
+            .and_then(|name| self.state().placement.remove(name));
+        match &placed {
+            Some(Placed { data: Data::Writing(_) | Data::Memory(_), .. }) => (),
+            Some(Placed { data: Data::Stored { key, local }, tier, .. }) => {
+                if *local {
+                    self.retry.remove_file(path)?;
+                }
+                let store = self.store(*tier);
+                self.retry.run(|| store.remove(key))?;
+            },
+            _ => self.retry.remove_file(path)?
+        }
+        if let Some(tier) = tier {
+            let mut state = self.state();
+            state.used[tier] = state.used[tier].saturating_sub(size);
+        }
+        Ok(())
+    }
+
+    /// Returns the paths and the sizes of the files that are not on the disk,
+    /// as they're kept in memory or in the store.
+    pub fn off_disk(&self) -> Vec<(PathBuf, u64)> {
+        self.state().placement.iter()
+            .filter(|(_, placed)| !placed.data.on_disk())
+            .map(|(name, placed)| {
+                (self.tiers[placed.tier].dir.path().join(name), placed.size)
+            })
+            .collect()
+    }
+}
+
+impl Drop for Tiers {
+    fn drop(&mut self) {
+        // The files left in the store are removed, like the ones left in the
+        // directories
+        let state = self.state();
+        for placed in state.placement.values() {
+            if let Data::Stored { key, .. } = &placed.data {
+                let _ = self.store(placed.tier).remove(key);
+            }
+        }
+    }
+}
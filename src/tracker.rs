@@ -0,0 +1,225 @@
+use std::fs::{self, File};
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tempfile::TempDir;
+use super::metrics::{ActiveMerge, Metrics};
+use super::retry::RetryPolicy;
+use super::tiers::{SpillReader, SpillWriter, Tiers};
+
+/// Event in the lifecycle of a temporary file of the sorter, which is passed
+/// to the hook set with `Sort::with_temp_file_hook()`.
+#[derive(Clone, Copy, Debug)]
+pub enum TempFileEvent<'a> {
+    /// The run file is written from the input, with `size` bytes
+    Created { path: &'a Path, size: u64 },
+    /// The run files from `inputs` are merged into `output`, with `size`
+    /// bytes. The inputs are reported as deleted afterwards. The only input
+    /// may also be renamed into `output` without rewriting
+    Merged { inputs: &'a [PathBuf], output: &'a Path, size: u64 },
+    /// The temporary file is deleted, with `size` bytes
+    Deleted { path: &'a Path, size: u64 }
+}
+
+/// Hook called on the events in the lifecycle of the temporary files.
+pub(crate) type TempFileHook = Arc<dyn Fn(&TempFileEvent) + Send + Sync>;
+
+/// Performs the operations on the temporary files, retrying them according
+/// to the policy, and reports the changes of the files to the metrics, the
+/// hook and the storage tiers, if any.
+#[derive(Clone, Default)]
+pub(crate) struct Tracker {
+    /// Metrics that count the temporary files, if any
+    pub metrics: Option<Arc<Metrics>>,
+    /// Hook called on each event, if any
+    pub hook: Option<TempFileHook>,
+    /// Storage tiers that count the files spilled into them, if any
+    pub tiers: Option<Arc<Tiers>>,
+    /// Policy of retrying the failed operations
+    pub retry: RetryPolicy
+}
+
+impl Tracker {
+    /// Calls the hook, if any.
+    fn emit(&self, event: TempFileEvent) {
+        if let Some(hook) = &self.hook {
+            hook(&event);
+        }
+    }
+
+    /// Counts `count` records taken from the input.
+    pub fn add_records(&self, count: u64) {
+        if let Some(metrics) = &self.metrics {
+            metrics.add_records(count);
+        }
+    }
+
+    /// Counts the merge job until the returned value is dropped.
+    pub fn start_merge(&self) -> Option<ActiveMerge> {
+        self.metrics.as_ref().map(Metrics::start_merge)
+    }
+
+    /// Counts the file at `path` written with `size` bytes. The file is put
+    /// into the store if it's placed there.
+    fn written(&self, path: &Path, size: u64) -> io::Result<()> {
+        if let Some(metrics) = &self.metrics {
+            metrics.add_spilled(size);
+        }
+        match &self.tiers {
+            Some(tiers) => tiers.written(path, size),
+            None => Ok(())
+        }
+    }
+
+    /// Creates the temporary file at `path` for writing, in memory if it's
+    /// placed there.
+    pub fn create(&self, path: &Path) -> io::Result<SpillWriter> {
+        if let Some(writer) = self.tiers.as_ref()
+            .and_then(|tiers| tiers.create(path))
+        {
+            return Ok(writer);
+        }
+        Ok(SpillWriter::Disk(self.retry.run(|| File::create(path))?))
+    }
+
+    /// Opens the temporary file at `path` for reading. The file is got back
+    /// from the store first if it's there.
+    pub fn open(&self, path: &Path) -> io::Result<SpillReader> {
+        if let Some(tiers) = &self.tiers {
+            if let Some(data) = tiers.fetch(path)? {
+                return Ok(SpillReader::Memory(Cursor::new(data)));
+            }
+        }
+        Ok(SpillReader::Disk(self.retry.run(|| File::open(path))?))
+    }
+
+    /// Indicates whether the temporary files at `paths` can be read from the
+    /// disk, getting them back from the store if needed.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub fn fetch_all<P: AsRef<Path>>(&self, paths: &[P]) -> io::Result<bool> {
+        let tiers = match &self.tiers {
+            Some(tiers) => tiers,
+            None => return Ok(true)
+        };
+        for path in paths {
+            if tiers.fetch(path.as_ref())?.is_some() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns the size of the temporary file at `path`.
+    pub fn len(&self, path: &Path) -> io::Result<u64> {
+        match self.tiers.as_ref().and_then(|tiers| tiers.len(path)) {
+            Some(len) => Ok(len),
+            None => Ok(fs::metadata(path)?.len())
+        }
+    }
+
+    /// Reports that the run file at `path` is written from the input. The
+    /// hook is called before the file is put into the store, so it can read
+    /// the file.
+    pub fn created(&self, path: &Path, size: u64) -> io::Result<()> {
+        self.emit(TempFileEvent::Created { path, size });
+        self.written(path, size)
+    }
+
+    /// Reports that the files from `inputs` are merged into `output`, like
+    /// `created()`.
+    pub fn merged(&self, inputs: &[PathBuf], output: &Path,
+                  size: u64) -> io::Result<()> {
+        self.emit(TempFileEvent::Merged { inputs, output, size });
+        self.written(output, size)
+    }
+
+    /// Renames the file `input` into `output`, reporting it as the merge of
+    /// a single file followed by the deletion of the input. The disk usage
+    /// doesn't change.
+    pub fn rename(&self, input: PathBuf, output: &Path) -> io::Result<()> {
+        match &self.tiers {
+            Some(tiers) => tiers.rename(&input, output)?,
+            None => fs::rename(&input, output)?
+        }
+        if self.hook.is_some() {
+            let size = self.len(output)?;
+            let inputs = [input];
+            self.emit(TempFileEvent::Merged { inputs: &inputs, output, size });
+            self.emit(TempFileEvent::Deleted { path: &inputs[0], size });
+        }
+        Ok(())
+    }
+
+    /// Removes the temporary file, subtracting its size from the disk usage.
+    pub fn remove(&self, path: &Path) -> io::Result<()> {
+        let size = if self.is_active() {
+            self.len(path)?
+        } else {
+            0
+        };
+        match &self.tiers {
+            Some(tiers) => tiers.remove(path, size)?,
+            None => self.retry.remove_file(path)?
+        }
+        self.deleted(path, size);
+        Ok(())
+    }
+
+    /// Reports that the temporary file at `path` is deleted.
+    fn deleted(&self, path: &Path, size: u64) {
+        if let Some(metrics) = &self.metrics {
+            metrics.sub_temp_usage(size);
+        }
+        self.emit(TempFileEvent::Deleted { path, size });
+    }
+
+    /// Indicates whether the files are reported anywhere.
+    fn is_active(&self) -> bool {
+        self.metrics.is_some() || self.hook.is_some() || self.tiers.is_some()
+    }
+}
+
+/// Temporary directory of the sorter. The files left in it and in the storage
+/// tiers are reported as deleted when it's removed.
+pub(crate) struct TrackedTempDir {
+    /// The directory, which is removed when dropped
+    dir: TempDir,
+    /// Tracker of the files in the directory
+    pub tracker: Tracker
+}
+
+impl TrackedTempDir {
+    /// Wraps the temporary directory. The files are not tracked.
+    pub fn new(dir: TempDir) -> TrackedTempDir {
+        TrackedTempDir { dir, tracker: Tracker::default() }
+    }
+
+    /// Returns the path to the directory.
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+}
+
+impl Drop for TrackedTempDir {
+    fn drop(&mut self) {
+        if !self.tracker.is_active() {
+            return;
+        }
+        let mut dirs = vec![self.dir.path()];
+        if let Some(tiers) = &self.tracker.tiers {
+            dirs.extend(tiers.dirs());
+            for (path, size) in tiers.off_disk() {
+                self.tracker.deleted(&path, size);
+            }
+        }
+        for dir in dirs {
+            if let Ok(entries) = fs::read_dir(dir) {
+                for entry in entries.flatten() {
+                    if let Ok(meta) = entry.metadata() {
+                        self.tracker.deleted(&entry.path(), meta.len());
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use std::cmp::max;
+use std::fmt;
+use super::lines::IntoLine;
+use super::output::Compression;
+use super::sort::{Config, DEFAULT_MEMORY, default_num_threads};
+
+/// Maximum number of files merged at once that is suggested
+const MAX_SUGGESTED_MERGE: usize = 256;
+
+/// Estimated compression ratio below which compressing the output is
+/// suggested
+const COMPRESSIBLE_RATIO: f64 = 0.5;
+
+/// Sorting strategy chosen by `Sort::sort_auto()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// The data fits into memory and is sorted there without the temporary
+    /// files
+    InMemory,
+    /// The runs are merged into the result in a single pass
+    SinglePass,
+    /// The runs are merged in several passes
+    MultiPass
+}
+
+/// Configuration suggested by `Config::suggest_for_sample()`, along with the
+/// reasoning behind it.
+#[derive(Clone, Debug)]
+pub struct Suggestion {
+    /// Suggested configuration
+    pub config: Config,
+    /// Average size of the record (in bytes, including the line separator)
+    pub avg_record_size: f64,
+    /// Estimated compression ratio of the data (compressed size divided by
+    /// the original size), based on the entropy of the bytes in the sample
+    pub compression_ratio: f64,
+    /// Suggested compression of the output, which is `Compression::None` if
+    /// the data is poorly compressible or no compression feature is enabled
+    pub compression: Compression,
+    /// Estimated number of runs created during the split phase
+    pub runs: u64,
+    /// Estimated number of merge passes
+    pub merge_passes: u32,
+    /// Human-readable explanation of the suggestion, one line per item
+    pub rationale: Vec<String>
+}
+
+impl fmt::Display for Suggestion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in &self.rationale {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Computes the entropy of the bytes (in bits per byte) from their counts.
+fn entropy(counts: &[u64; 256]) -> f64 {
+    let total: u64 = counts.iter().sum();
+    if total == 0 {
+        return 0.0;
+    }
+    counts.iter()
+        .filter(|&&count| count != 0)
+        .map(|&count| {
+            let p = count as f64 / total as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Returns the number of merge passes needed to merge `runs` files, merging
+/// `num_merge` files at once.
+fn merge_passes(mut runs: u64, num_merge: usize) -> u32 {
+    let mut passes = 0;
+    while runs > 1 {
+        runs = runs.div_ceil(num_merge as u64);
+        passes += 1;
+    }
+    passes
+}
+
+/// Returns the smallest number of files to merge at once that merges `runs`
+/// files in as few passes as merging `max_merge` files at once does, along
+/// with the number of passes.
+fn min_num_merge(runs: u64, max_merge: usize) -> (usize, u32) {
+    let passes = merge_passes(runs, max_merge);
+    let mut num_merge = 2;
+    while merge_passes(runs, num_merge) > passes {
+        num_merge += 1;
+    }
+    (num_merge, passes)
+}
+
+/// Returns the compression of the output suggested for the data with the
+/// estimated `compression_ratio`: zstd (or gzip if only it's enabled) for the
+/// well compressible data, and none otherwise.
+fn suggest_compression(compression_ratio: f64) -> Compression {
+    if compression_ratio < COMPRESSIBLE_RATIO {
+        #[cfg(feature = "zstd")]
+        return Compression::Zstd(0);
+        #[cfg(all(feature = "gzip", not(feature = "zstd")))]
+        return Compression::Gzip(6);
+    }
+    Compression::None
+}
+
+impl Config {
+    /// Returns the memory budget of the split phase in bytes, which is
+    /// `max_split_size` for each thread.
+    pub(crate) fn memory_budget(&self) -> usize {
+        self.max_split_size.saturating_mul(max(self.num_threads, 1))
+    }
+
+    /// Chooses the strategy for sorting about `total_size` bytes within the
+    /// memory budget. For the external sort, `num_merge` is set to the
+    /// smallest number of files that gives the minimum number of merge passes
+    /// without exceeding `max_open_files`.
+    pub(crate) fn plan(&mut self, total_size: u64) -> Strategy {
+        if total_size <= self.memory_budget() as u64 {
+            return Strategy::InMemory;
+        }
+        let runs = total_size.div_ceil(max(self.max_split_size, 1) as u64);
+        let (num_merge, passes) =
+            min_num_merge(runs, max(self.max_open_files, 2));
+        self.num_merge = num_merge;
+        if passes <= 1 {
+            Strategy::SinglePass
+        } else {
+            Strategy::MultiPass
+        }
+    }
+
+    /// Suggests the configuration for sorting `total_estimate` records, based
+    /// on the records taken from `sample` (e.g. `iter.take(1000)` on a
+    /// similar input).
+    ///
+    /// The suggestion keeps the default memory budget, splits it between all
+    /// the CPUs, and picks the smallest number of files to merge at once that
+    /// still gives the minimum number of merge passes. The `rationale` field
+    /// of the result explains the choice.
+    pub fn suggest_for_sample<I, T>(sample: I, total_estimate: u64) -> Suggestion
+    where
+        I: Iterator<Item = T>,
+        T: IntoLine
+    {
+        let mut counts = [0u64; 256];
+        let mut sample_len = 0u64;
+        let mut sample_size = 0u64;
+        for data in sample {
+            let line = data.into_line();
+            for &byte in line.as_bytes() {
+                counts[byte as usize] += 1;
+            }
+            counts[b'\n' as usize] += 1;
+            sample_len += 1;
+            sample_size += line.len() as u64 + 1;
+        }
+        let avg_record_size = if sample_len == 0 {
+            1.0
+        } else {
+            sample_size as f64 / sample_len as f64
+        };
+        let compression_ratio = entropy(&counts) / 8.0;
+        let total_size = (avg_record_size * total_estimate as f64) as u64;
+
+        let mut rationale = vec![format!(
+            "Average record size is {:.1} bytes, so {} records take about \
+             {} bytes.",
+            avg_record_size, total_estimate, total_size
+        )];
+
+        let num_threads = default_num_threads();
+        let mut max_split_size = DEFAULT_MEMORY / num_threads;
+        if total_size < DEFAULT_MEMORY as u64 {
+            max_split_size = max(
+                (total_size as usize).div_ceil(num_threads),
+                avg_record_size.ceil() as usize
+            );
+            rationale.push(format!(
+                "The data fits into the memory budget of {} bytes, so it's \
+                 split evenly between {} thread(s) ({} bytes each).",
+                DEFAULT_MEMORY, num_threads, max_split_size
+            ));
+        } else {
+            rationale.push(format!(
+                "The memory budget of {} bytes is split between {} thread(s), \
+                 so each run is up to {} bytes.",
+                DEFAULT_MEMORY, num_threads, max_split_size
+            ));
+        }
+
+        let runs = max(total_size.div_ceil(max_split_size as u64), 1);
+        let (num_merge, passes) = min_num_merge(runs, MAX_SUGGESTED_MERGE);
+        rationale.push(format!(
+            "About {} runs are created, which are merged in {} passes when \
+             merging {} files at once.",
+            runs, passes, num_merge
+        ));
+
+        let compression = suggest_compression(compression_ratio);
+        if compression != Compression::None {
+            rationale.push(format!(
+                "The data is well compressible (estimated ratio {:.2}), so \
+                 compressing the output with {:?} is worthwhile.",
+                compression_ratio, compression
+            ));
+        } else if compression_ratio < COMPRESSIBLE_RATIO {
+            rationale.push(format!(
+                "The data is well compressible (estimated ratio {:.2}), but \
+                 neither `zstd` nor `gzip` feature is enabled.",
+                compression_ratio
+            ));
+        } else {
+            rationale.push(format!(
+                "The data is poorly compressible (estimated ratio {:.2}), so \
+                 compressing it is not worthwhile.",
+                compression_ratio
+            ));
+        }
+
+        Suggestion {
+            config: Config {
+                num_merge,
+                num_threads,
+                max_split_size,
+                ..Config::default()
+            },
+            avg_record_size,
+            compression_ratio,
+            compression,
+            runs,
+            merge_passes: passes,
+            rationale
+        }
+    }
+}
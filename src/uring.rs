@@ -0,0 +1,221 @@
+use std::io::{self, Read, Error, ErrorKind};
+use std::fs::File;
+use std::mem;
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use io_uring::{IoUring, opcode, types};
+
+/// Size of the buffer read from one file at once
+const READ_SIZE: usize = 1 << 16;
+
+/// State of the reads from one file.
+enum SlotState {
+    /// No read is in flight, and no data is buffered
+    Idle,
+    /// The read is submitted into the ring
+    Pending,
+    /// The buffer contains the data in range `pos..len`
+    Ready { pos: usize, len: usize },
+    /// The file has ended, and it's closed after that
+    Eof,
+    /// The read has failed
+    Failed(Error)
+}
+
+/// One of the files read through the ring.
+struct Slot {
+    /// The file being read, or `None` if it's closed
+    file: Option<File>,
+    /// Offset of the next read in the file
+    offset: u64,
+    /// Buffer that the kernel reads the data into. It's never moved or
+    /// reallocated while the read is in flight
+    buf: Box<[u8]>,
+    /// Current state of the reads
+    state: SlotState
+}
+
+/// The ring shared by all the files of one merge.
+struct Shared {
+    /// The ring used to submit the reads
+    ring: IoUring,
+    /// The files being read
+    slots: Vec<Slot>,
+    /// Number of reads in flight
+    pending: usize
+}
+
+impl Shared {
+    /// Submits the reads for all the idle files, so the reads from all the
+    /// merge inputs are in flight at once.
+    fn submit_idle(&mut self) -> io::Result<()> {
+        for (idx, slot) in self.slots.iter_mut().enumerate() {
+            let fd = match (&slot.state, &slot.file) {
+                (SlotState::Idle, Some(file)) => file.as_raw_fd(),
+                _ => continue
+            };
+            let entry = opcode::Read::new(types::Fd(fd),
+                                          slot.buf.as_mut_ptr(),
+                                          slot.buf.len() as u32)
+                .offset(slot.offset)
+                .build()
+                .user_data(idx as u64);
+            // The buffer stays valid until the read completes, as the slot
+            // is not touched while it's pending, and `Drop` waits for all the
+            // pending reads
+            unsafe {
+                self.ring.submission()
+                    .push(&entry)
+                    .map_err(|_| Error::other("io_uring queue is full"))?;
+            }
+            slot.state = SlotState::Pending;
+            self.pending += 1;
+        }
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    /// Waits until at least one read completes, and updates the states of
+    /// the completed files.
+    fn wait(&mut self) -> io::Result<()> {
+        match self.ring.submit_and_wait(1) {
+            Err(err) if err.kind() == ErrorKind::Interrupted => {},
+            result => { result?; }
+        }
+        for entry in self.ring.completion() {
+            let slot = &mut self.slots[entry.user_data() as usize];
+            let result = entry.result();
+            slot.state = if result > 0 {
+                slot.offset += result as u64;
+                SlotState::Ready { pos: 0, len: result as usize }
+            } else if result == 0 {
+                SlotState::Eof
+            } else {
+                let err = Error::from_raw_os_error(-result);
+                match err.kind() {
+                    ErrorKind::Interrupted | ErrorKind::WouldBlock => {
+                        SlotState::Idle
+                    },
+                    _ => SlotState::Failed(err)
+                }
+            };
+            self.pending -= 1;
+        }
+        Ok(())
+    }
+
+    /// Waits for the read of the file `idx` in flight, if any, and closes the
+    /// file. Unlike dropping the file, it returns the error of closing it.
+    fn finish(&mut self, idx: usize) -> io::Result<()> {
+        while matches!(self.slots[idx].state, SlotState::Pending) {
+            self.wait()?;
+        }
+        let slot = &mut self.slots[idx];
+        if !matches!(slot.state, SlotState::Failed(_)) {
+            slot.state = SlotState::Eof;
+        }
+        match slot.file.take() {
+            Some(file) => close(file),
+            None => Ok(())
+        }
+    }
+
+    /// Copies the buffered data of the file `idx` into `buf`, waiting for
+    /// the reads if necessary.
+    fn read(&mut self, idx: usize, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let slot = &mut self.slots[idx];
+            match &mut slot.state {
+                SlotState::Ready { pos, len } => {
+                    let count = buf.len().min(*len - *pos);
+                    let data = &slot.buf[*pos..*pos + count];
+                    buf[..count].copy_from_slice(data);
+                    *pos += count;
+                    if *pos == *len {
+                        slot.state = SlotState::Idle;
+                    }
+                    return Ok(count);
+                },
+                SlotState::Eof => {
+                    self.finish(idx)?;
+                    return Ok(0);
+                },
+                SlotState::Failed(_) => {
+                    match mem::replace(&mut slot.state, SlotState::Eof) {
+                        SlotState::Failed(err) => return Err(err),
+                        _ => unreachable!()
+                    }
+                },
+                SlotState::Idle => self.submit_idle()?,
+                SlotState::Pending => self.wait()?
+            }
+        }
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        while self.pending > 0 {
+            if self.wait().is_err() {
+                // The kernel may still write into the buffers of the pending
+                // reads, so they must never be freed. The files are closed,
+                // as the ring holds its own references to them
+                for slot in self.slots.drain(..) {
+                    if matches!(slot.state, SlotState::Pending) {
+                        mem::forget(slot.buf);
+                    }
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Closes the file, returning the error that dropping it would ignore.
+fn close(file: File) -> io::Result<()> {
+    if unsafe { libc::close(file.into_raw_fd()) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// File of the merge that is read through the ring shared with the other
+/// files. When its data is needed, the reads are submitted for all the files
+/// that have no buffered data, so the device gets many requests at once.
+pub(crate) struct UringFile {
+    /// The ring and the states of all the files
+    shared: Arc<Mutex<Shared>>,
+    /// Index of this file
+    idx: usize
+}
+
+impl Read for UringFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.shared.lock().unwrap().read(self.idx, buf)
+    }
+}
+
+/// Opens the files to be read through one ring. Returns `None` if io_uring is
+/// not supported by the system, so the files can be read in the usual way.
+pub(crate) fn open_files<P>(paths: &[P]) -> io::Result<Option<Vec<UringFile>>>
+where
+    P: AsRef<Path>
+{
+    let ring = match IoUring::new(paths.len().max(1) as u32) {
+        Ok(ring) => ring,
+        Err(_) => return Ok(None)
+    };
+    let slots = paths.iter()
+        .map(|path| Ok(Slot {
+            file: Some(File::open(path)?),
+            offset: 0,
+            buf: vec![0; READ_SIZE].into_boxed_slice(),
+            state: SlotState::Idle
+        }))
+        .collect::<io::Result<Vec<_>>>()?;
+    let shared = Arc::new(Mutex::new(Shared { ring, slots, pending: 0 }));
+    Ok(Some((0..paths.len())
+        .map(|idx| UringFile { shared: shared.clone(), idx })
+        .collect()))
+}
@@ -0,0 +1,12 @@
+#![cfg(feature = "icu")]
+
+use std::io::ErrorKind;
+use extsort::{Collated, FromLine};
+
+#[test]
+fn rejects_corrupt_lines() {
+    for line in ["0\u{e9}1 text", "abc text", "zz text", "text"] {
+        let err = Collated::from_line(line).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,37 @@
+use std::fs;
+use std::io::ErrorKind;
+use extsort::compact;
+
+#[test]
+fn later_value_wins() {
+    let dir = tempfile::tempdir().unwrap();
+    let old = dir.path().join("old.txt");
+    let new = dir.path().join("new.txt");
+    let output = dir.path().join("output.txt");
+    fs::write(&old, "1:1a\n1:2a\n1:2b\n1:3a\n").unwrap();
+    fs::write(&new, "1:2c\n1:3b\n1:3c\n").unwrap();
+    let file = compact::<u64, String, _, _>(&[&old, &new], &output).unwrap();
+    assert_eq!(file.records(), 3);
+    let pairs = file.iter::<extsort::KeyValue<u64, String>>().unwrap()
+        .map(|pair| pair.unwrap().into_pair())
+        .collect::<Vec<_>>();
+    let expected = [(1, "a"), (2, "c"), (3, "c")];
+    let expected: Vec<_> = expected.iter()
+        .map(|&(key, value)| (key, value.to_string()))
+        .collect();
+    assert_eq!(pairs, expected);
+}
+
+#[test]
+fn rejects_output_among_inputs() {
+    let dir = tempfile::tempdir().unwrap();
+    let old = dir.path().join("old.txt");
+    let new = dir.path().join("new.txt");
+    fs::write(&old, "1:1a\n").unwrap();
+    fs::write(&new, "1:1b\n").unwrap();
+    let output = dir.path().join(".").join("old.txt");
+    let err = compact::<u64, String, _, _>(&[&old, &new], &output)
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert_eq!(fs::read_to_string(&old).unwrap(), "1:1a\n");
+}
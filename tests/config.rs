@@ -0,0 +1,65 @@
+use std::io::ErrorKind;
+use extsort::{Compression, Config, Sort};
+
+#[test]
+fn rejects_merging_less_than_two_files() {
+    for num_merge in 0..2 {
+        let config = Config { num_merge, ..Config::default() };
+        let err = Sort::<u64>::new(config).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
+
+#[test]
+fn counts_distinct_with_few_open_files() {
+    for max_open_files in 0..3 {
+        let config = Config {
+            max_split_size: 64,
+            max_open_files,
+            ..Config::default()
+        };
+        let count = Sort::new(config).unwrap()
+            .count_distinct((0..1000u64).map(|num| num % 77))
+            .unwrap();
+        assert_eq!(count, 77);
+    }
+}
+
+#[test]
+fn verifies_the_last_merge() {
+    let config = Config {
+        max_split_size: 64,
+        max_open_files: 3,
+        verify: true,
+        ..Config::default()
+    };
+    let input = || (0..1000u64).map(|num| num % 77);
+    let count = Sort::new(config.clone()).unwrap()
+        .count_distinct(input())
+        .unwrap();
+    assert_eq!(count, 77);
+    let top = Sort::new(config).unwrap()
+        .top_frequent(input(), 3)
+        .unwrap();
+    assert_eq!(top.len(), 3);
+    assert!(top.iter().all(|&(num, count)| num < 76 && count == 13));
+}
+
+#[test]
+fn suggests_compression() {
+    let repetitive = (0..1000).map(|_| "aaaaaaaaaaaaaaaa".to_string());
+    let suggestion = Config::suggest_for_sample(repetitive, 1_000_000);
+    assert!(suggestion.compression_ratio < 0.5);
+    #[cfg(feature = "zstd")]
+    assert_eq!(suggestion.compression, Compression::Zstd(0));
+    #[cfg(not(any(feature = "zstd", feature = "gzip")))]
+    assert_eq!(suggestion.compression, Compression::None);
+
+    let varied = (0..1000u32).map(|num| {
+        num.wrapping_mul(2_654_435_761).to_be_bytes().iter()
+            .map(|byte| char::from(b'!' + byte % 94))
+            .collect::<String>()
+    });
+    let suggestion = Config::suggest_for_sample(varied, 1_000_000);
+    assert_eq!(suggestion.compression, Compression::None);
+}
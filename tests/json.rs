@@ -0,0 +1,34 @@
+#![cfg(feature = "json")]
+
+use serde_json::{Value, json};
+use extsort::{Config, sort_json_by_key, sort_ndjson};
+
+#[test]
+fn projects_each_key_once() {
+    let values: Vec<Value> = (0..300)
+        .map(|num| json!({"key": num % 7, "pos": num}))
+        .collect();
+    let mut calls = 0;
+    let config = Config { max_split_size: 500, ..Config::default() };
+    let sorted = sort_json_by_key(values.into_iter(), |value| {
+        calls += 1;
+        value["key"].clone()
+    }, config).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(calls, 300);
+    let pairs: Vec<_> = sorted.iter()
+        .map(|value| (value["key"].as_u64(), value["pos"].as_u64()))
+        .collect();
+    let mut expected = pairs.clone();
+    expected.sort();
+    assert_eq!(pairs, expected);
+}
+
+#[test]
+fn sorts_ndjson_by_pointer() {
+    let input = "{\"a\":{\"b\":2}}\n\n{\"a\":{\"b\":10}}\n{\"c\":1}\n";
+    let mut output = Vec::new();
+    sort_ndjson(input.as_bytes(), "/a/b", &mut output, Config::default())
+        .unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(),
+               "{\"c\":1}\n{\"a\":{\"b\":2}}\n{\"a\":{\"b\":10}}\n");
+}
@@ -0,0 +1,96 @@
+#![cfg(feature = "parquet")]
+
+use std::fs;
+use std::sync::{Arc, Mutex};
+use arrow::datatypes::DataType;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use extsort::{
+    ArrowCodec, Config, KeyValue, Sort, TempFileEvent, codec_schema
+};
+
+/// Creates the pairs with the keys that repeat many times and the values that
+/// hold their positions in the input.
+fn input(len: u64) -> Vec<KeyValue<u64, String>> {
+    (0..len)
+        .map(|pos| KeyValue::new(pos * 7 % 13, format!("{:05}", pos)))
+        .collect()
+}
+
+fn config() -> Config {
+    Config {
+        num_merge: 3,
+        max_open_files: 2,
+        max_split_size: 500,
+        verify: true,
+        ..Config::default()
+    }
+}
+
+fn assert_sorted(sorted: &[KeyValue<u64, String>]) {
+    assert_eq!(sorted.len(), 1000);
+    for pair in sorted.windows(2) {
+        assert!((&pair[0].key, &pair[0].value) < (&pair[1].key, &pair[1].value),
+                "{:?} goes before {:?}", pair[0], pair[1]);
+    }
+}
+
+#[test]
+fn writes_runs_in_parquet() {
+    let magics = Arc::new(Mutex::new(Vec::new()));
+    let hook_magics = magics.clone();
+    let sorted = Sort::new(config()).unwrap()
+        .with_parquet_runs()
+        .with_temp_file_hook(move |event: &TempFileEvent| {
+            let path = match event {
+                TempFileEvent::Created { path, .. } => path,
+                TempFileEvent::Merged { output, .. } => output,
+                TempFileEvent::Deleted { .. } => return
+            };
+            let magic = fs::read(path).unwrap()[..4].to_vec();
+            hook_magics.lock().unwrap().push(magic);
+        })
+        .sort(input(1000).into_iter())
+        .unwrap();
+    let stats = sorted.stats();
+    let sorted: Vec<_> = sorted.collect::<Result<_, _>>().unwrap();
+    assert_sorted(&sorted);
+
+    let magics = magics.lock().unwrap();
+    assert!(magics.len() > stats.runs);
+    assert!(magics.iter().all(|magic| magic == b"PAR1"));
+    let records: u64 = stats.run_ranges.iter()
+        .map(|range| range.records)
+        .sum();
+    assert_eq!(records, 1000);
+    assert_eq!(stats.run_ranges[0].first, "1:000000");
+}
+
+#[test]
+fn writes_output_in_parquet() {
+    let file = tempfile::tempfile().unwrap();
+    Sort::new(config()).unwrap()
+        .sort_to_parquet(input(1000).into_iter(), file.try_clone().unwrap())
+        .unwrap();
+
+    let builder = ParquetRecordBatchReaderBuilder::try_new(file).unwrap();
+    let schema = builder.schema().clone();
+    assert_eq!(schema, codec_schema::<KeyValue<u64, String>>());
+    assert_eq!(schema.field(0).name(), "key");
+    assert_eq!(schema.field(0).data_type(), &DataType::UInt64);
+    assert_eq!(schema.field(1).name(), "value");
+    assert_eq!(schema.field(1).data_type(), &DataType::Utf8);
+    let mut sorted = Vec::new();
+    for batch in builder.build().unwrap() {
+        let batch = batch.unwrap();
+        sorted.extend(KeyValue::<u64, String>::from_columns(batch.columns())
+            .unwrap());
+    }
+    assert_sorted(&sorted);
+}
+
+#[test]
+fn rejects_mismatched_columns() {
+    let columns = u64::to_columns(vec![1, 2, 3]).unwrap();
+    assert!(String::from_columns(&columns).is_err());
+    assert!(KeyValue::<u64, u64>::from_columns(&columns).is_err());
+}
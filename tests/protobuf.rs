@@ -0,0 +1,61 @@
+#![cfg(feature = "protobuf")]
+
+use std::io::ErrorKind;
+use extsort::{Config, read_delimited, sort_protobuf, write_delimited};
+
+#[test]
+fn sorts_messages() {
+    let messages: Vec<String> = (0..500)
+        .map(|num| format!("{}\n\u{0}{}", num * 7 % 500, num))
+        .collect();
+    let mut input = Vec::new();
+    write_delimited(messages.iter().cloned().map(Ok), &mut input).unwrap();
+    let mut output = Vec::new();
+    let config = Config { max_split_size: 1000, ..Config::default() };
+    sort_protobuf(&input[..], &mut output, |a: &String, b: &String| a.cmp(b),
+                  config).unwrap();
+    let sorted = read_delimited::<String, _>(&output[..])
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let mut expected = messages;
+    expected.sort();
+    assert_eq!(sorted, expected);
+}
+
+#[test]
+fn rejects_corrupt_lengths() {
+    let too_long: &[u8] = &[0xff, 0xff, 0xff, 0xff, 0x0f];
+    let err = read_delimited::<String, _>(too_long).next().unwrap()
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    let truncated: &[u8] = &[0x80, 0x80, 0x80, 0x80, 0x04, 0x0a];
+    let err = read_delimited::<String, _>(truncated).next().unwrap()
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn spills_delimited_runs() {
+    let messages: Vec<String> = (0..500).map(|num| format!("{:03}", num))
+        .collect();
+    let mut input = Vec::new();
+    write_delimited(messages.iter().rev().cloned().map(Ok), &mut input)
+        .unwrap();
+    let mut output = Vec::new();
+    let config = Config {
+        max_split_size: 300,
+        verify: true,
+        ..Config::default()
+    };
+    let stats = sort_protobuf(&input[..], &mut output,
+                              |a: &String, b: &String| a.cmp(b), config)
+        .unwrap();
+    assert!(stats.runs > 1);
+    // Each string of 3 bytes is framed with its tag and two lengths
+    assert_eq!(stats.temp_bytes_written % 6, 0);
+    assert_eq!(stats.run_ranges.last().unwrap().first, "\"000\"");
+    let sorted = read_delimited::<String, _>(&output[..])
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(sorted, messages);
+}
@@ -0,0 +1,54 @@
+use std::fs;
+use std::sync::{Arc, Mutex};
+use extsort::{Config, Sort, TempFileEvent};
+
+fn config() -> Config {
+    Config {
+        num_merge: 3,
+        max_open_files: 2,
+        max_split_size: 500,
+        verify: true,
+        ..Config::default()
+    }
+}
+
+/// Creates the pseudo-random numbers of both signs.
+fn input(len: u64) -> Vec<i64> {
+    (0..len)
+        .map(|pos| (pos * 7919 % 1000) as i64 - 500)
+        .collect()
+}
+
+#[test]
+fn writes_binary_runs() {
+    let sizes = Arc::new(Mutex::new(Vec::new()));
+    let hook_sizes = sizes.clone();
+    let sorted = Sort::new(config()).unwrap()
+        .with_radix_sort()
+        .with_binary_runs()
+        .with_temp_file_hook(move |event: &TempFileEvent| {
+            let path = match event {
+                TempFileEvent::Created { path, .. } => path,
+                TempFileEvent::Merged { output, .. } => output,
+                TempFileEvent::Deleted { .. } => return
+            };
+            let size = fs::metadata(path).unwrap().len();
+            hook_sizes.lock().unwrap().push(size);
+        })
+        .sort(input(1000).into_iter())
+        .unwrap();
+    let stats = sorted.stats();
+    let sorted: Vec<_> = sorted.collect::<Result<_, _>>().unwrap();
+    let mut expected = input(1000);
+    expected.sort();
+    assert_eq!(sorted, expected);
+
+    let sizes = sizes.lock().unwrap();
+    assert!(sizes.len() > stats.runs);
+    assert!(sizes.iter().all(|size| size % 8 == 0));
+    let records: u64 = stats.run_ranges.iter()
+        .map(|range| range.records)
+        .sum();
+    assert_eq!(records, 1000);
+    assert_eq!(stats.run_ranges[0].first, "-500");
+}
@@ -0,0 +1,88 @@
+#![cfg(feature = "arrow")]
+
+use std::sync::Arc;
+use arrow::array::{Array, UInt64Array};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use extsort::{
+    BatchSortColumn, BatchSortConfig, SpillFormat, sort_record_batches
+};
+
+fn schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("key", DataType::UInt64, false),
+        Field::new("pos", DataType::UInt64, false)
+    ]))
+}
+
+/// Creates `count` batches of 100 rows with the keys that repeat many times
+/// and the positions of the rows in the input.
+fn input(count: u64) -> Vec<RecordBatch> {
+    (0..count)
+        .map(|num| {
+            let pos: Vec<u64> = (num * 100..(num + 1) * 100).collect();
+            let key: Vec<u64> = pos.iter().map(|pos| pos * 7 % 5).collect();
+            RecordBatch::try_new(schema(), vec![
+                Arc::new(UInt64Array::from(key)),
+                Arc::new(UInt64Array::from(pos))
+            ]).unwrap()
+        })
+        .collect()
+}
+
+fn column(batch: &RecordBatch, idx: usize) -> Vec<u64> {
+    let array = batch.column(idx).as_any()
+        .downcast_ref::<UInt64Array>()
+        .unwrap();
+    array.values().to_vec()
+}
+
+fn assert_sorts(config: BatchSortConfig) {
+    let sorted = sort_record_batches(input(20).into_iter().map(Ok), schema(),
+                                     &[BatchSortColumn::asc("key")], config)
+        .unwrap();
+    let mut rows = Vec::new();
+    for batch in sorted {
+        let batch = batch.unwrap();
+        rows.extend(column(&batch, 0).into_iter().zip(column(&batch, 1)));
+    }
+    assert_eq!(rows.len(), 2000);
+    for pair in rows.windows(2) {
+        assert!(pair[0] < pair[1], "{:?} goes before {:?}", pair[0], pair[1]);
+    }
+}
+
+#[test]
+fn merges_runs_in_passes() {
+    // Each batch is spilled into its own run
+    for max_open_files in [2, 3, 16, 256] {
+        assert_sorts(BatchSortConfig {
+            max_memory: 1,
+            batch_size: 30,
+            max_open_files,
+            ..BatchSortConfig::default()
+        });
+    }
+    assert_sorts(BatchSortConfig::default());
+}
+
+#[cfg(feature = "parquet")]
+#[test]
+fn merges_parquet_runs_in_passes() {
+    assert_sorts(BatchSortConfig {
+        max_memory: 1,
+        batch_size: 30,
+        max_open_files: 3,
+        spill_format: SpillFormat::Parquet,
+        ..BatchSortConfig::default()
+    });
+}
+
+#[test]
+fn spills_ipc_runs() {
+    assert_sorts(BatchSortConfig {
+        max_memory: 5000,
+        spill_format: SpillFormat::Ipc,
+        ..BatchSortConfig::default()
+    });
+}
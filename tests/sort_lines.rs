@@ -0,0 +1,37 @@
+use std::io::{BufReader, ErrorKind};
+use extsort::{Config, RecordTooLarge, Sort, sort_lines};
+
+#[test]
+fn handles_line_endings() {
+    let input = "b\r\n\nc\ra\n\na";
+    let sorted = sort_lines(input.as_bytes()).unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(sorted, ["", "", "a", "b", "c\ra"]);
+}
+
+#[test]
+fn reads_lines_longer_than_the_buffer() {
+    let lines: Vec<String> = (0..50)
+        .map(|num| format!("{}{}", num % 10, "x".repeat(num * 7)))
+        .collect();
+    let input = lines.join("\r\n");
+    let reader = BufReader::with_capacity(16, input.as_bytes());
+    let sorted = sort_lines(reader).unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let mut expected = lines;
+    expected.sort();
+    assert_eq!(sorted, expected);
+}
+
+#[test]
+fn rejects_long_lines() {
+    let config = Config { max_record_size: Some(3), ..Config::default() };
+    let input = "abc\r\nabcd\n";
+    let reader = BufReader::with_capacity(2, input.as_bytes());
+    let err = Sort::new(config).unwrap().sort_lines(reader).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    let err = err.into_inner().unwrap().downcast::<RecordTooLarge>().unwrap();
+    assert_eq!(err.index, 1);
+}
@@ -0,0 +1,76 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use extsort::{
+    Count, Last, SplitConfig, Sum, aggregate, for_each_group_parallel,
+    split_by_key, split_by_key_with_config
+};
+
+#[test]
+fn processes_every_group_once() {
+    let data: Vec<u64> = (0..10_000).collect();
+    let groups = Arc::new(AtomicU64::new(0));
+    let total = Arc::new(AtomicU64::new(0));
+    let (groups_seen, total_seen) = (groups.clone(), total.clone());
+    for_each_group_parallel(
+        split_by_key(data.into_iter(), |num| num / 100),
+        3,
+        move |group| {
+            let mut sum = 0;
+            for maybe_num in group {
+                sum += maybe_num?;
+            }
+            groups_seen.fetch_add(1, Ordering::Relaxed);
+            total_seen.fetch_add(sum, Ordering::Relaxed);
+            Ok(())
+        }
+    ).unwrap();
+    assert_eq!(groups.load(Ordering::Relaxed), 100);
+    assert_eq!(total.load(Ordering::Relaxed), 10_000 * 9_999 / 2);
+}
+
+#[test]
+fn aggregates_groups_without_storing_them() {
+    let data: Vec<u64> = (0..10_000).collect();
+    // The groups exceed the limit, so taking them would fail
+    let config = SplitConfig {
+        max_group_len: Some(10),
+        ..SplitConfig::default()
+    };
+    let groups = split_by_key_with_config(data.into_iter(), |num| num / 1000,
+                                          config);
+    let sums: Vec<_> = aggregate(groups, |&num| num, Sum::default).collect();
+    assert_eq!(sums.len(), 10);
+    for (group, (first, sum)) in sums.into_iter().enumerate() {
+        let group = group as u64;
+        assert_eq!(first, group * 1000);
+        assert_eq!(sum, (group * 1000..(group + 1) * 1000).sum::<u64>());
+    }
+
+    let data = vec![1u64, 1, 2, 3, 3, 3];
+    let groups = split_by_key(data.into_iter(), |&num| num);
+    let counts: Vec<_> = aggregate(groups, |_| (), Count::default).collect();
+    assert_eq!(counts, [(1, 2), (2, 1), (3, 3)]);
+    let groups = split_by_key(vec![10u64, 11, 20].into_iter(), |num| num / 10);
+    let lasts: Vec<_> = aggregate(groups, |&num| num, Last::default).collect();
+    assert_eq!(lasts, [(10, 11), (20, 20)]);
+}
+
+#[test]
+fn computes_each_key_once() {
+    let calls = AtomicU64::new(0);
+    let groups = split_by_key((0..1000u64).collect::<Vec<_>>().into_iter(),
+                              |num| {
+                                  calls.fetch_add(1, Ordering::Relaxed);
+                                  num / 10
+                              });
+    let mut count = 0;
+    for maybe_group in groups {
+        let group: Vec<u64> = maybe_group.unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(group.len(), 10);
+        count += 1;
+    }
+    assert_eq!(count, 100);
+    assert_eq!(calls.load(Ordering::Relaxed), 1000);
+}
@@ -0,0 +1,94 @@
+use extsort::{Config, KeyValue, Sort};
+
+/// Creates the pairs with the keys that repeat many times and the values that
+/// hold their positions in the input. The values have different lengths, so
+/// the runs have different sizes.
+fn input(len: u64) -> Vec<KeyValue<u64, String>> {
+    (0..len)
+        .map(|pos| {
+            let width = if pos % 97 < 30 { 24 } else { 1 };
+            KeyValue::new(pos % 5, format!("{:0width$}", pos, width = width))
+        })
+        .collect()
+}
+
+/// Returns the position in the input stored in the value.
+fn position(pair: &KeyValue<u64, String>) -> u64 {
+    pair.value.parse().unwrap()
+}
+
+fn config(num_merge: usize, max_open_files: usize) -> Config {
+    Config {
+        num_merge,
+        max_open_files,
+        max_split_size: 300,
+        ..Config::default()
+    }
+}
+
+fn assert_stable(config: Config) {
+    let sorted: Vec<_> = Sort::new(config).unwrap()
+        .sort(input(2000).into_iter()).unwrap()
+        .collect::<Result<_, _>>().unwrap();
+    assert_eq!(sorted.len(), 2000);
+    for pair in sorted.windows(2) {
+        assert!(pair[0].key <= pair[1].key);
+        if pair[0].key == pair[1].key {
+            assert!(position(&pair[0]) < position(&pair[1]),
+                    "{:?} goes before {:?}", pair[0], pair[1]);
+        }
+    }
+}
+
+#[test]
+fn equal_keys_keep_input_order() {
+    assert_stable(config(2, 1024));
+    assert_stable(config(3, 1024));
+    assert_stable(config(16, 1024));
+    assert_stable(config(16, 3));
+}
+
+#[test]
+fn combiner_gets_arguments_in_input_order() {
+    let sorted: Vec<_> = Sort::new(config(3, 1024)).unwrap()
+        .with_combiner(|mut a: KeyValue<u64, String>, b| {
+            a.value.push(',');
+            a.value.push_str(&b.value);
+            a
+        })
+        .sort(input(2000).into_iter()).unwrap()
+        .collect::<Result<_, _>>().unwrap();
+    assert_eq!(sorted.len(), 5);
+    for pair in sorted {
+        let positions: Vec<u64> = pair.value.split(',')
+            .map(|pos| pos.parse().unwrap())
+            .collect();
+        assert_eq!(positions.len(), 400);
+        assert!(positions.windows(2).all(|pos| pos[0] < pos[1]));
+    }
+}
+
+#[test]
+fn merged_results_combine_duplicates() {
+    let sort = |input: Vec<KeyValue<u64, String>>| {
+        Sort::new(config(3, 1024)).unwrap()
+            .with_combiner(|mut a: KeyValue<u64, String>, b| {
+                a.value.push(',');
+                a.value.push_str(&b.value);
+                a
+            })
+            .sort(input.into_iter()).unwrap()
+    };
+    let mut input = input(2000);
+    let second = input.split_off(1000);
+    let merged: Vec<_> = sort(input).merge_with(sort(second)).unwrap()
+        .collect::<Result<_, _>>().unwrap();
+    assert_eq!(merged.len(), 5);
+    for pair in merged {
+        let positions: Vec<u64> = pair.value.split(',')
+            .map(|pos| pos.parse().unwrap())
+            .collect();
+        assert_eq!(positions.len(), 400);
+        assert!(positions.windows(2).all(|pos| pos[0] < pos[1]));
+    }
+}
@@ -0,0 +1,12 @@
+#![cfg(feature = "test-support")]
+
+use extsort::{assert_sorts, assert_sorts_by, by_key};
+
+#[test]
+fn sorts_values_stably() {
+    assert_sorts((0..100u64).map(|num| num * 37 % 101).collect());
+    let words: Vec<String> = (0..100u64)
+        .map(|num| format!("{}{}", num % 3, num))
+        .collect();
+    assert_sorts_by(words, by_key(|word: &String| word.len()));
+}
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use extsort::{Config, Sort, SpillMemory, SpillStore, TempFileEvent};
+
+/// Store that keeps the files in a map and counts the files put into it.
+#[derive(Debug, Default)]
+struct MapStore {
+    files: Mutex<HashMap<String, Vec<u8>>>,
+    puts: AtomicU64
+}
+
+impl SpillStore for MapStore {
+    fn put(&self, key: &str, data: &mut dyn Read) -> io::Result<()> {
+        let mut buf = Vec::new();
+        data.read_to_end(&mut buf)?;
+        self.files.lock().unwrap().insert(key.to_string(), buf);
+        self.puts.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn get(&self, key: &str, out: &mut dyn Write) -> io::Result<()> {
+        let files = self.files.lock().unwrap();
+        let data = files.get(key).ok_or(ErrorKind::NotFound)?;
+        out.write_all(data)
+    }
+
+    fn remove(&self, key: &str) -> io::Result<()> {
+        self.files.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+fn config() -> Config {
+    Config {
+        num_merge: 3,
+        max_open_files: 2,
+        max_split_size: 500,
+        verify: true,
+        ..Config::default()
+    }
+}
+
+/// Creates the pseudo-random numbers.
+fn input(len: u64) -> Vec<u64> {
+    (0..len).map(|pos| pos * 7919 % 1000).collect()
+}
+
+/// Sorts `input(1000)` with `config` and checks the result. Returns the
+/// numbers of the written files that were on the disk and that were not.
+fn sort_with(config: Config) -> (u64, u64) {
+    let counts = Arc::new([AtomicU64::new(0), AtomicU64::new(0)]);
+    let hook_counts = counts.clone();
+    let sorted = Sort::new(config).unwrap()
+        .with_temp_file_hook(move |event: &TempFileEvent| {
+            let path = match event {
+                TempFileEvent::Created { path, .. } => path,
+                TempFileEvent::Merged { output, .. } => output,
+                TempFileEvent::Deleted { .. } => return
+            };
+            let idx = if path.exists() { 0 } else { 1 };
+            hook_counts[idx].fetch_add(1, Ordering::Relaxed);
+        })
+        .sort(input(1000).into_iter())
+        .unwrap();
+    let sorted: Vec<_> = sorted.collect::<Result<_, _>>().unwrap();
+    let mut expected = input(1000);
+    expected.sort();
+    assert_eq!(sorted, expected);
+    (counts[0].load(Ordering::Relaxed), counts[1].load(Ordering::Relaxed))
+}
+
+#[test]
+fn keeps_small_runs_in_memory() {
+    let config = Config {
+        spill_memory: Some(SpillMemory {
+            max_run_size: 1 << 20,
+            capacity: 1 << 30
+        }),
+        ..config()
+    };
+    let (on_disk, in_memory) = sort_with(config);
+    assert_eq!(on_disk, 0);
+    assert!(in_memory > 0);
+}
+
+#[test]
+fn overflows_to_store() {
+    let store = Arc::new(MapStore::default());
+    let config = Config {
+        spill_memory: Some(SpillMemory {
+            max_run_size: 1 << 20,
+            capacity: 1000
+        }),
+        spill_store: Some(store.clone()),
+        ..config()
+    };
+    let (on_disk, in_memory) = sort_with(config);
+    assert!(on_disk > 0 && in_memory > 0);
+    assert!(store.puts.load(Ordering::Relaxed) > 0);
+    assert!(store.files.lock().unwrap().is_empty());
+}